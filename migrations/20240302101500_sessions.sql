@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE sessions (
+  session_hash VARCHAR(64) PRIMARY KEY NOT NULL,
+  user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE user_tokens (
+  token_hash VARCHAR(64) PRIMARY KEY NOT NULL,
+  user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  purpose INT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL,
+  used_at TIMESTAMPTZ
+);
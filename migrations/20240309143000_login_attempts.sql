@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE login_attempts (
+  kind INT NOT NULL,
+  subject VARCHAR(255) NOT NULL,
+  failures INT NOT NULL DEFAULT 0,
+  last_failure TIMESTAMPTZ,
+  locked_until TIMESTAMPTZ,
+  PRIMARY KEY(kind, subject)
+);
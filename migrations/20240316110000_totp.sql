@@ -0,0 +1,16 @@
+-- Optional TOTP second factor.
+CREATE TABLE user_totp (
+  user_id UUID PRIMARY KEY NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  secret VARCHAR(64) NOT NULL,
+  enabled BOOLEAN NOT NULL DEFAULT false,
+  last_step BIGINT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE TABLE recovery_codes (
+  code_hash VARCHAR(64) NOT NULL,
+  user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  used_at TIMESTAMPTZ,
+  PRIMARY KEY (user_id, code_hash)
+);
+-- Sessions that have passed the password, but still owe a second factor.
+ALTER TABLE sessions ADD COLUMN pending_mfa BOOLEAN NOT NULL DEFAULT false;
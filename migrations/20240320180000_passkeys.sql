@@ -0,0 +1,11 @@
+-- WebAuthn credentials, a user can have several.
+CREATE TABLE passkeys (
+  credential_id VARCHAR(1024) PRIMARY KEY NOT NULL,
+  user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  name VARCHAR(64) NOT NULL,
+  public_key BYTEA NOT NULL,
+  sign_count BIGINT NOT NULL DEFAULT 0,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_used_at TIMESTAMPTZ
+);
+CREATE INDEX passkeys_user_id ON passkeys (user_id);
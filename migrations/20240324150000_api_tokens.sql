@@ -0,0 +1,12 @@
+-- Personal access tokens, sent as a bearer header.
+CREATE TABLE api_tokens (
+  token_id UUID PRIMARY KEY NOT NULL,
+  token_hash VARCHAR(64) UNIQUE NOT NULL,
+  user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  name VARCHAR(64) NOT NULL,
+  scopes TEXT[] NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL,
+  last_used_at TIMESTAMPTZ
+);
+CREATE INDEX api_tokens_user_id ON api_tokens (user_id);
@@ -0,0 +1,4 @@
+-- When a user asked for their account to be deleted, it goes after a grace period.
+ALTER TABLE users ADD COLUMN deletion_scheduled_at TIMESTAMPTZ;
+CREATE INDEX users_deletion_scheduled_at ON users (deletion_scheduled_at)
+  WHERE deletion_scheduled_at IS NOT NULL;
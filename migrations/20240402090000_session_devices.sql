@@ -0,0 +1,7 @@
+-- Where each session was started from, and when it was last used.
+ALTER TABLE sessions ADD COLUMN session_id UUID NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE sessions ADD CONSTRAINT unique_session_id UNIQUE (session_id);
+ALTER TABLE sessions ADD COLUMN last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
+ALTER TABLE sessions ADD COLUMN ip TEXT;
+CREATE INDEX sessions_user_id ON sessions (user_id);
@@ -0,0 +1,23 @@
+-- Append-only record of security relevant events.
+-- No foreign keys, so events outlive the users and groups they are about.
+CREATE TABLE audit_events (
+  event_id BIGSERIAL PRIMARY KEY,
+  kind TEXT NOT NULL,
+  user_id UUID,
+  -- Who caused the event, NULL when that isn't a known user, eg failed logins or the app itself.
+  actor_id UUID,
+  group_id INT,
+  ip TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX audit_events_user_id ON audit_events (user_id, created_at);
+CREATE INDEX audit_events_group_id ON audit_events (group_id, created_at);
+
+CREATE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER audit_events_append_only
+  BEFORE UPDATE OR DELETE ON audit_events
+  FOR EACH ROW EXECUTE FUNCTION audit_events_append_only();
@@ -0,0 +1,30 @@
+-- Emails are now stored trimmed and lowercased, as the app normalizes them before writing.
+-- Accounts whose addresses only differ in case or whitespace can't be merged here without
+-- losing one of them, so the migration refuses to run until they're merged or renamed by hand.
+DO $$
+DECLARE
+    collisions TEXT;
+BEGIN
+    SELECT string_agg(emails, '; ') INTO collisions FROM (
+        SELECT string_agg(email || ' (' || user_id || ')', ', ') AS emails
+        FROM users GROUP BY lower(trim(email)) HAVING count(*) > 1
+    ) colliding;
+    IF collisions IS NOT NULL THEN
+        RAISE EXCEPTION 'Accounts share an email address once normalized, merge or rename them first: %', collisions;
+    END IF;
+END $$;
+-- The app also applies NFKC and IDNA to non-ASCII addresses, which SQL can't reproduce,
+-- so those have to be rewritten to their normalized form by hand before migrating.
+DO $$
+DECLARE
+    unicode TEXT;
+BEGIN
+    SELECT string_agg(email || ' (' || user_id || ')', ', ') INTO unicode
+    FROM users WHERE email !~ '^[ -~]*$';
+    IF unicode IS NOT NULL THEN
+        RAISE EXCEPTION 'Non-ASCII email addresses need normalizing by hand first: %', unicode;
+    END IF;
+END $$;
+UPDATE users SET email = lower(trim(email)) WHERE email <> lower(trim(email));
+-- Keeps a differently cased spelling of an address from being written alongside it.
+CREATE UNIQUE INDEX unique_normalized_email ON users (lower(trim(email)));
@@ -0,0 +1,7 @@
+-- Proof-of-work challenges already used, kept until they expire.
+CREATE TABLE spent_challenges (
+  nonce TEXT PRIMARY KEY,
+  spent_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  expires_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX spent_challenges_spent_at ON spent_challenges(spent_at);
@@ -0,0 +1,10 @@
+-- Devices each user has signed in from, so sign-ins from a new one can be flagged.
+CREATE TABLE known_devices (
+  user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  fingerprint VARCHAR(64) NOT NULL,
+  first_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, fingerprint)
+);
+-- Set when a sign-in is reported as not the user's, the password can't be used until reset.
+ALTER TABLE users ADD COLUMN password_reset_required BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,13 @@
+-- Invitations to join a group, as a link or emailed to one address. Only the hash is kept.
+CREATE TABLE group_invites (
+  invite_id UUID PRIMARY KEY NOT NULL DEFAULT gen_random_uuid(),
+  invite_hash VARCHAR(64) UNIQUE NOT NULL,
+  user_group INT NOT NULL REFERENCES user_groups(id) ON DELETE CASCADE,
+  created_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+  email TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL,
+  used_at TIMESTAMPTZ,
+  used_by UUID REFERENCES users(user_id) ON DELETE SET NULL
+);
+CREATE INDEX group_invites_user_group ON group_invites (user_group);
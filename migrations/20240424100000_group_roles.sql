@@ -0,0 +1,19 @@
+-- Roles within a group, and who suggested each date.
+ALTER TABLE users ADD COLUMN group_role VARCHAR NOT NULL DEFAULT 'member'
+  CHECK (group_role IN ('owner', 'admin', 'member'));
+-- The longest-standing member of each existing group owns it.
+UPDATE users SET group_role = 'owner' WHERE user_id IN (
+  SELECT DISTINCT ON (user_group) user_id FROM users
+  WHERE user_group IS NOT NULL
+  ORDER BY user_group, created_at, user_id
+);
+
+ALTER TABLE dates ADD COLUMN created_by UUID REFERENCES users(user_id) ON DELETE SET NULL;
+
+-- The least role allowed each permission, groups use the defaults until they change one.
+CREATE TABLE group_permissions (
+  user_group INT NOT NULL REFERENCES user_groups(id) ON DELETE CASCADE,
+  permission VARCHAR NOT NULL,
+  min_role VARCHAR NOT NULL CHECK (min_role IN ('owner', 'admin', 'member')),
+  PRIMARY KEY (user_group, permission)
+);
@@ -0,0 +1,18 @@
+-- Users can belong to any number of groups.
+CREATE TABLE group_members (
+  user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  user_group INT NOT NULL REFERENCES user_groups(id) ON DELETE CASCADE,
+  group_role VARCHAR NOT NULL DEFAULT 'member'
+    CHECK (group_role IN ('owner', 'admin', 'member')),
+  joined_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, user_group)
+);
+CREATE INDEX group_members_user_group ON group_members (user_group);
+INSERT INTO group_members (user_id, user_group, group_role, joined_at)
+  SELECT user_id, user_group, group_role, created_at FROM users WHERE user_group IS NOT NULL;
+ALTER TABLE users DROP COLUMN group_role;
+
+-- users.user_group is now the group the user has open, one of those they belong to.
+ALTER TABLE users DROP CONSTRAINT group_foreign_key;
+ALTER TABLE users ADD CONSTRAINT group_foreign_key
+  FOREIGN KEY (user_group) REFERENCES user_groups(id) ON DELETE SET NULL;
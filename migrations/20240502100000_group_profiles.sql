@@ -0,0 +1,16 @@
+-- How a group presents itself, and the settings its owner controls.
+ALTER TABLE user_groups
+  ADD COLUMN name VARCHAR NOT NULL DEFAULT '',
+  ADD COLUMN description VARCHAR NOT NULL DEFAULT '',
+  ADD COLUMN emoji VARCHAR NOT NULL DEFAULT '',
+  ADD COLUMN timezone VARCHAR NOT NULL DEFAULT 'UTC',
+  ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  ADD COLUMN date_sort VARCHAR NOT NULL DEFAULT 'votes'
+    CHECK (date_sort IN ('votes', 'name', 'day'));
+-- Existing groups are named after their number, and date from their first member.
+UPDATE user_groups SET
+  name = 'Group ' || id,
+  created_at = COALESCE(
+    (SELECT min(joined_at) FROM group_members WHERE group_members.user_group = user_groups.id),
+    created_at
+  );
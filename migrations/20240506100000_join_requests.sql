@@ -0,0 +1,22 @@
+-- Groups get a handle people can ask to join by, stored lowercase.
+-- Random, like new groups get, so groups can't be found by counting through their ids.
+ALTER TABLE user_groups ADD COLUMN handle VARCHAR;
+UPDATE user_groups SET handle = 'group-' || substr(md5(random()::text || id::text), 1, 12);
+ALTER TABLE user_groups ALTER COLUMN handle SET NOT NULL;
+CREATE UNIQUE INDEX user_groups_handle ON user_groups (handle);
+
+CREATE TABLE group_join_requests (
+  request_id UUID PRIMARY KEY,
+  user_group INT NOT NULL REFERENCES user_groups(id) ON DELETE CASCADE,
+  user_id UUID NOT NULL REFERENCES users(user_id) ON DELETE CASCADE,
+  status VARCHAR NOT NULL DEFAULT 'pending'
+    CHECK (status IN ('pending', 'approved', 'rejected')),
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMPTZ NOT NULL,
+  decided_by UUID REFERENCES users(user_id) ON DELETE SET NULL,
+  decided_at TIMESTAMPTZ
+);
+-- One request waiting at a time for each user and group.
+CREATE UNIQUE INDEX group_join_requests_pending ON group_join_requests (user_id, user_group)
+  WHERE status = 'pending';
+CREATE INDEX group_join_requests_user_group ON group_join_requests (user_group);
@@ -0,0 +1,4 @@
+-- Withdrawn requests are kept, so asking again can wait out a cooldown like a rejection.
+ALTER TABLE group_join_requests DROP CONSTRAINT group_join_requests_status_check;
+ALTER TABLE group_join_requests ADD CONSTRAINT group_join_requests_status_check
+  CHECK (status IN ('pending', 'approved', 'rejected', 'cancelled'));
@@ -0,0 +1,73 @@
+// Glue between the passkey routes and the browser's WebAuthn api.
+// Binary fields travel as base64url, as the server expects.
+function toBase64Url(buffer) {
+  return btoa(String.fromCharCode(...new Uint8Array(buffer)))
+    .replace(/\+/g, "-")
+    .replace(/\//g, "_")
+    .replace(/=+$/, "");
+}
+function fromBase64Url(text) {
+  const base64 = text.replace(/-/g, "+").replace(/_/g, "/");
+  return Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+}
+// Pages that use these carry their session's anti-forgery token in a meta tag.
+function csrfToken() {
+  const meta = document.querySelector('meta[name="csrf-token"]');
+  return meta ? meta.content : "";
+}
+async function postJson(url, body) {
+  const response = await fetch(url, {
+    method: "POST",
+    headers: { "Content-Type": "application/json", "X-CSRF-Token": csrfToken() },
+    body: JSON.stringify(body || {}),
+  });
+  const json = await response.json().catch(() => ({}));
+  return { ok: response.ok, json };
+}
+
+// Returns an error message, or nothing once the passkey is stored.
+async function registerPasskey(name) {
+  const start = await postJson("/account/passkeys/register/start");
+  if (!start.ok) return "Passkeys aren't available right now.";
+  const options = start.json;
+  options.challenge = fromBase64Url(options.challenge);
+  options.user.id = fromBase64Url(options.user.id);
+  options.excludeCredentials.forEach((c) => (c.id = fromBase64Url(c.id)));
+  let credential;
+  try {
+    credential = await navigator.credentials.create({ publicKey: options });
+  } catch (e) {
+    return "The passkey wasn't created.";
+  }
+  const finish = await postJson("/account/passkeys/register/finish", {
+    id: credential.id,
+    clientDataJSON: toBase64Url(credential.response.clientDataJSON),
+    attestationObject: toBase64Url(credential.response.attestationObject),
+    name,
+  });
+  return finish.ok ? null : finish.json.error || "The passkey couldn't be registered.";
+}
+
+// Returns an error message, or redirects once logged in.
+async function loginWithPasskey() {
+  const start = await postJson("/passkeys/login/start");
+  if (!start.ok) return "Passkeys aren't available right now.";
+  const options = start.json;
+  options.challenge = fromBase64Url(options.challenge);
+  let credential;
+  try {
+    credential = await navigator.credentials.get({ publicKey: options });
+  } catch (e) {
+    return "No passkey was used.";
+  }
+  const response = credential.response;
+  const finish = await postJson("/passkeys/login/finish", {
+    id: credential.id,
+    clientDataJSON: toBase64Url(response.clientDataJSON),
+    authenticatorData: toBase64Url(response.authenticatorData),
+    signature: toBase64Url(response.signature),
+    userHandle: response.userHandle ? toBase64Url(response.userHandle) : null,
+  });
+  if (!finish.ok) return finish.json.error || "That passkey wasn't accepted.";
+  window.location.href = finish.json.redirect;
+}
@@ -0,0 +1,48 @@
+// Solves the server's proof-of-work challenge before a form is sent.
+// The search is the same as `solve` in src/auth/proof_of_work.rs.
+function leadingZeroBits(bytes) {
+  let bits = 0;
+  for (const byte of bytes) {
+    if (byte !== 0) return bits + Math.clz32(byte) - 24;
+    bits += 8;
+  }
+  return bits;
+}
+async function solveChallenge(challenge, difficulty) {
+  const encoder = new TextEncoder();
+  for (let counter = 0; ; counter++) {
+    const hash = await crypto.subtle.digest("SHA-256", encoder.encode(`${challenge}:${counter}`));
+    if (leadingZeroBits(new Uint8Array(hash)) >= difficulty) return counter;
+  }
+}
+function setHiddenField(form, name, value) {
+  let input = form.querySelector(`input[name="${name}"]`);
+  if (!input) {
+    input = document.createElement("input");
+    input.type = "hidden";
+    input.name = name;
+    form.appendChild(input);
+  }
+  input.value = value;
+}
+
+// Holds back a form's submission until a fresh challenge is solved.
+function protectForm(form, status) {
+  let solved = false;
+  form.addEventListener("submit", async (event) => {
+    if (solved) return;
+    event.preventDefault();
+    const submitter = event.submitter;
+    form.querySelectorAll("input[type=submit]").forEach((b) => (b.disabled = true));
+    status.hidden = false;
+    const response = await fetch("/challenge", { cache: "no-store" });
+    if (response.ok) {
+      const { challenge, difficulty } = await response.json();
+      setHiddenField(form, "pow_challenge", challenge);
+      setHiddenField(form, "pow_solution", await solveChallenge(challenge, difficulty));
+    }
+    solved = true;
+    form.querySelectorAll("input[type=submit]").forEach((b) => (b.disabled = false));
+    form.requestSubmit(submitter);
+  });
+}
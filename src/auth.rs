@@ -1,3 +1,19 @@
+pub mod api_token;
+pub mod audit;
+pub mod csrf;
+pub mod email_address;
+pub mod group;
+pub mod invite;
+pub mod join_request;
+pub mod oidc;
+pub mod passkey;
+pub mod password_policy;
+pub mod proof_of_work;
+pub mod role;
+pub mod session;
+pub mod throttle;
+pub mod token;
+pub mod totp;
 pub mod user;
 
 use anyhow::Context;
@@ -35,19 +51,43 @@ pub async fn verify_password_hash<'a>(
     .context("Join error")?
 }
 
-pub async fn compute_password_hash(password: Secret<String>) -> anyhow::Result<Secret<String>> {
+/// Argon2 cost used when nothing else is configured.
+pub fn default_hash_params() -> Params {
+    Params::new(1500, 2, 1, None).unwrap()
+}
+
+/// Whether a stored hash was made with weaker settings than `params`, and should be redone.
+///
+/// * `password_hash`: PHC string from the database.
+/// * `params`: Currently configured cost.
+pub fn needs_rehash(password_hash: &PasswordHash, params: &Params) -> bool {
+    if Algorithm::try_from(password_hash.algorithm) != Ok(Algorithm::Argon2id)
+        || password_hash.version != Some(Version::V0x13.into())
+    {
+        return true;
+    }
+    match Params::try_from(password_hash) {
+        Ok(stored) => {
+            stored.m_cost() < params.m_cost()
+                || stored.t_cost() < params.t_cost()
+                || stored.p_cost() < params.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
+pub async fn compute_password_hash(
+    password: Secret<String>,
+    params: Params,
+) -> anyhow::Result<Secret<String>> {
     let string_hash = tokio::task::spawn_blocking(move || {
-        Argon2::new(
-            Algorithm::Argon2id,
-            Version::V0x13,
-            Params::new(1500, 2, 1, None).unwrap(),
-        )
-        .hash_password(
-            password.expose_secret().as_bytes(),
-            &SaltString::generate(rand::thread_rng()),
-        )
-        .unwrap()
-        .to_string()
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(
+                password.expose_secret().as_bytes(),
+                &SaltString::generate(rand::thread_rng()),
+            )
+            .unwrap()
+            .to_string()
     })
     .await
     .context("Join error.")?;
@@ -55,21 +95,23 @@ pub async fn compute_password_hash(password: Secret<String>) -> anyhow::Result<S
 }
 #[cfg(test)]
 mod tests {
-    use secrecy::Secret;
+    use secrecy::{ExposeSecret, Secret};
+
+    use argon2::{Params, PasswordHash};
 
-    use super::{compute_password_hash, verify_password_hash};
+    use super::{compute_password_hash, default_hash_params, needs_rehash, verify_password_hash};
 
     #[tokio::test]
     async fn test_password_accept() -> anyhow::Result<()> {
         let dummy_pword = Secret::new(String::from("Sexy As Funk"));
-        let hash_str = compute_password_hash(dummy_pword.clone()).await?;
+        let hash_str = compute_password_hash(dummy_pword.clone(), default_hash_params()).await?;
         verify_password_hash(dummy_pword, hash_str).await?;
         Ok(())
     }
     #[tokio::test]
     async fn test_password_fail() -> anyhow::Result<()> {
         let dummy_pword = Secret::new(String::from("Sexy As Funk"));
-        let hash_str = compute_password_hash(dummy_pword.clone()).await?;
+        let hash_str = compute_password_hash(dummy_pword.clone(), default_hash_params()).await?;
         assert!(
             verify_password_hash(Secret::new(String::from("Not Sexy As Funk")), hash_str)
                 .await
@@ -77,4 +119,14 @@ mod tests {
         );
         Ok(())
     }
+    #[tokio::test]
+    async fn test_needs_rehash() -> anyhow::Result<()> {
+        let weak = Params::new(1000, 1, 1, None).unwrap();
+        let hash_str =
+            compute_password_hash(Secret::new(String::from("Sexy As Funk")), weak.clone()).await?;
+        let hash = PasswordHash::new(hash_str.expose_secret())?;
+        assert!(!needs_rehash(&hash, &weak));
+        assert!(needs_rehash(&hash, &default_hash_params()));
+        Ok(())
+    }
 }
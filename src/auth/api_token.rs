@@ -0,0 +1,179 @@
+//! Personal API tokens, for scripting against a group without a browser.
+//!
+//! A token is sent as `Authorization: Bearer <token>`, and only reaches routes
+//! that take an `ApiUser` or a `GroupUser`, limited to the scopes it was created with.
+//! Dates routes also need the group's handle in an `X-Group` header, as a token
+//! isn't tied to the group its user has open in the browser.
+//! Account pages stay behind the session cookie.
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::{
+    dev::Payload,
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
+    http::header,
+    web::Data,
+    FromRequest, HttpRequest,
+};
+use chrono::{DateTime, Utc};
+use shuttle_runtime::async_trait;
+use tracing::info;
+use uuid::Uuid;
+
+use super::token::Token;
+use super::user::{AuthorizedUser, UserValidationError};
+use crate::domain::repository::AppState;
+
+/// Marks tokens, so they are easy to spot if they leak into logs or code.
+pub const API_TOKEN_PREFIX: &str = "drs_";
+pub const API_TOKEN_MAX_DAYS: i64 = 365;
+/// Names the group, by its handle, a token acts in.
+pub const GROUP_HEADER: &str = "X-Group";
+
+/// What a token is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiScope {
+    ReadDates,
+    WriteDates,
+    ManageGroup,
+}
+impl ApiScope {
+    pub const ALL: [ApiScope; 3] = [Self::ReadDates, Self::WriteDates, Self::ManageGroup];
+    /// Name the scope is stored and shown as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ReadDates => "dates:read",
+            Self::WriteDates => "dates:write",
+            Self::ManageGroup => "group:manage",
+        }
+    }
+    pub fn parse(scope: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.as_str() == scope)
+    }
+}
+
+/// A token as listed on the settings page, the secret itself is never stored.
+#[derive(Debug, Clone)]
+pub struct ApiToken {
+    pub token_id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// New random token, with the prefix.
+pub fn generate_api_token() -> Token {
+    Token::from(format!(
+        "{}{}",
+        API_TOKEN_PREFIX,
+        Token::generate().expose()
+    ))
+}
+
+#[async_trait]
+pub trait ApiTokenRepository {
+    /// Issue a personal token to a user.
+    ///
+    /// * `name`: What the user calls the token, eg the script using it.
+    /// * `scopes`: What the token may do.
+    /// * `expires_at`: When the token stops working.
+    async fn create_api_token(
+        &self,
+        user_id: &Uuid,
+        name: &str,
+        scopes: &[ApiScope],
+        expires_at: DateTime<Utc>,
+    ) -> anyhow::Result<Token>;
+    /// A user's tokens, newest first, including expired ones.
+    async fn list_api_tokens(&self, user_id: &Uuid) -> anyhow::Result<Vec<ApiToken>>;
+    /// Revoke one of a user's tokens, false if they don't have it.
+    async fn revoke_api_token(&self, user_id: &Uuid, token_id: &Uuid) -> anyhow::Result<bool>;
+    /// Get the user an unexpired token belongs to, and its scopes, marking it as used.
+    async fn get_api_token_user(
+        &self,
+        token: &Token,
+    ) -> Result<(AuthorizedUser, Vec<ApiScope>), UserValidationError>;
+}
+
+/// A user acting through either their session cookie, or a personal API token.
+pub struct ApiUser {
+    user: AuthorizedUser,
+    /// None when logged in through the browser, which may do anything.
+    scopes: Option<Vec<ApiScope>>,
+}
+impl ApiUser {
+    /// The user, if they are allowed to act with a scope.
+    pub fn require(self, scope: ApiScope) -> actix_web::Result<AuthorizedUser> {
+        match &self.scopes {
+            Some(scopes) if !scopes.contains(&scope) => Err(ErrorForbidden(format!(
+                "Token is missing the {} scope.",
+                scope.as_str()
+            ))),
+            _ => Ok(self.user),
+        }
+    }
+}
+
+/// Read a bearer token from a request, if it carries one.
+pub(crate) fn bearer_token(req: &HttpRequest) -> Option<actix_web::Result<Token>> {
+    let value = req.headers().get(header::AUTHORIZATION)?;
+    Some(
+        value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|t| Token::from(t.trim().to_string()))
+            .ok_or(ErrorUnauthorized("Malformed authorization header.")),
+    )
+}
+
+/// Resolve the user from a bearer token, falling back to the session cookie.
+impl FromRequest for ApiUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let Some(token) = bearer_token(req) else {
+            let session_user = AuthorizedUser::from_request(req, payload);
+            return Box::pin(async move {
+                Ok(ApiUser {
+                    user: session_user.await?,
+                    scopes: None,
+                })
+            });
+        };
+        let req = req.clone();
+        Box::pin(async move {
+            let app_state = req
+                .app_data::<Data<AppState>>()
+                .ok_or(ErrorInternalServerError("App state missing."))?;
+            let (user, scopes) = app_state
+                .repo
+                .get_api_token_user(&token?)
+                .await
+                .map_err(|e| {
+                    info!("Rejected API token: {}", e);
+                    ErrorUnauthorized("Invalid or expired token.")
+                })?;
+            Ok(ApiUser {
+                user,
+                scopes: Some(scopes),
+            })
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_names() {
+        for scope in ApiScope::ALL {
+            assert_eq!(ApiScope::parse(scope.as_str()), Some(scope));
+        }
+        assert_eq!(ApiScope::parse("dates:delete"), None);
+        assert!(generate_api_token().expose().starts_with(API_TOKEN_PREFIX));
+    }
+}
@@ -0,0 +1,208 @@
+//! Append-only record of security relevant events, eg logins and password changes.
+//!
+//! Events are written by the repository as the change is made. The request they came
+//! from isn't passed down that far, so the `AuditContext` middleware keeps the
+//! client's address in a task local for the length of each request.
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use chrono::{DateTime, Utc};
+use shuttle_runtime::async_trait;
+use uuid::Uuid;
+
+use super::session::client_ip;
+
+/// How many events the account page shows.
+pub const RECENT_EVENTS: i64 = 10;
+
+#[async_trait]
+pub trait AuditRepository {
+    /// Events about a user, newest first.
+    ///
+    /// * `limit`: Most events to return.
+    async fn list_user_events(&self, user_id: &Uuid, limit: i64)
+        -> anyhow::Result<Vec<AuditEvent>>;
+    /// Events about a group's members, newest first.
+    ///
+    /// * `limit`: Most events to return.
+    async fn list_group_events(&self, group: i32, limit: i64) -> anyhow::Result<Vec<AuditEvent>>;
+    /// Record a login that didn't go through `validate_user`, eg with a passkey or a second factor.
+    ///
+    /// * `succeeded`: Whether the user was let in.
+    async fn record_login(&self, user_id: &Uuid, succeeded: bool) -> anyhow::Result<()>;
+}
+
+/// What happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEventKind {
+    Registered,
+    Activated,
+    LoginSucceeded,
+    LoginFailed,
+    PasswordChanged,
+    GroupJoined,
+    GroupLeft,
+    UserRemoved,
+    SignInDenied,
+    GroupRoleChanged,
+    RemovedFromGroup,
+    GroupDeleted,
+}
+impl AuditEventKind {
+    pub const ALL: [AuditEventKind; 12] = [
+        Self::Registered,
+        Self::Activated,
+        Self::LoginSucceeded,
+        Self::LoginFailed,
+        Self::PasswordChanged,
+        Self::GroupJoined,
+        Self::GroupLeft,
+        Self::UserRemoved,
+        Self::SignInDenied,
+        Self::GroupRoleChanged,
+        Self::RemovedFromGroup,
+        Self::GroupDeleted,
+    ];
+    /// Name the event is stored as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Registered => "registered",
+            Self::Activated => "activated",
+            Self::LoginSucceeded => "login_succeeded",
+            Self::LoginFailed => "login_failed",
+            Self::PasswordChanged => "password_changed",
+            Self::GroupJoined => "group_joined",
+            Self::GroupLeft => "group_left",
+            Self::UserRemoved => "user_removed",
+            Self::SignInDenied => "sign_in_denied",
+            Self::GroupRoleChanged => "group_role_changed",
+            Self::RemovedFromGroup => "removed_from_group",
+            Self::GroupDeleted => "group_deleted",
+        }
+    }
+    pub fn parse(kind: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| k.as_str() == kind)
+    }
+    /// How the event is described to the user it is about.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Registered => "Account created",
+            Self::Activated => "Email address verified",
+            Self::LoginSucceeded => "Logged in",
+            Self::LoginFailed => "Failed login attempt",
+            Self::PasswordChanged => "Password changed",
+            Self::GroupJoined => "Joined a group",
+            Self::GroupLeft => "Left a group",
+            Self::UserRemoved => "Account deleted",
+            Self::SignInDenied => "Sign-in reported as not you, every device signed out",
+            Self::GroupRoleChanged => "Role in your group changed",
+            Self::RemovedFromGroup => "Removed from a group",
+            Self::GroupDeleted => "Group deleted",
+        }
+    }
+}
+
+/// A recorded event.
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub event_id: i64,
+    pub kind: AuditEventKind,
+    /// User the event is about, missing for failed logins to unknown addresses.
+    pub user_id: Option<Uuid>,
+    /// Who caused it, missing when that isn't a known user, eg failed logins or the app itself.
+    pub actor_id: Option<Uuid>,
+    pub group_id: Option<i32>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What is known about the request being handled.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    pub ip: Option<String>,
+}
+tokio::task_local! {
+    static REQUEST_CONTEXT: RequestContext;
+}
+impl RequestContext {
+    /// Context of the request being handled, empty outside of one, eg in background jobs.
+    pub fn current() -> Self {
+        REQUEST_CONTEXT
+            .try_with(RequestContext::clone)
+            .unwrap_or_default()
+    }
+    /// Run `f` with this as the current context.
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        REQUEST_CONTEXT.scope(self, f).await
+    }
+}
+
+/// Middleware that makes each request's `RequestContext` available to the repository.
+pub struct AuditContext;
+
+impl<S, B> Transform<S, ServiceRequest> for AuditContext
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = AuditContextMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuditContextMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct AuditContextMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuditContextMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let context = RequestContext {
+            // Same source as sessions use.
+            ip: client_ip(req.request()),
+        };
+        let service = Rc::clone(&self.service);
+        Box::pin(context.scope(async move { service.call(req).await }))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind_round_trip() {
+        for kind in AuditEventKind::ALL {
+            assert_eq!(AuditEventKind::parse(kind.as_str()), Some(kind));
+        }
+        assert_eq!(AuditEventKind::parse("unknown"), None);
+    }
+
+    #[tokio::test]
+    async fn test_context_is_scoped() {
+        assert!(RequestContext::current().ip.is_none());
+        let context = RequestContext {
+            ip: Some("127.0.0.1".into()),
+        };
+        let ip = context.scope(async { RequestContext::current().ip }).await;
+        assert_eq!(ip.as_deref(), Some("127.0.0.1"));
+    }
+}
@@ -0,0 +1,180 @@
+//! Cross-site request forgery protection, for routes that change state on a session cookie.
+//!
+//! Each session has its own token, derived from the session token, so nothing extra is stored.
+//! Pages put it in an `X-CSRF-Token` header for htmx, or a `csrf_token` field for plain forms,
+//! and the `Csrf` middleware, which wraps the whole app, refuses unsafe requests that don't
+//! carry it. Only requests carrying a session cookie are checked, so scripts sending a bearer
+//! token pass, while a bearer header alongside a session cookie doesn't skip the check.
+use std::fs;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::{
+    body::EitherBody,
+    cookie::Key,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    error::ErrorInternalServerError,
+    http::header,
+    web::{Bytes, Data},
+    FromRequest, HttpRequest, HttpResponse,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::info;
+use url::form_urlencoded;
+
+use super::session::session_token;
+use super::token::Token;
+use crate::domain::repository::AppState;
+
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+pub const CSRF_FIELD: &str = "csrf_token";
+
+/// Anti-forgery token for the session a page is rendered for.
+#[derive(Debug, Clone, Default)]
+pub struct CsrfToken(String);
+impl CsrfToken {
+    /// Token for a session, use this when the session was only just created.
+    pub fn for_session(session: &Token, key: &Key) -> Self {
+        CsrfToken(hex::encode(
+            session_mac(session, key).finalize().into_bytes(),
+        ))
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+fn session_mac(session: &Token, key: &Key) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.signing()).expect("Hmac takes any key length.");
+    mac.update(b"csrf:");
+    mac.update(session.expose().as_bytes());
+    mac
+}
+
+/// Check a token sent back with a request, in constant time.
+fn token_matches(session: &Token, key: &Key, sent: &str) -> bool {
+    hex::decode(sent).is_ok_and(|sent| session_mac(session, key).verify_slice(&sent).is_ok())
+}
+
+/// Token for the session the request carries, empty if it has none.
+impl FromRequest for CsrfToken {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.app_data::<Data<AppState>>() {
+            Some(app_state) => Ok(session_token(req, &app_state.session_key)
+                .map(|session| CsrfToken::for_session(&session, &app_state.session_key))
+                .unwrap_or_default()),
+            None => Err(ErrorInternalServerError("App state missing.")),
+        })
+    }
+}
+
+/// Middleware that refuses state changing requests without the session's token.
+pub struct Csrf;
+
+impl<S, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        Box::pin(async move {
+            if req.method().is_safe() {
+                return Ok(service.call(req).await?.map_into_left_body());
+            }
+            let key = req
+                .app_data::<Data<AppState>>()
+                .ok_or(ErrorInternalServerError("App state missing."))?
+                .session_key
+                .clone();
+            // Without a session the route turns the request away itself, or it's a
+            // script using an API token.
+            let Some(session) = session_token(req.request(), &key) else {
+                return Ok(service.call(req).await?.map_into_left_body());
+            };
+            let mut sent = req
+                .headers()
+                .get(CSRF_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            if sent.is_none() && is_form(&req) {
+                let body = req.extract::<Bytes>().await?;
+                sent = form_urlencoded::parse(&body)
+                    .find(|(k, _)| k == CSRF_FIELD)
+                    .map(|(_, v)| v.into_owned());
+                // Put the body back for the route's own extractors.
+                let (_, mut payload) = actix_http::h1::Payload::create(true);
+                payload.unread_data(body);
+                req.set_payload(payload.into());
+            }
+            if sent.is_some_and(|sent| token_matches(&session, &key, &sent)) {
+                return Ok(service.call(req).await?.map_into_left_body());
+            }
+            info!(
+                "Rejected {} {} without a CSRF token",
+                req.method(),
+                req.path()
+            );
+            let page = fs::read_to_string("./pages/csrf.html").map_err(ErrorInternalServerError)?;
+            Ok(req
+                .into_response(HttpResponse::Forbidden().body(page))
+                .map_into_right_body())
+        })
+    }
+}
+
+fn is_form(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_is_per_session() {
+        let key = Key::generate();
+        let session = Token::generate();
+        let token = CsrfToken::for_session(&session, &key);
+        assert!(token_matches(&session, &key, token.as_str()));
+        assert!(!token_matches(&Token::generate(), &key, token.as_str()));
+        assert!(!token_matches(&session, &Key::generate(), token.as_str()));
+        assert!(!token_matches(&session, &key, "not hex"));
+    }
+}
@@ -0,0 +1,225 @@
+//! Email addresses, checked and normalized before they reach the database.
+//!
+//! Addresses are NFKC normalized and lowercased, with the domain in its ASCII
+//! (punycode) form, so `Foo@Example.com` and `foo@example.com` are one account.
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest address that fits in the `MAIL` and `RCPT` commands.
+pub const EMAIL_MAX_LENGTH: usize = 254;
+pub const LOCAL_PART_MAX_LENGTH: usize = 64;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum EmailAddressError {
+    #[error("Enter an email address.")]
+    Missing,
+    #[error("That doesn't look like an email address.")]
+    Invalid,
+    #[error("Addresses from that provider can't be used, use a permanent address instead.")]
+    Disposable,
+}
+
+/// An email address known to be well formed, in its normalized form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct EmailAddress(String);
+impl EmailAddress {
+    /// Check and normalize an address as typed by a user.
+    pub fn parse(email: &str) -> Result<Self, EmailAddressError> {
+        let email = email.trim();
+        if email.is_empty() {
+            return Err(EmailAddressError::Missing);
+        }
+        let (local, domain) = email.rsplit_once('@').ok_or(EmailAddressError::Invalid)?;
+        let local = normalize_local(local);
+        let domain = idna::domain_to_ascii(domain).map_err(|_| EmailAddressError::Invalid)?;
+        if !valid_local(&local) || !valid_domain(&domain) {
+            return Err(EmailAddressError::Invalid);
+        }
+        let email = format!("{}@{}", local, domain);
+        if email.len() > EMAIL_MAX_LENGTH {
+            return Err(EmailAddressError::Invalid);
+        }
+        Ok(Self(email))
+    }
+    /// Normalize an address to look it up, without checking it.
+    ///
+    /// Well formed addresses come out as `parse` gives them, anything else is only tidied.
+    pub fn normalize(email: &str) -> String {
+        match Self::parse(email) {
+            Ok(email) => email.0,
+            Err(_) => normalize_local(email.trim()),
+        }
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+    pub fn domain(&self) -> &str {
+        self.0.rsplit_once('@').map_or("", |(_, domain)| domain)
+    }
+}
+impl fmt::Display for EmailAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl AsRef<str> for EmailAddress {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+impl From<EmailAddress> for String {
+    fn from(email: EmailAddress) -> Self {
+        email.0
+    }
+}
+impl TryFrom<String> for EmailAddress {
+    type Error = EmailAddressError;
+    fn try_from(email: String) -> Result<Self, Self::Error> {
+        Self::parse(&email)
+    }
+}
+
+fn normalize_local(local: &str) -> String {
+    local.nfkc().collect::<String>().to_lowercase()
+}
+
+/// Dot-atom local parts only, quoted ones are allowed by the RFC but nobody uses them.
+fn valid_local(local: &str) -> bool {
+    !local.is_empty()
+        && local.chars().count() <= LOCAL_PART_MAX_LENGTH
+        && !local.starts_with('.')
+        && !local.ends_with('.')
+        && !local.contains("..")
+        && local
+            .chars()
+            .all(|c| !c.is_whitespace() && !c.is_control() && !"\"(),:;<>@[\\]".contains(c))
+}
+
+/// A domain with at least two labels, after conversion to ASCII.
+fn valid_domain(domain: &str) -> bool {
+    let labels: Vec<&str> = domain.split('.').collect();
+    labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Domains of throwaway email providers, that accounts can't be registered with.
+#[derive(Debug, Clone, Default)]
+pub struct DisposableDomains {
+    domains: HashSet<String>,
+}
+impl DisposableDomains {
+    /// Load a blocklist, one domain per line.
+    ///
+    /// Blank lines and lines starting with `#` are skipped.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let list = fs::read_to_string(path.as_ref()).with_context(|| {
+            format!(
+                "Reading disposable domains from {} failed",
+                path.as_ref().display()
+            )
+        })?;
+        Ok(Self::parse(&list))
+    }
+    pub fn parse(list: &str) -> Self {
+        Self {
+            domains: list
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter_map(|domain| idna::domain_to_ascii(domain).ok())
+                .collect(),
+        }
+    }
+    /// Whether an address is at a listed domain, or a subdomain of one.
+    pub fn contains(&self, email: &EmailAddress) -> bool {
+        let mut domain = email.domain();
+        loop {
+            if self.domains.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false,
+            }
+        }
+    }
+    /// Parse an address for a new account, refusing listed domains.
+    pub fn parse_address(&self, email: &str) -> Result<EmailAddress, EmailAddressError> {
+        let email = EmailAddress::parse(email)?;
+        if self.contains(&email) {
+            return Err(EmailAddressError::Disposable);
+        }
+        Ok(email)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalization() {
+        let email = EmailAddress::parse("  Foo.Bar@Example.COM ").unwrap();
+        assert_eq!(email.as_str(), "foo.bar@example.com");
+        // Fullwidth letters fold to their ASCII forms.
+        assert_eq!(
+            EmailAddress::parse("ｆｏｏ@example.com").unwrap().as_str(),
+            "foo@example.com"
+        );
+        assert_eq!(
+            EmailAddress::parse("user@Bücher.example").unwrap().as_str(),
+            "user@xn--bcher-kva.example"
+        );
+        assert_eq!(EmailAddress::normalize("Not An Email"), "not an email");
+    }
+
+    #[test]
+    fn test_invalid_addresses() {
+        assert_eq!(EmailAddress::parse(""), Err(EmailAddressError::Missing));
+        for email in [
+            "no_at_sign",
+            "@example.com",
+            "user@",
+            "user@localhost",
+            "two words@example.com",
+            ".user@example.com",
+            "us..er@example.com",
+            "user@-example.com",
+            "user@exa_mple.com",
+        ] {
+            assert_eq!(
+                EmailAddress::parse(email),
+                Err(EmailAddressError::Invalid),
+                "{}",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn test_disposable_domains() {
+        let domains = DisposableDomains::parse("# throwaways\nmailinator.com\n\n");
+        assert_eq!(
+            domains.parse_address("someone@mailinator.com"),
+            Err(EmailAddressError::Disposable)
+        );
+        assert_eq!(
+            domains.parse_address("someone@eu.Mailinator.com"),
+            Err(EmailAddressError::Disposable)
+        );
+        assert!(domains.parse_address("someone@notmailinator.com").is_ok());
+    }
+}
@@ -0,0 +1,150 @@
+//! Access to a group's dates.
+//!
+//! Every dates route takes a `GroupUser`, resolved once per request from
+//! either the session cookie or a bearer token, so none can skip the check.
+//! A session acts in the group its user has open, a token in the group named
+//! by the request, once the user is found to be a member of it.
+use std::fs;
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::{
+    dev::Payload, error::ErrorInternalServerError, http::StatusCode, web::Data, FromRequest,
+    HttpRequest, HttpResponse, ResponseError,
+};
+use thiserror::Error;
+use tracing::{error, info};
+
+use super::api_token::{bearer_token, ApiScope, GROUP_HEADER};
+use super::session::session_token;
+use super::user::{AuthorizedUser, GroupUser, UserValidationError};
+use crate::domain::repository::AppState;
+
+/// Why a caller can't reach their group's dates.
+#[derive(Error, Debug)]
+pub enum GroupAccessError {
+    #[error("Not logged in.")]
+    NotLoggedIn,
+    #[error("Check your email for a link to activate your account.")]
+    Unverified,
+    #[error("Create or join a group first.")]
+    NoGroup,
+    #[error("Name the group in the {} header.", GROUP_HEADER)]
+    GroupRequired,
+    #[error("You aren't a member of that group.")]
+    NotMember,
+    #[error("Token is missing the {} scope.", .0.as_str())]
+    MissingScope(ApiScope),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl From<UserValidationError> for GroupAccessError {
+    fn from(e: UserValidationError) -> Self {
+        match e {
+            UserValidationError::Unverified => Self::Unverified,
+            UserValidationError::UnexpectedError(e) => Self::UnexpectedError(e),
+            e => {
+                info!("Rejected credentials: {}", e);
+                Self::NotLoggedIn
+            }
+        }
+    }
+}
+impl ResponseError for GroupAccessError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::NotLoggedIn => StatusCode::UNAUTHORIZED,
+            Self::GroupRequired => StatusCode::BAD_REQUEST,
+            Self::Unverified | Self::NoGroup | Self::NotMember | Self::MissingScope(_) => {
+                StatusCode::FORBIDDEN
+            }
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::NotLoggedIn => match fs::read_to_string("./pages/disallowed.html") {
+                Ok(page) => HttpResponse::Unauthorized().body(page),
+                Err(e) => ErrorInternalServerError(e).error_response(),
+            },
+            Self::UnexpectedError(e) => {
+                error!("{:?}", e);
+                HttpResponse::InternalServerError().body("Server Error.")
+            }
+            e => HttpResponse::build(e.status_code()).body(e.to_string()),
+        }
+    }
+}
+
+/// Scope a token needs for a request.
+///
+/// Reading with a safe method needs `dates:read`, anything else `dates:write`.
+fn required_scope(req: &HttpRequest) -> ApiScope {
+    if req.method().is_safe() {
+        ApiScope::ReadDates
+    } else {
+        ApiScope::WriteDates
+    }
+}
+
+/// Group a token request names, if the token's user is a member of it.
+async fn token_group(
+    app_state: &AppState,
+    req: &HttpRequest,
+    user: &AuthorizedUser,
+) -> Result<i32, GroupAccessError> {
+    let handle = req
+        .headers()
+        .get(GROUP_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .ok_or(GroupAccessError::GroupRequired)?;
+    // Unknown groups are refused the same way, so handles can't be probed with a token.
+    let group = app_state
+        .repo
+        .find_group_by_handle(handle.trim())
+        .await?
+        .ok_or(GroupAccessError::NotMember)?;
+    app_state
+        .repo
+        .find_group_role(&user.id(), group)
+        .await?
+        .ok_or(GroupAccessError::NotMember)?;
+    Ok(group)
+}
+
+async fn group_user(req: &HttpRequest) -> Result<GroupUser, GroupAccessError> {
+    let app_state = req
+        .app_data::<Data<AppState>>()
+        .ok_or(anyhow::anyhow!("App state missing."))?;
+    match bearer_token(req) {
+        Some(token) => {
+            let token = token.map_err(|_| GroupAccessError::NotLoggedIn)?;
+            let (user, scopes) = app_state.repo.get_api_token_user(&token).await?;
+            let scope = required_scope(req);
+            if !scopes.contains(&scope) {
+                return Err(GroupAccessError::MissingScope(scope));
+            }
+            let group = token_group(app_state, req, &user).await?;
+            Ok(GroupUser::new(user.id(), user.email(), group))
+        }
+        None => {
+            let token =
+                session_token(req, &app_state.session_key).ok_or(GroupAccessError::NotLoggedIn)?;
+            match app_state.repo.get_session_user(&token).await? {
+                AuthorizedUser::GroupUser(user) => Ok(user),
+                AuthorizedUser::NoGroupUser(_) => Err(GroupAccessError::NoGroup),
+            }
+        }
+    }
+}
+
+/// Resolve a verified member of a group, from their session or an API token.
+impl FromRequest for GroupUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { Ok(group_user(&req).await?) })
+    }
+}
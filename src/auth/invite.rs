@@ -0,0 +1,91 @@
+//! Invitations to join a group.
+//!
+//! A member hands out an invite, either as a link that works once, or emailed to a
+//! particular address, in which case only the account with that address can use it.
+//! Like other tokens only the hash of an invite is stored.
+use chrono::{DateTime, Duration, Utc};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use shuttle_runtime::async_trait;
+use thiserror::Error;
+use uuid::Uuid;
+
+use super::email_address::EmailAddress;
+use super::token::Token;
+use super::user::GroupUser;
+
+/// How long an invite can be used for.
+pub const INVITE_LENGTH_DAYS: i64 = 7;
+
+#[derive(Error, Debug)]
+pub enum InviteError {
+    #[error("This invite isn't valid.")]
+    Invalid,
+    #[error("This invite has expired.")]
+    Expired,
+    #[error("This invite has already been used.")]
+    Used,
+    #[error("This invite was sent to a different email address.")]
+    WrongAccount,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// An invite as listed to the group, the token itself is never stored.
+#[derive(Debug, Clone)]
+pub struct Invite {
+    pub invite_id: Uuid,
+    pub user_group: i32,
+    pub group_name: String,
+    pub created_by: Option<Uuid>,
+    /// Address the invite was emailed to, links can be used by anyone.
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[async_trait]
+pub trait InviteRepository {
+    /// Create an invite to a group.
+    ///
+    /// * `created_by`: Member handing it out.
+    /// * `email`: Only the account with this address can use it, anyone can if missing.
+    /// * `valid_for`: How long it can be used for.
+    async fn create_invite(
+        &self,
+        group: i32,
+        created_by: &Uuid,
+        email: Option<&EmailAddress>,
+        valid_for: Duration,
+    ) -> anyhow::Result<Token>;
+    /// Get an invite that can still be used.
+    async fn get_invite(&self, token: &Token) -> Result<Invite, InviteError>;
+    /// Use an invite, adding the user to its group and opening it.
+    ///
+    /// Users keep the groups they are already in.
+    async fn accept_invite(&self, token: &Token, user_id: &Uuid) -> Result<GroupUser, InviteError>;
+    /// A group's invites that can still be used, newest first.
+    async fn list_invites(&self, group: i32) -> anyhow::Result<Vec<Invite>>;
+    /// Withdraw one of a group's invites, false if the group doesn't have it.
+    async fn revoke_invite(&self, group: i32, invite_id: &Uuid) -> anyhow::Result<bool>;
+}
+
+/// Render a link as a QR code, to scan from another phone when inviting in person.
+pub fn qr_code_svg(url: &str) -> anyhow::Result<String> {
+    Ok(QrCode::new(url.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qr_code() -> anyhow::Result<()> {
+        let svg = qr_code_svg("https://example.com/invite/abc")?;
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.contains("<svg"));
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+//! Asking to join a group, as an alternative to being invited.
+//!
+//! Users find a group by its handle and ask to join. Members whose role allows it
+//! approve or reject the request, and only then is the user added to the group.
+//! Requests nobody answers expire, after `JOIN_REQUEST_LENGTH_DAYS` unless configured.
+//! After a rejection or withdrawal the user waits `JOIN_REQUEST_COOLDOWN_HOURS` to ask again.
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use shuttle_runtime::async_trait;
+use thiserror::Error;
+use tracing::error;
+use uuid::Uuid;
+
+use super::role::RoleError;
+
+/// How long a request waits for an answer, unless configured otherwise.
+pub const JOIN_REQUEST_LENGTH_DAYS: i64 = 14;
+/// How long after a rejected or withdrawn request the user can ask the group again.
+pub const JOIN_REQUEST_COOLDOWN_HOURS: i64 = 24;
+/// Most requests a user can have waiting at once, across every group.
+pub const MAX_OPEN_JOIN_REQUESTS: i64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JoinRequestStatus {
+    Pending,
+    Approved,
+    Rejected,
+    Cancelled,
+}
+impl JoinRequestStatus {
+    pub const ALL: [JoinRequestStatus; 4] = [
+        Self::Pending,
+        Self::Approved,
+        Self::Rejected,
+        Self::Cancelled,
+    ];
+    /// Name the status is stored as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Rejected => "rejected",
+            Self::Cancelled => "cancelled",
+        }
+    }
+    pub fn parse(status: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.as_str() == status)
+    }
+}
+
+/// A request to join a group, as shown to the user who made it and to the group.
+#[derive(Debug, Clone)]
+pub struct JoinRequest {
+    pub request_id: Uuid,
+    pub user_group: i32,
+    pub group_name: String,
+    pub user_id: Uuid,
+    /// Address of the user asking to join.
+    pub email: String,
+    pub status: JoinRequestStatus,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Error, Debug)]
+pub enum JoinRequestError {
+    #[error("No group has that handle.")]
+    UnknownGroup,
+    #[error("You're already a member of that group.")]
+    AlreadyMember,
+    #[error("You've already asked to join that group.")]
+    AlreadyRequested,
+    #[error("You asked to join that group recently, try again tomorrow.")]
+    TooSoon,
+    #[error("You have too many requests waiting, withdraw one or wait for an answer.")]
+    TooManyOpen,
+    #[error("That request isn't waiting for an answer.")]
+    NotFound,
+    #[error("Your role in the group doesn't allow that.")]
+    Forbidden,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl From<RoleError> for JoinRequestError {
+    fn from(e: RoleError) -> Self {
+        match e {
+            RoleError::Forbidden => Self::Forbidden,
+            RoleError::NotMember | RoleError::DateNotFound => Self::NotFound,
+            RoleError::UnexpectedError(e) => Self::UnexpectedError(e),
+        }
+    }
+}
+impl ResponseError for JoinRequestError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::UnknownGroup | Self::NotFound => StatusCode::NOT_FOUND,
+            Self::AlreadyMember | Self::AlreadyRequested => StatusCode::CONFLICT,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::TooSoon | Self::TooManyOpen => StatusCode::TOO_MANY_REQUESTS,
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::UnexpectedError(e) => {
+                error!("{:?}", e);
+                HttpResponse::InternalServerError().body("Server Error.")
+            }
+            e => HttpResponse::build(e.status_code()).body(e.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+pub trait JoinRequestRepository {
+    /// Group with the given handle, which is matched whatever its case.
+    async fn find_group_by_handle(&self, handle: &str) -> anyhow::Result<Option<i32>>;
+    /// Ask to join a group, unless the user's last request to it was refused or withdrawn lately.
+    ///
+    /// Refused too while the user has `MAX_OPEN_JOIN_REQUESTS` waiting elsewhere.
+    ///
+    /// * `valid_for`: How long the group has to answer.
+    async fn create_join_request(
+        &self,
+        user_id: &Uuid,
+        group: i32,
+        valid_for: Duration,
+    ) -> Result<JoinRequest, JoinRequestError>;
+    /// A user's requests that haven't expired, answered or not, newest first.
+    async fn list_user_join_requests(&self, user_id: &Uuid) -> anyhow::Result<Vec<JoinRequest>>;
+    /// Requests to join a group that are waiting for an answer, oldest first.
+    async fn list_group_join_requests(&self, group: i32) -> anyhow::Result<Vec<JoinRequest>>;
+    /// Answer a request to join the actor's group, if their role may approve members.
+    ///
+    /// Approving adds the user to the group along with the answer, so neither happens alone.
+    async fn decide_join_request(
+        &self,
+        actor_id: &Uuid,
+        group: i32,
+        request_id: &Uuid,
+        approve: bool,
+    ) -> Result<JoinRequest, JoinRequestError>;
+    /// Withdraw one of the user's requests, false if it isn't waiting for an answer.
+    ///
+    /// The request is kept as cancelled, so asking again waits out the cooldown.
+    async fn cancel_join_request(&self, user_id: &Uuid, request_id: &Uuid) -> anyhow::Result<bool>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_names() {
+        for status in JoinRequestStatus::ALL {
+            assert_eq!(JoinRequestStatus::parse(status.as_str()), Some(status));
+        }
+        assert_eq!(JoinRequestStatus::parse("expired"), None);
+    }
+}
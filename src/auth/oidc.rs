@@ -0,0 +1,409 @@
+//! OpenID Connect login.
+//!
+//! Authorization code flow with PKCE, against any provider that publishes discovery and JWKS.
+//! 1) The browser is sent to the provider, with the flow's secrets kept in a private cookie.
+//! 2) The provider sends the browser back with a code.
+//! 3) The code is exchanged for an id token, whose signature and claims are checked.
+//! 4) The token's issuer and subject identify the user, who is created on their first login.
+//!
+//! An identity only joins an existing account when the account's owner connects it while
+//! logged in, an address the provider vouches for isn't enough.
+use anyhow::{anyhow, Context};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use reqwest::Client;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use shuttle_runtime::async_trait;
+use thiserror::Error;
+use url::Url;
+use uuid::Uuid;
+
+use super::email_address::EmailAddress;
+use super::token::Token;
+use super::user::UserValidationError;
+
+/// Seconds of clock difference with the provider that are tolerated.
+pub const CLOCK_LEEWAY_SECONDS: i64 = 60;
+
+/// A provider registered for this app.
+#[derive(Clone)]
+pub struct OidcConfig {
+    /// Shown on the login button.
+    pub name: String,
+    /// Issuer url, discovery is read from below it.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Secret<String>,
+    /// Where the provider sends the browser back to, must be registered with it.
+    pub redirect_url: String,
+}
+
+#[derive(Error, Debug)]
+pub enum OidcError {
+    #[error("The provider's response was rejected: {0}")]
+    InvalidToken(&'static str),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// The parts of a provider's discovery document that are used.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// Secrets of a single login attempt, that have to survive the trip to the provider.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OidcFlow {
+    pub state: String,
+    pub nonce: String,
+    pub verifier: String,
+    /// User connecting the identity from their account page, rather than logging in.
+    #[serde(default)]
+    pub linking: Option<Uuid>,
+}
+impl OidcFlow {
+    pub fn new() -> Self {
+        OidcFlow {
+            state: Token::generate().expose().to_string(),
+            nonce: Token::generate().expose().to_string(),
+            verifier: Token::generate().expose().to_string(),
+            linking: None,
+        }
+    }
+    /// A flow connecting the identity to a logged in user.
+    pub fn linking(user_id: Uuid) -> Self {
+        OidcFlow {
+            linking: Some(user_id),
+            ..Self::new()
+        }
+    }
+    /// PKCE challenge sent up front, that only the verifier satisfies.
+    pub fn challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.verifier.as_bytes()))
+    }
+}
+impl Default for OidcFlow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Self::One(aud) => aud == client_id,
+            Self::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+/// Claims of a verified id token.
+#[derive(Deserialize, Debug, Clone)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: Audience,
+    pub azp: Option<String>,
+    pub exp: i64,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+#[derive(Deserialize, Debug, Clone)]
+pub struct Jwk {
+    pub kty: String,
+    pub kid: Option<String>,
+    #[serde(rename = "use")]
+    pub use_: Option<String>,
+    pub n: Option<String>,
+    pub e: Option<String>,
+}
+#[derive(Deserialize, Debug, Clone)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+pub struct OidcClient {
+    pub config: OidcConfig,
+    c: Client,
+}
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        OidcClient {
+            config,
+            c: Client::new(),
+        }
+    }
+    /// Read the provider's endpoints from its discovery document.
+    pub async fn discover(&self) -> anyhow::Result<ProviderMetadata> {
+        let metadata: ProviderMetadata = self
+            .c
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                self.config.issuer.trim_end_matches('/')
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("Parsing discovery document failed.")?;
+        if metadata.issuer != self.config.issuer {
+            return Err(anyhow!(
+                "Discovery issuer {} doesn't match {}.",
+                metadata.issuer,
+                self.config.issuer
+            ));
+        }
+        Ok(metadata)
+    }
+    /// Url that starts a login at the provider.
+    pub fn authorization_url(
+        &self,
+        metadata: &ProviderMetadata,
+        flow: &OidcFlow,
+    ) -> anyhow::Result<Url> {
+        let mut url = Url::parse(&metadata.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", "openid email")
+            .append_pair("state", &flow.state)
+            .append_pair("nonce", &flow.nonce)
+            .append_pair("code_challenge", &flow.challenge())
+            .append_pair("code_challenge_method", "S256");
+        Ok(url)
+    }
+    /// Trade the code the provider sent back for the claims of a verified id token.
+    ///
+    /// * `code`: Authorization code from the callback.
+    /// * `flow`: The login attempt the code belongs to.
+    pub async fn exchange_code(
+        &self,
+        metadata: &ProviderMetadata,
+        code: &str,
+        flow: &OidcFlow,
+    ) -> Result<IdTokenClaims, OidcError> {
+        let resp = self
+            .c
+            .post(&metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+                ("client_id", &self.config.client_id),
+                ("client_secret", self.config.client_secret.expose_secret()),
+                ("code_verifier", &flow.verifier),
+            ])
+            .send()
+            .await
+            .context("Token request failed.")?;
+        if !resp.status().is_success() {
+            return Err(OidcError::InvalidToken("the code was refused"));
+        }
+        let token: TokenResponse = resp
+            .json()
+            .await
+            .map_err(|_| OidcError::InvalidToken("no id token"))?;
+        let jwks: Jwks = self
+            .c
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .context("Fetching JWKS failed.")?
+            .json()
+            .await
+            .context("Parsing JWKS failed.")?;
+        verify_id_token(
+            &token.id_token,
+            &jwks,
+            &metadata.issuer,
+            &self.config.client_id,
+            &flow.nonce,
+        )
+    }
+}
+
+/// Check an id token's signature and claims.
+///
+/// Only RS256 is accepted, which every provider has to support.
+/// * `issuer`: Expected `iss`.
+/// * `client_id`: Must be in `aud`.
+/// * `nonce`: The nonce the login attempt was started with.
+pub fn verify_id_token(
+    id_token: &str,
+    jwks: &Jwks,
+    issuer: &str,
+    client_id: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let invalid = OidcError::InvalidToken;
+    let mut parts = id_token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid("malformed id token"));
+    };
+    let decode = |part: &str| {
+        URL_SAFE_NO_PAD
+            .decode(part)
+            .map_err(|_| invalid("malformed id token"))
+    };
+    let jwt_header: JwtHeader =
+        serde_json::from_slice(&decode(header)?).map_err(|_| invalid("malformed id token"))?;
+    if jwt_header.alg != "RS256" {
+        return Err(invalid("unsupported signing algorithm"));
+    }
+    let digest = Sha256::digest(format!("{}.{}", header, payload).as_bytes());
+    let signature = decode(signature)?;
+    let verified = jwks
+        .keys
+        .iter()
+        .filter(|k| k.kty == "RSA" && k.use_.as_deref() != Some("enc"))
+        .filter(|k| jwt_header.kid.is_none() || k.kid == jwt_header.kid)
+        .filter_map(|k| {
+            let n = URL_SAFE_NO_PAD.decode(k.n.as_ref()?).ok()?;
+            let e = URL_SAFE_NO_PAD.decode(k.e.as_ref()?).ok()?;
+            RsaPublicKey::new(BigUint::from_bytes_be(&n), BigUint::from_bytes_be(&e)).ok()
+        })
+        .any(|key| {
+            key.verify(Pkcs1v15Sign::new::<Sha256>(), &digest, &signature)
+                .is_ok()
+        });
+    if !verified {
+        return Err(invalid("bad signature"));
+    }
+    let claims: IdTokenClaims =
+        serde_json::from_slice(&decode(payload)?).map_err(|_| invalid("malformed claims"))?;
+    if claims.iss != issuer {
+        return Err(invalid("wrong issuer"));
+    }
+    if !claims.aud.contains(client_id)
+        || (matches!(claims.aud, Audience::Many(_))
+            && claims.azp.as_deref().is_some_and(|azp| azp != client_id))
+    {
+        return Err(invalid("wrong audience"));
+    }
+    if claims.exp + CLOCK_LEEWAY_SECONDS < Utc::now().timestamp() {
+        return Err(invalid("expired id token"));
+    }
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(invalid("wrong nonce"));
+    }
+    Ok(claims)
+}
+
+#[async_trait]
+pub trait IdentityRepository {
+    /// Get the user an external identity is linked to.
+    ///
+    /// * `issuer`: Provider the identity belongs to.
+    /// * `subject`: The provider's id for the user.
+    async fn get_identity_user(&self, issuer: &str, subject: &str) -> anyhow::Result<Option<Uuid>>;
+    /// Link an external identity to an existing user.
+    async fn link_identity(
+        &self,
+        user_id: &Uuid,
+        issuer: &str,
+        subject: &str,
+    ) -> anyhow::Result<()>;
+    /// Create a user without a password, linked to an external identity.
+    ///
+    /// * `verified`: The provider vouched for the email, so the account starts activated.
+    async fn register_identity_user(
+        &self,
+        email: &EmailAddress,
+        issuer: &str,
+        subject: &str,
+        verified: bool,
+    ) -> Result<Uuid, UserValidationError>;
+}
+#[cfg(test)]
+mod tests {
+    use rsa::traits::PublicKeyParts;
+    use rsa::RsaPrivateKey;
+    use serde_json::json;
+
+    use super::*;
+
+    fn sign(key: &RsaPrivateKey, claims: serde_json::Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({"alg": "RS256", "kid": "k"}).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let digest = Sha256::digest(format!("{}.{}", header, payload).as_bytes());
+        let signature = key.sign(Pkcs1v15Sign::new::<Sha256>(), &digest).unwrap();
+        format!(
+            "{}.{}.{}",
+            header,
+            payload,
+            URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    #[test]
+    fn test_verify_id_token() {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let jwks = Jwks {
+            keys: vec![Jwk {
+                kty: "RSA".into(),
+                kid: Some("k".into()),
+                use_: Some("sig".into()),
+                n: Some(URL_SAFE_NO_PAD.encode(key.n().to_bytes_be())),
+                e: Some(URL_SAFE_NO_PAD.encode(key.e().to_bytes_be())),
+            }],
+        };
+        let claims = json!({
+            "iss": "https://issuer",
+            "sub": "123",
+            "aud": "client",
+            "exp": Utc::now().timestamp() + 300,
+            "nonce": "nonce",
+            "email": "oidc@unit.com",
+            "email_verified": true,
+        });
+        let verify =
+            |token: &str| verify_id_token(token, &jwks, "https://issuer", "client", "nonce");
+        let token = sign(&key, claims.clone());
+        let verified = verify(&token).unwrap();
+        assert_eq!(verified.sub, "123");
+        assert!(verified.email_verified);
+
+        assert!(verify_id_token(&token, &jwks, "https://issuer", "client", "other").is_err());
+        assert!(verify_id_token(&token, &jwks, "https://other", "client", "nonce").is_err());
+        assert!(verify_id_token(&token, &jwks, "https://issuer", "other", "nonce").is_err());
+        let mut expired = claims.clone();
+        expired["exp"] = json!(Utc::now().timestamp() - 2 * CLOCK_LEEWAY_SECONDS);
+        assert!(verify(&sign(&key, expired)).is_err());
+        // Claims swapped in under a valid signature.
+        let mut tampered: Vec<&str> = token.split('.').collect();
+        let other = URL_SAFE_NO_PAD.encode(json!({"sub": "456"}).to_string());
+        tampered[1] = &other;
+        assert!(verify(&tampered.join(".")).is_err());
+    }
+}
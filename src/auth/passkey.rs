@@ -0,0 +1,434 @@
+//! Passkeys (WebAuthn), as a replacement for the password.
+//!
+//! Registration Flow:
+//! 1) A logged in user is sent a challenge, kept in a private cookie.
+//! 2) Their authenticator makes a key pair, and returns the public key signed over the challenge.
+//! 3) The public key is stored against the user.
+//!
+//! Login Flow:
+//! 1) Anyone is sent a challenge.
+//! 2) Their authenticator signs it with a stored key, after verifying the user.
+//! 3) The signature is checked with the stored public key, and a full session is started.
+//!
+//! Attestation isn't checked, so any authenticator is accepted.
+use anyhow::anyhow;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use thiserror::Error;
+use url::Url;
+use uuid::Uuid;
+
+/// How long a user has to answer a challenge.
+pub const PASSKEY_CHALLENGE_MINUTES: i64 = 5;
+/// COSE algorithm ids that are accepted, ES256 then RS256.
+pub const SUPPORTED_ALGORITHMS: [i64; 2] = [-7, -257];
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_DATA: u8 = 0x40;
+
+/// The site passkeys are bound to.
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    /// Domain the credentials are scoped to.
+    pub id: String,
+    pub name: String,
+    /// Origin the browser reports the ceremony ran on.
+    pub origin: String,
+}
+impl RelyingParty {
+    /// Derive the relying party from the url the app is served on.
+    pub fn from_origin(origin: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(origin)?;
+        Ok(RelyingParty {
+            id: url
+                .host_str()
+                .ok_or(anyhow!("Origin {} has no host.", origin))?
+                .to_string(),
+            name: "Date.rs".to_string(),
+            origin: url.origin().ascii_serialization(),
+        })
+    }
+}
+
+/// A stored credential.
+#[derive(Debug, Clone, FromRow)]
+pub struct Passkey {
+    /// Base64url, as the browser reports it.
+    pub credential_id: String,
+    pub user_id: Uuid,
+    pub name: String,
+    /// COSE encoded.
+    pub public_key: Vec<u8>,
+    /// Last counter the authenticator reported, a counter going backwards means a cloned key.
+    pub sign_count: i64,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// A challenge waiting for an answer, kept in the browser in a private cookie.
+///
+/// The cookie can be replayed until it expires, so an answered challenge is
+/// spent server side and can't be answered again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PasskeyChallenge {
+    pub challenge: String,
+    /// Who is registering a key, none for a login.
+    pub registering: Option<Uuid>,
+    pub expires_at: DateTime<Utc>,
+}
+impl PasskeyChallenge {
+    pub fn new(registering: Option<Uuid>) -> Self {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        PasskeyChallenge {
+            challenge: URL_SAFE_NO_PAD.encode(bytes),
+            registering,
+            expires_at: Utc::now() + chrono::Duration::minutes(PASSKEY_CHALLENGE_MINUTES),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum PasskeyError {
+    #[error("The passkey response was rejected: {0}")]
+    Invalid(&'static str),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+use PasskeyError::Invalid;
+
+/// A public key read out of a registration.
+#[derive(Debug, Clone)]
+pub struct NewCredential {
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Check the browser's summary of a ceremony.
+fn check_client_data(
+    rp: &RelyingParty,
+    challenge: &PasskeyChallenge,
+    client_data_json: &[u8],
+    expected_type: &str,
+) -> Result<(), PasskeyError> {
+    let client_data: ClientData =
+        serde_json::from_slice(client_data_json).map_err(|_| Invalid("malformed client data"))?;
+    if client_data.type_ != expected_type {
+        return Err(Invalid("wrong ceremony"));
+    }
+    if client_data.challenge != challenge.challenge {
+        return Err(Invalid("wrong challenge"));
+    }
+    if client_data.origin != rp.origin {
+        return Err(Invalid("wrong origin"));
+    }
+    Ok(())
+}
+
+/// Authenticator data, up to the optional attested credential.
+struct AuthData<'a> {
+    flags: u8,
+    sign_count: u32,
+    rest: &'a [u8],
+}
+fn parse_auth_data<'a>(
+    rp: &RelyingParty,
+    auth_data: &'a [u8],
+) -> Result<AuthData<'a>, PasskeyError> {
+    if auth_data.len() < 37 {
+        return Err(Invalid("short authenticator data"));
+    }
+    if auth_data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err(Invalid("wrong relying party"));
+    }
+    let flags = auth_data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err(Invalid("user not present"));
+    }
+    Ok(AuthData {
+        flags,
+        sign_count: u32::from_be_bytes(auth_data[33..37].try_into().unwrap()),
+        rest: &auth_data[37..],
+    })
+}
+
+/// Check a registration, and read out the new credential.
+///
+/// * `challenge`: The challenge the registration was started with.
+/// * `client_data_json`: As returned by the browser.
+/// * `attestation_object`: CBOR, as returned by the browser.
+pub fn verify_registration(
+    rp: &RelyingParty,
+    challenge: &PasskeyChallenge,
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+) -> Result<NewCredential, PasskeyError> {
+    check_client_data(rp, challenge, client_data_json, "webauthn.create")?;
+    let (attestation, _) =
+        cbor::decode(attestation_object).ok_or(Invalid("malformed attestation"))?;
+    let auth_data = attestation
+        .get_text("authData")
+        .and_then(cbor::Value::as_bytes)
+        .ok_or(Invalid("missing authenticator data"))?;
+    let auth_data = parse_auth_data(rp, auth_data)?;
+    if auth_data.flags & FLAG_ATTESTED_DATA == 0 || auth_data.rest.len() < 18 {
+        return Err(Invalid("no credential"));
+    }
+    // Skip the authenticator's model id.
+    let rest = &auth_data.rest[16..];
+    let id_len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+    let credential_id = rest.get(2..2 + id_len).ok_or(Invalid("short credential"))?;
+    let key_bytes = &rest[2 + id_len..];
+    let (key, key_len) = cbor::decode(key_bytes).ok_or(Invalid("malformed public key"))?;
+    CoseKey::from_cbor(&key)?;
+    Ok(NewCredential {
+        credential_id: URL_SAFE_NO_PAD.encode(credential_id),
+        public_key: key_bytes[..key_len].to_vec(),
+        sign_count: auth_data.sign_count.into(),
+    })
+}
+
+/// Check a login with a stored passkey, returning the authenticator's new counter.
+///
+/// * `passkey`: The credential the browser said it used.
+/// * `authenticator_data`, `signature`, `client_data_json`: As returned by the browser.
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    challenge: &PasskeyChallenge,
+    passkey: &Passkey,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+) -> Result<i64, PasskeyError> {
+    check_client_data(rp, challenge, client_data_json, "webauthn.get")?;
+    let auth_data = parse_auth_data(rp, authenticator_data)?;
+    // Standing in for the password and second factor, so the user must be verified.
+    if auth_data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err(Invalid("user not verified"));
+    }
+    let (key, _) = cbor::decode(&passkey.public_key).ok_or(Invalid("malformed public key"))?;
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+    CoseKey::from_cbor(&key)?.verify(&signed, signature)?;
+    let sign_count = i64::from(auth_data.sign_count);
+    // Authenticators that don't count always report zero.
+    if (sign_count != 0 || passkey.sign_count != 0) && sign_count <= passkey.sign_count {
+        return Err(Invalid("signature counter went backwards"));
+    }
+    Ok(sign_count)
+}
+
+enum CoseKey {
+    Es256 { point: Vec<u8> },
+    Rs256 { key: RsaPublicKey },
+}
+impl CoseKey {
+    fn from_cbor(key: &cbor::Value) -> Result<Self, PasskeyError> {
+        let param = |label: i64| key.get_int(label);
+        let bytes = |label: i64| {
+            param(label)
+                .and_then(cbor::Value::as_bytes)
+                .ok_or(Invalid("malformed public key"))
+        };
+        match param(3).and_then(cbor::Value::as_int) {
+            // EC2 key on P-256.
+            Some(-7) if param(1).and_then(cbor::Value::as_int) == Some(2) => {
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                if param(-1).and_then(cbor::Value::as_int) != Some(1)
+                    || x.len() != 32
+                    || y.len() != 32
+                {
+                    return Err(Invalid("unsupported curve"));
+                }
+                let mut point = vec![0x04];
+                point.extend_from_slice(x);
+                point.extend_from_slice(y);
+                Ok(CoseKey::Es256 { point })
+            }
+            Some(-257) if param(1).and_then(cbor::Value::as_int) == Some(3) => Ok(CoseKey::Rs256 {
+                key: RsaPublicKey::new(
+                    BigUint::from_bytes_be(bytes(-1)?),
+                    BigUint::from_bytes_be(bytes(-2)?),
+                )
+                .map_err(|_| Invalid("malformed public key"))?,
+            }),
+            _ => Err(Invalid("unsupported algorithm")),
+        }
+    }
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), PasskeyError> {
+        let verified = match self {
+            Self::Es256 { point } => UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point)
+                .verify(message, signature)
+                .is_ok(),
+            Self::Rs256 { key } => key
+                .verify(
+                    Pkcs1v15Sign::new::<Sha256>(),
+                    &Sha256::digest(message),
+                    signature,
+                )
+                .is_ok(),
+        };
+        verified.then_some(()).ok_or(Invalid("bad signature"))
+    }
+}
+
+/// Just enough CBOR (RFC 8949) to read attestation objects and COSE keys.
+pub mod cbor {
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Value {
+        Int(i64),
+        Bytes(Vec<u8>),
+        Text(String),
+        Array(Vec<Value>),
+        Map(Vec<(Value, Value)>),
+        Bool(bool),
+        Null,
+    }
+    impl Value {
+        pub fn as_int(&self) -> Option<i64> {
+            match self {
+                Self::Int(i) => Some(*i),
+                _ => None,
+            }
+        }
+        pub fn as_bytes(&self) -> Option<&[u8]> {
+            match self {
+                Self::Bytes(b) => Some(b),
+                _ => None,
+            }
+        }
+        fn get(&self, key: &Value) -> Option<&Value> {
+            match self {
+                Self::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+                _ => None,
+            }
+        }
+        pub fn get_int(&self, key: i64) -> Option<&Value> {
+            self.get(&Value::Int(key))
+        }
+        pub fn get_text(&self, key: &str) -> Option<&Value> {
+            self.get(&Value::Text(key.to_string()))
+        }
+    }
+
+    /// Decode one item, returning it and the number of bytes it took up.
+    pub fn decode(bytes: &[u8]) -> Option<(Value, usize)> {
+        decode_depth(bytes, 0)
+    }
+
+    fn decode_depth(bytes: &[u8], depth: usize) -> Option<(Value, usize)> {
+        // Keys are shallow, anything deeper is malformed or hostile.
+        if depth > 8 {
+            return None;
+        }
+        let initial = *bytes.first()?;
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let (arg, mut used): (u64, usize) = match info {
+            0..=23 => (u64::from(info), 1),
+            24 => (u64::from(*bytes.get(1)?), 2),
+            25 => (
+                u64::from(u16::from_be_bytes(bytes.get(1..3)?.try_into().ok()?)),
+                3,
+            ),
+            26 => (
+                u64::from(u32::from_be_bytes(bytes.get(1..5)?.try_into().ok()?)),
+                5,
+            ),
+            27 => (u64::from_be_bytes(bytes.get(1..9)?.try_into().ok()?), 9),
+            _ => return None,
+        };
+        let value = match major {
+            0 => Value::Int(i64::try_from(arg).ok()?),
+            1 => Value::Int(-1 - i64::try_from(arg).ok()?),
+            2 | 3 => {
+                let end = used.checked_add(usize::try_from(arg).ok()?)?;
+                let content = bytes.get(used..end)?.to_vec();
+                used = end;
+                if major == 2 {
+                    Value::Bytes(content)
+                } else {
+                    Value::Text(String::from_utf8(content).ok()?)
+                }
+            }
+            4 => {
+                let mut items = Vec::new();
+                for _ in 0..arg {
+                    let (item, len) = decode_depth(bytes.get(used..)?, depth + 1)?;
+                    items.push(item);
+                    used += len;
+                }
+                Value::Array(items)
+            }
+            5 => {
+                let mut entries = Vec::new();
+                for _ in 0..arg {
+                    let (key, len) = decode_depth(bytes.get(used..)?, depth + 1)?;
+                    used += len;
+                    let (value, len) = decode_depth(bytes.get(used..)?, depth + 1)?;
+                    used += len;
+                    entries.push((key, value));
+                }
+                Value::Map(entries)
+            }
+            7 => match info {
+                20 => Value::Bool(false),
+                21 => Value::Bool(true),
+                22 => Value::Null,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some((value, used))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::cbor::{decode, Value};
+    use super::*;
+
+    #[test]
+    fn test_cbor_decode() {
+        // {1: 2, -1: h'0102', "fmt": "none", "a": [true, null]}
+        let bytes = [
+            0xa4, 0x01, 0x02, 0x20, 0x42, 0x01, 0x02, 0x63, b'f', b'm', b't', 0x64, b'n', b'o',
+            b'n', b'e', 0x61, b'a', 0x82, 0xf5, 0xf6,
+        ];
+        let (value, used) = decode(&bytes).unwrap();
+        assert_eq!(used, bytes.len());
+        assert_eq!(value.get_int(1), Some(&Value::Int(2)));
+        assert_eq!(
+            value.get_int(-1).and_then(Value::as_bytes),
+            Some(&[1, 2][..])
+        );
+        assert_eq!(value.get_text("fmt"), Some(&Value::Text("none".into())));
+        assert_eq!(
+            value.get_text("a"),
+            Some(&Value::Array(vec![Value::Bool(true), Value::Null]))
+        );
+        // Truncated input is refused rather than read past.
+        assert!(decode(&bytes[..bytes.len() - 1]).is_none());
+    }
+    #[test]
+    fn test_relying_party_from_origin() {
+        let rp = RelyingParty::from_origin("https://date-rs.shuttleapp.rs/").unwrap();
+        assert_eq!(rp.id, "date-rs.shuttleapp.rs");
+        assert_eq!(rp.origin, "https://date-rs.shuttleapp.rs");
+    }
+}
@@ -0,0 +1,222 @@
+//! Rules a new password has to meet, on registration, reset and change.
+//!
+//! Besides a minimum length, passwords get a rough strength score, in the spirit of zxcvbn,
+//! and are looked up in a local list of breached passwords. The list is kept by SHA-1 prefix,
+//! the same way the Pwned Passwords range API splits it, so lookups only touch one bucket.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use secrecy::{ExposeSecret, Secret};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+/// Characters of the hash each bucket of the breached list is keyed by.
+pub const BREACHED_PREFIX_LENGTH: usize = 5;
+/// Where the shipped breached password list lives.
+pub const BREACHED_PASSWORDS_FILE: &str = "./passwords/breached_sha1.txt";
+
+/// Keyboard rows, walking along one is as guessable as counting.
+const KEYBOARD_ROWS: [&str; 4] = ["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PasswordPolicyError {
+    #[error("Use at least {0} characters.")]
+    TooShort(usize),
+    #[error("That password is too easy to guess, try a longer one or a few unrelated words.")]
+    TooWeak,
+    #[error("That password has appeared in a data breach, choose another.")]
+    Breached,
+}
+
+/// What a new password has to be.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// Lowest `strength_score` allowed, from 0 to 4.
+    pub min_score: u8,
+    pub breached: BreachedPasswords,
+}
+impl Default for PasswordPolicy {
+    /// Without a breached list, load one with `BreachedPasswords::load`.
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            min_score: 2,
+            breached: BreachedPasswords::default(),
+        }
+    }
+}
+impl PasswordPolicy {
+    /// Check a new password, returning the first rule it breaks.
+    pub fn check(&self, password: &Secret<String>) -> Result<(), PasswordPolicyError> {
+        let password = password.expose_secret();
+        if password.chars().count() < self.min_length {
+            return Err(PasswordPolicyError::TooShort(self.min_length));
+        }
+        if self.breached.contains(password) {
+            return Err(PasswordPolicyError::Breached);
+        }
+        if strength_score(password) < self.min_score {
+            return Err(PasswordPolicyError::TooWeak);
+        }
+        Ok(())
+    }
+}
+
+/// SHA-1 hashes of breached passwords, bucketed by prefix.
+#[derive(Debug, Clone, Default)]
+pub struct BreachedPasswords {
+    buckets: HashMap<String, HashSet<String>>,
+}
+impl BreachedPasswords {
+    /// Load a list in the Pwned Passwords format, one `HASH` or `HASH:COUNT` per line.
+    ///
+    /// Blank lines and lines starting with `#` are skipped.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let list = fs::read_to_string(path.as_ref()).with_context(|| {
+            format!(
+                "Reading breached passwords from {} failed",
+                path.as_ref().display()
+            )
+        })?;
+        Self::parse(&list)
+    }
+    pub fn parse(list: &str) -> anyhow::Result<Self> {
+        let mut buckets: HashMap<String, HashSet<String>> = HashMap::new();
+        for line in list.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let hash = line.split(':').next().unwrap_or_default().to_uppercase();
+            if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(anyhow::anyhow!("Not a SHA-1 hash: {}", line));
+            }
+            let (prefix, suffix) = hash.split_at(BREACHED_PREFIX_LENGTH);
+            buckets
+                .entry(prefix.to_string())
+                .or_default()
+                .insert(suffix.to_string());
+        }
+        Ok(Self { buckets })
+    }
+    pub fn len(&self) -> usize {
+        self.buckets.values().map(HashSet::len).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+    pub fn contains(&self, password: &str) -> bool {
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(BREACHED_PREFIX_LENGTH);
+        self.buckets
+            .get(prefix)
+            .is_some_and(|bucket| bucket.contains(suffix))
+    }
+}
+
+/// Rough strength of a password, from 0, trivial to guess, to 4, very hard.
+///
+/// Guesses are estimated from the size of the alphabet used and the length,
+/// where repeated characters and runs, like `abc` or `qwe`, count for little.
+pub fn strength_score(password: &str) -> u8 {
+    let chars: Vec<char> = password.chars().collect();
+    let mut alphabet = 0;
+    if chars.iter().any(char::is_ascii_lowercase) {
+        alphabet += 26;
+    }
+    if chars.iter().any(char::is_ascii_uppercase) {
+        alphabet += 26;
+    }
+    if chars.iter().any(char::is_ascii_digit) {
+        alphabet += 10;
+    }
+    if chars
+        .iter()
+        .any(|c| c.is_ascii() && !c.is_ascii_alphanumeric())
+    {
+        alphabet += 33;
+    }
+    if chars.iter().any(|c| !c.is_ascii()) {
+        alphabet += 100;
+    }
+    let mut length = 0.0;
+    for (i, c) in chars.iter().enumerate() {
+        let predictable = i > 0 && {
+            let previous = chars[i - 1];
+            previous == *c || follows(previous, *c)
+        };
+        length += if predictable { 0.25 } else { 1.0 };
+    }
+    let bits = length * f64::from(alphabet.max(1)).log2();
+    match bits {
+        b if b < 25.0 => 0,
+        b if b < 40.0 => 1,
+        b if b < 55.0 => 2,
+        b if b < 70.0 => 3,
+        _ => 4,
+    }
+}
+
+/// Whether `next` comes straight after, or before, `previous` in the alphabet or on a keyboard.
+fn follows(previous: char, next: char) -> bool {
+    let (previous, next) = (previous.to_ascii_lowercase(), next.to_ascii_lowercase());
+    if previous.is_ascii_alphanumeric() && (next as i32 - previous as i32).abs() == 1 {
+        return true;
+    }
+    KEYBOARD_ROWS.iter().any(|row| {
+        row.find(previous)
+            .zip(row.find(next))
+            .is_some_and(|(p, n)| p.abs_diff(n) == 1)
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strength_score() {
+        assert_eq!(strength_score(""), 0);
+        assert_eq!(strength_score("aaaaaaaaaaaa"), 0);
+        assert_eq!(strength_score("abcdefghijkl"), 0);
+        assert_eq!(strength_score("qwertyuiop"), 0);
+        assert!(strength_score("assword") < 2);
+        assert!(strength_score("new_assword") >= 2);
+        assert_eq!(strength_score("correct horse battery staple"), 4);
+    }
+
+    #[test]
+    fn test_breached_lookup() -> anyhow::Result<()> {
+        let breached = BreachedPasswords::parse(
+            "# comment\n5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8:3861493\n\n",
+        )?;
+        assert_eq!(breached.len(), 1);
+        assert!(breached.contains("password"));
+        assert!(!breached.contains("Password"));
+        assert!(BreachedPasswords::parse("not a hash").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shipped_list() -> anyhow::Result<()> {
+        let policy = PasswordPolicy {
+            breached: BreachedPasswords::load(BREACHED_PASSWORDS_FILE)?,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.check(&Secret::new("short".into())),
+            Err(PasswordPolicyError::TooShort(8))
+        );
+        assert_eq!(
+            policy.check(&Secret::new("P@ssw0rd".into())),
+            Err(PasswordPolicyError::Breached)
+        );
+        assert_eq!(
+            policy.check(&Secret::new("aaaaaaaaaaaaaaaa".into())),
+            Err(PasswordPolicyError::TooWeak)
+        );
+        assert_eq!(policy.check(&Secret::new("new_assword".into())), Ok(()));
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Proof-of-work bot protection for registration and login.
+//!
+//! The server hands out challenges signed with its key, so nothing is stored when issuing one.
+//! The browser searches for a counter such that `sha256("{challenge}:{counter}")` starts with
+//! as many zero bits as the challenge's difficulty, and sends it back with the form.
+//! Checking a solution is one hash, while finding it takes about `2^difficulty`.
+//! Spent challenges are recorded so each is only good for one request, and the number spent
+//! recently sets the difficulty, so it climbs while the site is under load.
+use actix_web::cookie::Key;
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use shuttle_runtime::async_trait;
+use thiserror::Error;
+
+pub const CHALLENGE_FIELD: &str = "pow_challenge";
+pub const SOLUTION_FIELD: &str = "pow_solution";
+/// How long a challenge can be solved and spent for.
+pub const CHALLENGE_LIFETIME_MINUTES: i64 = 10;
+/// Solutions spent within this window count towards the load.
+pub const LOAD_WINDOW_MINUTES: i64 = 1;
+
+#[derive(Error, Debug)]
+pub enum ProofOfWorkError {
+    #[error("Your browser didn't solve the anti-bot challenge, make sure javascript is enabled.")]
+    Missing,
+    #[error("The anti-bot challenge wasn't valid, try again.")]
+    Invalid,
+    #[error("The anti-bot challenge expired, try again.")]
+    Expired,
+    #[error("The anti-bot challenge wasn't solved, try again.")]
+    Unsolved,
+    #[error("The site got busier, so the anti-bot challenge is harder now, try again.")]
+    TooEasy,
+    #[error("That anti-bot challenge was already used, try again.")]
+    Spent,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+/// How hard challenges are, and how quickly they get harder under load.
+#[derive(Debug, Clone)]
+pub struct ProofOfWork {
+    /// Leading zero bits asked for when the site is quiet.
+    pub base_difficulty: u8,
+    /// Difficulty never climbs past this, so real users can still get in.
+    pub max_difficulty: u8,
+    /// Solutions a minute before difficulty rises, each doubling of the load adds a bit.
+    pub solutions_per_step: i64,
+}
+impl Default for ProofOfWork {
+    fn default() -> Self {
+        Self {
+            base_difficulty: 16,
+            max_difficulty: 22,
+            solutions_per_step: 30,
+        }
+    }
+}
+impl ProofOfWork {
+    /// Difficulty for new challenges, given the solutions spent in the last load window.
+    pub fn difficulty(&self, recent_solutions: i64) -> u8 {
+        if recent_solutions < self.solutions_per_step.max(1) {
+            return self.base_difficulty;
+        }
+        let steps = (recent_solutions / self.solutions_per_step.max(1)).ilog2() + 1;
+        self.base_difficulty
+            .saturating_add(steps.min(u8::MAX.into()) as u8)
+            .min(self.max_difficulty)
+    }
+}
+
+/// A challenge as handed to the browser.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Challenge {
+    pub nonce: String,
+    pub difficulty: u8,
+    pub expires_at: i64,
+}
+impl Challenge {
+    pub fn new(difficulty: u8, now: DateTime<Utc>) -> Self {
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self {
+            nonce: hex::encode(bytes),
+            difficulty,
+            expires_at: (now + Duration::minutes(CHALLENGE_LIFETIME_MINUTES)).timestamp(),
+        }
+    }
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.expires_at, 0).unwrap_or_default()
+    }
+    /// The challenge and its signature, as one string for the form.
+    pub fn sign(&self, key: &Key) -> String {
+        let unsigned = self.unsigned();
+        let mac = challenge_mac(&unsigned, key).finalize().into_bytes();
+        format!("{}.{}", unsigned, hex::encode(mac))
+    }
+    /// Read back a challenge this server signed, refusing expired ones.
+    pub fn verify(signed: &str, key: &Key, now: DateTime<Utc>) -> Result<Self, ProofOfWorkError> {
+        let (unsigned, mac) = signed.rsplit_once('.').ok_or(ProofOfWorkError::Invalid)?;
+        let mac = hex::decode(mac).map_err(|_| ProofOfWorkError::Invalid)?;
+        challenge_mac(unsigned, key)
+            .verify_slice(&mac)
+            .map_err(|_| ProofOfWorkError::Invalid)?;
+        let mut parts = unsigned.split('.');
+        let (Some(nonce), Some(difficulty), Some(expires_at), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ProofOfWorkError::Invalid);
+        };
+        let challenge = Self {
+            nonce: nonce.to_string(),
+            difficulty: difficulty.parse().map_err(|_| ProofOfWorkError::Invalid)?,
+            expires_at: expires_at.parse().map_err(|_| ProofOfWorkError::Invalid)?,
+        };
+        if challenge.expires_at() <= now {
+            return Err(ProofOfWorkError::Expired);
+        }
+        Ok(challenge)
+    }
+    fn unsigned(&self) -> String {
+        format!("{}.{}.{}", self.nonce, self.difficulty, self.expires_at)
+    }
+}
+
+fn challenge_mac(unsigned: &str, key: &Key) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.signing()).expect("Hmac takes any key length.");
+    mac.update(b"pow:");
+    mac.update(unsigned.as_bytes());
+    mac
+}
+
+/// Whether a counter solves a signed challenge at a difficulty.
+pub fn is_solution(signed: &str, solution: &str, difficulty: u8) -> bool {
+    solution.parse::<u64>().is_ok()
+        && leading_zero_bits(&Sha256::digest(format!("{}:{}", signed, solution)))
+            >= u32::from(difficulty)
+}
+
+/// Find the counter solving a signed challenge, the same search `pow.js` runs.
+pub fn solve(signed: &str, difficulty: u8) -> u64 {
+    (0..)
+        .find(|counter| is_solution(signed, &counter.to_string(), difficulty))
+        .expect("Some counter solves any difficulty below 64.")
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[async_trait]
+pub trait ChallengeRepository {
+    /// Mark a challenge as used, forgetting ones that have expired.
+    ///
+    /// Returns false if it had already been spent.
+    async fn spend_challenge(&self, nonce: &str, expires_at: DateTime<Utc>)
+        -> anyhow::Result<bool>;
+    /// Number of challenges spent since a time.
+    async fn count_spent_challenges(&self, since: DateTime<Utc>) -> anyhow::Result<i64>;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_difficulty_rises_with_load() {
+        let pow = ProofOfWork::default();
+        assert_eq!(pow.difficulty(0), 16);
+        assert_eq!(pow.difficulty(29), 16);
+        assert_eq!(pow.difficulty(30), 17);
+        assert_eq!(pow.difficulty(60), 18);
+        assert_eq!(pow.difficulty(130), 19);
+        assert_eq!(pow.difficulty(i64::MAX), 22);
+    }
+
+    #[test]
+    fn test_signed_challenge() {
+        let key = Key::generate();
+        let now = Utc::now();
+        let challenge = Challenge::new(8, now);
+        let signed = challenge.sign(&key);
+        assert_eq!(Challenge::verify(&signed, &key, now).unwrap(), challenge);
+        assert!(matches!(
+            Challenge::verify(&signed, &Key::generate(), now),
+            Err(ProofOfWorkError::Invalid)
+        ));
+        // Lowering the difficulty breaks the signature.
+        let easier = signed.replacen(".8.", ".0.", 1);
+        assert!(matches!(
+            Challenge::verify(&easier, &key, now),
+            Err(ProofOfWorkError::Invalid)
+        ));
+        assert!(matches!(
+            Challenge::verify(&signed, &key, challenge.expires_at()),
+            Err(ProofOfWorkError::Expired)
+        ));
+    }
+
+    #[test]
+    fn test_solve() {
+        let signed = Challenge::new(10, Utc::now()).sign(&Key::generate());
+        let solution = solve(&signed, 10).to_string();
+        assert!(is_solution(&signed, &solution, 10));
+        assert!(!is_solution(&signed, "not a number", 0));
+        assert_eq!(leading_zero_bits(&[0, 0b0001_0000, 0xff]), 11);
+    }
+}
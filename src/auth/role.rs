@@ -0,0 +1,204 @@
+//! Roles within a group, and what each may do.
+//!
+//! Every group has one owner, who alone can hand the group on, delete it, change roles
+//! and change its settings. Everything else is set by the group's permission matrix,
+//! which names the least role allowed to do each thing. Members can always edit the
+//! dates they suggested.
+use std::collections::HashMap;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::error;
+use uuid::Uuid;
+
+/// A member's standing in their group, ordered from least to most powerful.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Member,
+    Admin,
+    Owner,
+}
+impl Role {
+    pub const ALL: [Role; 3] = [Self::Member, Self::Admin, Self::Owner];
+    /// Name the role is stored as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Member => "member",
+            Self::Admin => "admin",
+            Self::Owner => "owner",
+        }
+    }
+    pub fn parse(role: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.as_str() == role)
+    }
+}
+
+/// Something the permission matrix controls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    SuggestDates,
+    Vote,
+    EditAnyDate,
+    DeleteDates,
+    ApproveDates,
+    ApproveMembers,
+    RemoveMembers,
+    EditGroupProfile,
+}
+impl Permission {
+    pub const ALL: [Permission; 8] = [
+        Self::SuggestDates,
+        Self::Vote,
+        Self::EditAnyDate,
+        Self::DeleteDates,
+        Self::ApproveDates,
+        Self::ApproveMembers,
+        Self::RemoveMembers,
+        Self::EditGroupProfile,
+    ];
+    /// Name the permission is stored as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SuggestDates => "suggest_dates",
+            Self::Vote => "vote",
+            Self::EditAnyDate => "edit_any_date",
+            Self::DeleteDates => "delete_dates",
+            Self::ApproveDates => "approve_dates",
+            Self::ApproveMembers => "approve_members",
+            Self::RemoveMembers => "remove_members",
+            Self::EditGroupProfile => "edit_group_profile",
+        }
+    }
+    pub fn parse(permission: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.as_str() == permission)
+    }
+    /// How the permission is described on the settings page.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::SuggestDates => "Suggest dates",
+            Self::Vote => "Vote on dates",
+            Self::EditAnyDate => "Edit dates others suggested",
+            Self::DeleteDates => "Delete dates",
+            Self::ApproveDates => "Approve or reject dates",
+            Self::ApproveMembers => "Answer requests to join",
+            Self::RemoveMembers => "Remove members",
+            Self::EditGroupProfile => "Edit the group's name and description",
+        }
+    }
+    /// Least role allowed, for groups that haven't changed it.
+    pub fn default_role(&self) -> Role {
+        match self {
+            Self::SuggestDates | Self::Vote | Self::ApproveMembers => Role::Member,
+            Self::EditAnyDate
+            | Self::DeleteDates
+            | Self::ApproveDates
+            | Self::RemoveMembers
+            | Self::EditGroupProfile => Role::Admin,
+        }
+    }
+}
+
+/// The least role allowed each permission in a group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionMatrix(HashMap<Permission, Role>);
+impl Default for PermissionMatrix {
+    fn default() -> Self {
+        Self(
+            Permission::ALL
+                .into_iter()
+                .map(|p| (p, p.default_role()))
+                .collect(),
+        )
+    }
+}
+impl PermissionMatrix {
+    /// Least role allowed a permission.
+    pub fn required(&self, permission: Permission) -> Role {
+        self.0
+            .get(&permission)
+            .copied()
+            .unwrap_or(permission.default_role())
+    }
+    pub fn allows(&self, role: Role, permission: Permission) -> bool {
+        role >= self.required(permission)
+    }
+    pub fn set(&mut self, permission: Permission, role: Role) {
+        self.0.insert(permission, role);
+    }
+}
+
+/// A member of a group, as listed on its settings page.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupMember {
+    pub user_id: Uuid,
+    pub email: String,
+    pub role: Role,
+}
+
+/// A group a user belongs to, as offered by the group switcher.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupMembership {
+    pub user_group: i32,
+    pub name: String,
+    pub emoji: String,
+    pub role: Role,
+}
+
+/// Why a change to a group or its dates was refused.
+#[derive(Error, Debug)]
+pub enum RoleError {
+    #[error("Your role in the group doesn't allow that.")]
+    Forbidden,
+    #[error("They aren't a member of your group.")]
+    NotMember,
+    #[error("That date doesn't exist.")]
+    DateNotFound,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+impl ResponseError for RoleError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::NotMember | Self::DateNotFound => StatusCode::NOT_FOUND,
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::UnexpectedError(e) => {
+                error!("{:?}", e);
+                HttpResponse::InternalServerError().body("Server Error.")
+            }
+            e => HttpResponse::build(e.status_code()).body(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles_ordered() {
+        assert!(Role::Owner > Role::Admin);
+        assert!(Role::Admin > Role::Member);
+        for role in Role::ALL {
+            assert_eq!(Role::parse(role.as_str()), Some(role));
+        }
+    }
+    #[test]
+    fn test_permission_matrix() {
+        let mut matrix = PermissionMatrix::default();
+        assert!(matrix.allows(Role::Member, Permission::Vote));
+        assert!(!matrix.allows(Role::Member, Permission::DeleteDates));
+        assert!(matrix.allows(Role::Admin, Permission::DeleteDates));
+        assert!(matrix.allows(Role::Owner, Permission::RemoveMembers));
+        matrix.set(Permission::Vote, Role::Admin);
+        matrix.set(Permission::DeleteDates, Role::Member);
+        assert!(!matrix.allows(Role::Member, Permission::Vote));
+        assert!(matrix.allows(Role::Member, Permission::DeleteDates));
+    }
+}
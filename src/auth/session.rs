@@ -0,0 +1,270 @@
+//! Cookie based sessions.
+//!
+//! Logging in hands the browser a signed cookie holding a session token.
+//! Every request that needs a user resolves them from that cookie,
+//! rather than trusting an id in the url.
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::{
+    cookie::{time, Cookie, CookieJar, Key, SameSite},
+    dev::Payload,
+    error::{ErrorInternalServerError, InternalError},
+    http::header,
+    web::Data,
+    FromRequest, HttpRequest,
+};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use shuttle_runtime::async_trait;
+use tracing::info;
+use uuid::Uuid;
+
+use super::token::Token;
+use super::user::{AuthorizedUser, HalfAuthorizedUser, UserValidationError};
+use crate::domain::repository::AppState;
+use crate::routes::landing::unauthorized;
+
+pub const SESSION_COOKIE: &str = "date_rs_session";
+/// How long a session lasts before the user has to log in again.
+pub const SESSION_LENGTH_DAYS: i64 = 30;
+
+#[async_trait]
+pub trait SessionRepository {
+    /// Start a new session for a user.
+    ///
+    /// * `user_id`: The logged in user.
+    /// * `device`: Where they logged in from.
+    async fn create_session(&self, user_id: &Uuid, device: &SessionDevice)
+        -> anyhow::Result<Token>;
+    /// Start a short session for a user who still has to give their second factor.
+    async fn create_pending_session(&self, user_id: &Uuid) -> anyhow::Result<Token>;
+    /// Get the user a session belongs to, if the session hasn't expired, marking it as seen.
+    ///
+    /// Pending sessions are refused.
+    /// * `token`: Session token from the user's cookie.
+    async fn get_session_user(&self, token: &Token) -> Result<AuthorizedUser, UserValidationError>;
+    /// Get the user a pending session belongs to, if it hasn't expired.
+    async fn get_pending_session_user(
+        &self,
+        token: &Token,
+    ) -> Result<HalfAuthorizedUser, UserValidationError>;
+    /// End a single session.
+    async fn remove_session(&self, token: &Token) -> anyhow::Result<()>;
+    /// End every session a user has.
+    async fn remove_user_sessions(&self, user_id: &Uuid) -> anyhow::Result<()>;
+    /// A user's full sessions that haven't expired, most recently seen first.
+    ///
+    /// * `current`: Session of the browser asking, which is marked.
+    async fn list_sessions(
+        &self,
+        user_id: &Uuid,
+        current: &Token,
+    ) -> anyhow::Result<Vec<ActiveSession>>;
+    /// End one of a user's sessions, false if they don't have it.
+    async fn remove_user_session(&self, user_id: &Uuid, session_id: &Uuid) -> anyhow::Result<bool>;
+    /// Note that a user signed in from a device, saying whether it was seen before.
+    async fn remember_device(
+        &self,
+        user_id: &Uuid,
+        device: &SessionDevice,
+    ) -> anyhow::Result<DeviceHistory>;
+}
+
+/// The address a request came from.
+///
+/// Forwarding headers are only believed from the app's trusted proxies,
+/// anyone else could set them to whatever address they like.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    let trusted = req
+        .app_data::<Data<AppState>>()
+        .is_some_and(|state| state.trusted_proxies.contains(&peer));
+    if trusted {
+        req.connection_info()
+            .realip_remote_addr()
+            .map(str::to_string)
+    } else {
+        Some(peer.to_string())
+    }
+}
+/// Where a session was started from.
+#[derive(Debug, Clone, Default)]
+pub struct SessionDevice {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+impl SessionDevice {
+    /// Longest user agent kept, anything after is cut off.
+    pub const USER_AGENT_LENGTH: usize = 256;
+    pub fn from_request(req: &HttpRequest) -> Self {
+        SessionDevice {
+            user_agent: req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.chars().take(Self::USER_AGENT_LENGTH).collect()),
+            ip: client_ip(req),
+        }
+    }
+    /// Hex encoded sha256 hash of the browser and address, what known devices are stored as.
+    pub fn fingerprint(&self) -> String {
+        let mut hash = Sha256::new();
+        hash.update(self.user_agent.as_deref().unwrap_or_default());
+        hash.update(b"\n");
+        hash.update(self.ip.as_deref().unwrap_or_default());
+        hex::encode(hash.finalize())
+    }
+    /// How the device is described in emails, by its user agent and address.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} from {}",
+            self.user_agent.as_deref().unwrap_or("an unknown browser"),
+            self.ip.as_deref().unwrap_or("an unknown address")
+        )
+    }
+}
+
+/// Whether a user has signed in from a device before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceHistory {
+    Known,
+    /// The user's first device, there's nothing to compare it with.
+    First,
+    New,
+}
+
+/// A session as listed on the sessions page, the token itself is never stored.
+#[derive(Debug, Clone)]
+pub struct ActiveSession {
+    pub session_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    /// Whether this is the session of the browser that asked.
+    pub current: bool,
+}
+
+/// Build the signed cookie that carries a session token.
+pub fn session_cookie(token: &Token, key: &Key) -> Cookie<'static> {
+    let cookie = Cookie::build(SESSION_COOKIE, token.expose().to_string())
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::days(SESSION_LENGTH_DAYS))
+        .finish();
+    let mut jar = CookieJar::new();
+    jar.signed_mut(key).add(cookie);
+    jar.get(SESSION_COOKIE).unwrap().clone()
+}
+
+/// Cookie that clears the session cookie from the browser.
+pub fn removal_cookie() -> Cookie<'static> {
+    let mut cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    cookie
+}
+
+/// Build an encrypted cookie, for state a multi step login has to keep in the browser.
+///
+/// * `path`: Only sent to routes below this.
+/// * `minutes`: How long the step may take.
+pub fn private_cookie(
+    name: &'static str,
+    value: String,
+    path: &'static str,
+    minutes: i64,
+    key: &Key,
+) -> Cookie<'static> {
+    let cookie = Cookie::build(name, value)
+        .path(path)
+        .http_only(true)
+        .secure(true)
+        // Lax, so it is sent along when another site redirects back.
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(minutes))
+        .finish();
+    let mut jar = CookieJar::new();
+    jar.private_mut(key).add(cookie);
+    jar.get(name).unwrap().clone()
+}
+
+/// Cookie that clears a private cookie from the browser.
+pub fn private_removal_cookie(name: &'static str, path: &'static str) -> Cookie<'static> {
+    let mut cookie = Cookie::build(name, "").path(path).finish();
+    cookie.make_removal();
+    cookie
+}
+
+/// Read an encrypted cookie, if the request carries one that decrypts.
+pub fn read_private_cookie(req: &HttpRequest, name: &str, key: &Key) -> Option<String> {
+    let mut jar = CookieJar::new();
+    jar.add_original(req.cookie(name)?);
+    let cookie = jar.private(key).get(name)?;
+    Some(cookie.value().to_string())
+}
+
+/// Read the session token from a request, if it carries a correctly signed one.
+pub fn session_token(req: &HttpRequest, key: &Key) -> Option<Token> {
+    let mut jar = CookieJar::new();
+    jar.add_original(req.cookie(SESSION_COOKIE)?);
+    let cookie = jar.signed(key).get(SESSION_COOKIE)?;
+    Some(Token::from(cookie.value().to_string()))
+}
+
+/// Resolve the logged in user from their session cookie.
+impl FromRequest for AuthorizedUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let app_state = req
+                .app_data::<Data<AppState>>()
+                .ok_or(ErrorInternalServerError("App state missing."))?;
+            let Some(token) = session_token(&req, &app_state.session_key) else {
+                return Err(not_logged_in());
+            };
+            app_state.repo.get_session_user(&token).await.map_err(|e| {
+                info!("Rejected session: {}", e);
+                not_logged_in()
+            })
+        })
+    }
+}
+
+/// Resolve a user part way through logging in from their pending session cookie.
+impl FromRequest for HalfAuthorizedUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let app_state = req
+                .app_data::<Data<AppState>>()
+                .ok_or(ErrorInternalServerError("App state missing."))?;
+            let Some(token) = session_token(&req, &app_state.session_key) else {
+                return Err(not_logged_in());
+            };
+            app_state
+                .repo
+                .get_pending_session_user(&token)
+                .await
+                .map_err(|e| {
+                    info!("Rejected pending session: {}", e);
+                    not_logged_in()
+                })
+        })
+    }
+}
+
+fn not_logged_in() -> actix_web::Error {
+    match unauthorized() {
+        Ok(resp) => InternalError::from_response("Not logged in.", resp).into(),
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,136 @@
+//! Login throttling.
+//!
+//! Failed logins are counted per email and per client ip, and sign-in,
+//! verification and password reset links emailed out are counted the same way.
+//! After a few free attempts each further one has to wait exponentially longer,
+//! and enough failures lock the subject out for a while.
+use chrono::{DateTime, Duration, Utc};
+use shuttle_runtime::async_trait;
+
+/// Failures allowed before attempts start being slowed down.
+pub const FREE_ATTEMPTS: i32 = 3;
+/// Failures that lock a subject out.
+pub const LOCKOUT_ATTEMPTS: i32 = 10;
+pub const LOCKOUT_MINUTES: i64 = 15;
+/// Longest wait between two attempts, before a lockout.
+pub const MAX_BACKOFF_SECONDS: i64 = 120;
+/// Failures older than this are forgotten.
+pub const FAILURE_WINDOW_HOURS: i64 = 1;
+
+/// Something logins are counted against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThrottleSubject<'a> {
+    Email(&'a str),
+    Ip(&'a str),
+    /// Sign-in links sent to an address.
+    EmailLink(&'a str),
+    /// Verification links sent again to an address.
+    Verification(&'a str),
+    /// Password reset links sent to an address.
+    PasswordReset(&'a str),
+    /// Password reset links asked for from a client ip.
+    PasswordResetIp(&'a str),
+}
+impl ThrottleSubject<'_> {
+    pub fn kind(&self) -> i32 {
+        match self {
+            Self::Email(_) => 0,
+            Self::Ip(_) => 1,
+            Self::EmailLink(_) => 2,
+            Self::Verification(_) => 3,
+            Self::PasswordReset(_) => 4,
+            Self::PasswordResetIp(_) => 5,
+        }
+    }
+    pub fn value(&self) -> &str {
+        match self {
+            Self::Email(v)
+            | Self::Ip(v)
+            | Self::EmailLink(v)
+            | Self::Verification(v)
+            | Self::PasswordReset(v)
+            | Self::PasswordResetIp(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ThrottleState {
+    pub failures: i32,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+impl ThrottleState {
+    /// How long the subject has to wait before they can try again, if at all.
+    pub fn retry_after(&self, now: DateTime<Utc>) -> Option<Duration> {
+        if let Some(locked_until) = self.locked_until {
+            if locked_until > now {
+                return Some(locked_until - now);
+            }
+        }
+        let wait = backoff(self.failures) - (now - self.last_failure?);
+        (wait > Duration::zero()).then_some(wait)
+    }
+    pub fn is_locked(&self, now: DateTime<Utc>) -> bool {
+        self.locked_until.is_some_and(|t| t > now)
+    }
+    /// True for the failure that caused a lockout.
+    pub fn just_locked(&self) -> bool {
+        self.failures == LOCKOUT_ATTEMPTS
+    }
+}
+
+/// Wait required after a number of failures.
+pub fn backoff(failures: i32) -> Duration {
+    if failures < FREE_ATTEMPTS {
+        return Duration::zero();
+    }
+    let exponent = (failures - FREE_ATTEMPTS).min(16) as u32;
+    Duration::seconds(2_i64.pow(exponent).min(MAX_BACKOFF_SECONDS))
+}
+
+#[async_trait]
+pub trait ThrottleRepository {
+    /// Get the failed login record for a subject.
+    async fn get_throttle(&self, subject: &ThrottleSubject) -> anyhow::Result<ThrottleState>;
+    /// Count a failed login against a subject, locking them out if they have too many.
+    ///
+    /// Returns the updated record.
+    async fn record_failure(&self, subject: &ThrottleSubject) -> anyhow::Result<ThrottleState>;
+    /// Forget a subject's failed logins, and lift any lockout.
+    async fn clear_throttle(&self, subject: &ThrottleSubject) -> anyhow::Result<()>;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows() {
+        assert_eq!(backoff(0), Duration::zero());
+        assert_eq!(backoff(FREE_ATTEMPTS - 1), Duration::zero());
+        assert_eq!(backoff(FREE_ATTEMPTS), Duration::seconds(1));
+        assert_eq!(backoff(FREE_ATTEMPTS + 2), Duration::seconds(4));
+        assert_eq!(backoff(100), Duration::seconds(MAX_BACKOFF_SECONDS));
+    }
+    #[test]
+    fn test_retry_after() {
+        let now = Utc::now();
+        let state = ThrottleState {
+            failures: FREE_ATTEMPTS + 1,
+            last_failure: Some(now),
+            locked_until: None,
+        };
+        assert_eq!(state.retry_after(now), Some(Duration::seconds(2)));
+        assert_eq!(state.retry_after(now + Duration::seconds(3)), None);
+        let locked = ThrottleState {
+            locked_until: Some(now + Duration::minutes(LOCKOUT_MINUTES)),
+            ..state
+        };
+        assert!(locked.is_locked(now));
+        assert_eq!(
+            locked.retry_after(now),
+            Some(Duration::minutes(LOCKOUT_MINUTES))
+        );
+        assert_eq!(ThrottleState::default().retry_after(now), None);
+    }
+}
@@ -0,0 +1,131 @@
+use rand::RngCore;
+use secrecy::{ExposeSecret, Secret};
+use sha2::{Digest, Sha256};
+use shuttle_runtime::async_trait;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Random secret handed to a user, eg in a cookie or an emailed link.
+///
+/// Only the hash of a token is ever stored, so reading the database
+/// doesn't let you impersonate anyone.
+#[derive(Clone)]
+pub struct Token(Secret<String>);
+impl Token {
+    /// Create a new token from 32 random bytes.
+    pub fn generate() -> Token {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Token(Secret::new(hex::encode(bytes)))
+    }
+    /// Hex encoded sha256 hash of the token, this is what gets stored.
+    pub fn hash(&self) -> String {
+        hex::encode(Sha256::digest(self.0.expose_secret().as_bytes()))
+    }
+    pub fn expose(&self) -> &str {
+        self.0.expose_secret()
+    }
+}
+impl From<String> for Token {
+    fn from(value: String) -> Self {
+        Token(Secret::new(value))
+    }
+}
+
+/// What a token may be used for, a token for one purpose can't be used for another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(i32)]
+pub enum TokenPurpose {
+    EmailVerification,
+    PasswordReset,
+    /// Carries the new email address as its payload.
+    EmailChange,
+    AccountUnlock,
+    AccountDeletionCancel,
+    /// Carries the hash of the requesting browser's nonce as its payload.
+    EmailLogin,
+    /// Sent with a new device alert, reports the sign-in as not the user's.
+    SignInDenial,
+}
+
+#[derive(Error, Debug)]
+pub enum TokenError {
+    #[error("Token doesn't exist.")]
+    Invalid,
+    #[error("Token has expired.")]
+    Expired,
+    #[error("Token has already been used.")]
+    Used,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+#[async_trait]
+pub trait TokenRepository {
+    /// Issue a single use token to a user.
+    ///
+    /// * `user_id`: User the token is for.
+    /// * `purpose`: What the token can be used for.
+    /// * `valid_for`: How long until the token expires.
+    async fn create_token(
+        &self,
+        user_id: &Uuid,
+        purpose: TokenPurpose,
+        valid_for: chrono::Duration,
+    ) -> anyhow::Result<Token> {
+        self.create_token_with_payload(user_id, purpose, valid_for, None)
+            .await
+    }
+    /// Use up a token, returning the id of the user it was issued to.
+    ///
+    /// * `token`: Token from the user.
+    /// * `purpose`: What the token is being used for.
+    async fn consume_token(
+        &self,
+        token: &Token,
+        purpose: TokenPurpose,
+    ) -> Result<Uuid, TokenError> {
+        Ok(self.consume_token_with_payload(token, purpose).await?.0)
+    }
+    // To implement
+    /// Issue a single use token, that stores some data until it is used.
+    ///
+    /// * `payload`: Data that is handed back when the token is used.
+    async fn create_token_with_payload(
+        &self,
+        user_id: &Uuid,
+        purpose: TokenPurpose,
+        valid_for: chrono::Duration,
+        payload: Option<String>,
+    ) -> anyhow::Result<Token>;
+    /// Use up a token, returning the user it was issued to and its payload.
+    async fn consume_token_with_payload(
+        &self,
+        token: &Token,
+        purpose: TokenPurpose,
+    ) -> Result<(Uuid, Option<String>), TokenError>;
+    /// Check a token could be used, without using it up.
+    async fn check_token(&self, token: &Token, purpose: TokenPurpose) -> Result<Uuid, TokenError> {
+        Ok(self.check_token_with_payload(token, purpose).await?.0)
+    }
+    /// Check a token could be used, without using it up, returning its payload too.
+    async fn check_token_with_payload(
+        &self,
+        token: &Token,
+        purpose: TokenPurpose,
+    ) -> Result<(Uuid, Option<String>), TokenError>;
+    /// Invalidate every token a user has been issued, that hasn't been used.
+    async fn revoke_user_tokens(&self, user_id: &Uuid) -> anyhow::Result<()>;
+}
+#[cfg(test)]
+mod tests {
+    use super::Token;
+
+    #[test]
+    fn test_token_hash_matches() {
+        let token = Token::generate();
+        let same = Token::from(token.expose().to_string());
+        assert_eq!(token.hash(), same.hash());
+        assert_ne!(token.hash(), Token::generate().hash());
+    }
+}
@@ -0,0 +1,221 @@
+//! Time based one time passwords (RFC 6238), as a second factor after the password.
+//!
+//! Enrolment Flow:
+//! 1) User is shown a fresh secret, as an otpauth uri for their authenticator app.
+//! 2) User confirms a code from the app, which switches two-factor on,
+//!    and is shown one-time recovery codes.
+//!
+//! Login Flow:
+//! 1) The password gets a pending session, as a HalfAuthorizedUser.
+//! 2) A code, or an unused recovery code, turns it into a full session.
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use shuttle_runtime::async_trait;
+use url::Url;
+use uuid::Uuid;
+
+use super::token::Token;
+
+pub const TOTP_STEP_SECONDS: i64 = 30;
+pub const TOTP_DIGITS: u32 = 6;
+/// Steps either side of now that are accepted, for clock drift.
+pub const TOTP_SKEW_STEPS: i64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// How long a user has to enter their code after their password.
+pub const PENDING_MFA_MINUTES: i64 = 10;
+/// Name authenticator apps show the account under.
+pub const TOTP_ISSUER: &str = "Date.rs";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A user's authenticator secret, and whether it has been confirmed.
+#[derive(Debug, Clone)]
+pub struct TotpState {
+    /// Base32, as shown to the user.
+    pub secret: String,
+    pub enabled: bool,
+    /// Last step a code was accepted for, so a code can't be replayed.
+    pub last_step: Option<i64>,
+}
+
+/// New random secret, base32 encoded.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base32_encode(&bytes)
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = buf.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            out.push(BASE32_ALPHABET[((bits >> (35 - i * 5)) & 31) as usize] as char);
+        }
+    }
+    out
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u64, 0);
+    for c in encoded.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Code for a single time step (HOTP, RFC 4226).
+pub fn totp_code(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("Hmac takes any key length.");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    code % 10_u32.pow(TOTP_DIGITS)
+}
+
+/// Code an authenticator app would show at a time.
+///
+/// * `secret`: Base32 secret.
+pub fn code_at(secret: &str, unix_time: i64) -> Option<String> {
+    let secret = base32_decode(secret)?;
+    Some(format!(
+        "{:0width$}",
+        totp_code(&secret, current_step(unix_time)),
+        width = TOTP_DIGITS as usize
+    ))
+}
+
+/// Time step a unix time falls in.
+pub fn current_step(unix_time: i64) -> i64 {
+    unix_time / TOTP_STEP_SECONDS
+}
+
+/// Check a code the user typed, returning the step it matched.
+///
+/// * `secret`: Base32 secret.
+/// * `code`: As typed, spaces are ignored.
+/// * `last_step`: Codes for this step or earlier are refused, as already used.
+pub fn verify_code(secret: &str, code: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let secret = base32_decode(secret)?;
+    let step = current_step(now);
+    (step - TOTP_SKEW_STEPS..=step + TOTP_SKEW_STEPS)
+        .filter(|s| last_step.is_none_or(|last| *s > last))
+        .find(|s| totp_code(&secret, *s) == code)
+}
+
+/// Uri authenticator apps read, usually from a QR code.
+pub fn otpauth_uri(secret: &str, email: &str) -> String {
+    let mut url = Url::parse("otpauth://totp/").unwrap();
+    url.set_path(&format!("{}:{}", TOTP_ISSUER, email));
+    url.query_pairs_mut()
+        .append_pair("secret", secret)
+        .append_pair("issuer", TOTP_ISSUER)
+        .append_pair("digits", &TOTP_DIGITS.to_string())
+        .append_pair("period", &TOTP_STEP_SECONDS.to_string());
+    url.to_string()
+}
+
+/// Fresh recovery codes, in the form they are shown to the user.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 5];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let code = hex::encode(bytes);
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Hash a recovery code for storage, ignoring how the user formatted it.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalised: String = code
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    Token::from(normalised).hash()
+}
+
+#[async_trait]
+pub trait TotpRepository {
+    /// Get a user's authenticator secret, confirmed or not.
+    async fn get_totp(&self, user_id: &Uuid) -> anyhow::Result<Option<TotpState>>;
+    /// Store a secret the user hasn't confirmed yet, replacing any earlier unconfirmed one.
+    ///
+    /// Does nothing if two-factor is already on.
+    async fn set_pending_totp(&self, user_id: &Uuid, secret: &str) -> anyhow::Result<()>;
+    /// Switch two-factor on, replacing any old recovery codes.
+    ///
+    /// * `step`: Step of the code that confirmed the secret.
+    /// * `recovery_hashes`: Hashes of the new recovery codes.
+    async fn enable_totp(
+        &self,
+        user_id: &Uuid,
+        step: i64,
+        recovery_hashes: &[String],
+    ) -> anyhow::Result<()>;
+    /// Remember a step has been used, false if it, or a later one, already was.
+    async fn record_totp_step(&self, user_id: &Uuid, step: i64) -> anyhow::Result<bool>;
+    /// Use up a recovery code, false if it isn't one of the user's unused codes.
+    async fn use_recovery_code(&self, user_id: &Uuid, code_hash: &str) -> anyhow::Result<bool>;
+    /// Recovery codes the user has left.
+    async fn remaining_recovery_codes(&self, user_id: &Uuid) -> anyhow::Result<i64>;
+    /// Switch two-factor off, forgetting the secret and recovery codes.
+    async fn disable_totp(&self, user_id: &Uuid) -> anyhow::Result<()>;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc_6238_vectors() {
+        // SHA1 vectors from RFC 6238 appendix B, cut to 6 digits.
+        let secret = b"12345678901234567890";
+        assert_eq!(totp_code(secret, current_step(59)), 287082);
+        assert_eq!(totp_code(secret, current_step(1111111109)), 81804);
+        assert_eq!(totp_code(secret, current_step(2000000000)), 279037);
+    }
+    #[test]
+    fn test_verify_code() {
+        let secret = generate_secret();
+        assert_eq!(base32_decode(&secret).unwrap().len(), 20);
+        let now = 1_700_000_000;
+        let step = current_step(now);
+        let code = code_at(&secret, now - TOTP_STEP_SECONDS).unwrap();
+        assert_eq!(verify_code(&secret, &code, now, None), Some(step - 1));
+        assert_eq!(verify_code(&secret, &code, now, Some(step - 1)), None);
+        assert_eq!(
+            verify_code(&secret, &code, now + 3 * TOTP_STEP_SECONDS, None),
+            None
+        );
+        assert_eq!(verify_code(&secret, "12345", now, None), None);
+    }
+    #[test]
+    fn test_recovery_code_hash() {
+        let code = &generate_recovery_codes()[0];
+        assert_eq!(
+            hash_recovery_code(code),
+            hash_recovery_code(&code.to_uppercase().replace('-', " "))
+        );
+    }
+}
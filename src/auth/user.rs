@@ -9,7 +9,10 @@
 //! User Login Flow:
 //! 1) User logs in:
 //! UnAuthorizedUser -> AuthorizedUser
+//! 2) Or, if they have two-factor on:
+//! UnAuthorizedUser -> HalfAuthorizedUser -> AuthorizedUser
 
+use chrono::{DateTime, Utc};
 use secrecy::Secret;
 use serde::{Deserialize, Serialize};
 use shuttle_runtime::async_trait;
@@ -17,6 +20,11 @@ use shuttle_runtime::async_trait;
 use thiserror::Error;
 use uuid::Uuid;
 
+use super::email_address::EmailAddress;
+use super::passkey::{NewCredential, Passkey};
+use super::role::{GroupMember, GroupMembership, PermissionMatrix, Role, RoleError};
+use crate::domain::group::{GroupProfile, GroupSettings};
+
 #[async_trait]
 #[allow(clippy::module_name_repetitions)]
 pub trait UserRepository {
@@ -29,27 +37,13 @@ pub trait UserRepository {
         self.add_user_to_group(user, self.create_group().await?)
             .await
     }
-    /// Create a user and add them to an existing group.
-    ///
-    /// * `user`: New user without a group.
-    /// * `email`: email of a member of the target group.
-    async fn add_user_to_group_by_email(
-        &self,
-        user: NoGroupUser,
-        email: &str,
-    ) -> anyhow::Result<GroupUser> {
-        self.add_user_to_group(user, self.get_group_by_email(email).await?)
-            .await
-    }
     // To implememnt
-    /// Add a user to a group via group id.
+    /// Add a user to a group via group id, and open it.
+    ///
+    /// Users can belong to any number of groups, `NoGroupUser` is the user before joining this one.
     async fn add_user_to_group(&self, user: NoGroupUser, group: i32) -> anyhow::Result<GroupUser>;
-    /// Create a new group.
+    /// Create a new group, named after its number until someone renames it.
     async fn create_group(&self) -> anyhow::Result<i32>;
-    /// Find a group by the email of a member.
-    ///
-    /// * `email`: Group member's email.
-    async fn get_group_by_email(&self, email: &str) -> anyhow::Result<i32>;
     /// Validate a user, take a user with a password and return their grouped status.
     ///
     /// * `user`:
@@ -57,6 +51,17 @@ pub trait UserRepository {
         &self,
         user: &UnAuthorizedUser,
     ) -> Result<AuthorizedUser, UserValidationError>;
+    /// Check a signed in user's password before a sensitive change.
+    ///
+    /// Unlike `validate_user` it isn't a login, so nothing is audited or rehashed.
+    /// None if the account has no password, eg one made through single sign-on.
+    async fn check_password(
+        &self,
+        user_id: &Uuid,
+        password: Secret<String>,
+    ) -> anyhow::Result<Option<bool>>;
+    /// Whether an account has a password, rather than only signing in other ways.
+    async fn has_password(&self, user_id: &Uuid) -> anyhow::Result<bool>;
     /// Add a user to the database.
     ///
     /// * `user`: A user that doesn't exist on the system.
@@ -71,12 +76,107 @@ pub trait UserRepository {
         user_id: &Uuid,
         new_password: Secret<String>,
     ) -> anyhow::Result<()>;
+    /// Lock a user out of their account after they report a sign-in wasn't them.
+    ///
+    /// Every session, outstanding token and API token is revoked, known devices are forgotten,
+    /// and no way of logging in works until the password is changed.
+    async fn require_password_reset(&self, user_id: &Uuid) -> anyhow::Result<()>;
+    /// Move an existing user to a new, already verified, email address.
+    ///
+    /// * `new_email`: Must not belong to another user.
+    async fn change_user_email(
+        &self,
+        user_id: &Uuid,
+        new_email: &EmailAddress,
+    ) -> Result<(), UserValidationError>;
+    /// Delete a user straight away, along with their group if they were its last member.
     async fn remove_user(&self, user_id: &Uuid) -> anyhow::Result<()>;
+    /// Mark a user's account to be deleted once a grace period is over.
+    ///
+    /// * `at`: When the account can be deleted.
+    async fn schedule_user_deletion(&self, user_id: &Uuid, at: DateTime<Utc>)
+        -> anyhow::Result<()>;
+    /// Keep an account that was going to be deleted, false if it wasn't.
+    async fn cancel_user_deletion(&self, user_id: &Uuid) -> anyhow::Result<bool>;
+    /// When a user's account is going to be deleted, if it is.
+    async fn get_user_deletion(&self, user_id: &Uuid) -> anyhow::Result<Option<DateTime<Utc>>>;
+    /// Delete every account whose grace period is over, returning how many went.
+    ///
+    /// Groups left without members are deleted too, along with their dates.
+    async fn remove_scheduled_users(&self) -> anyhow::Result<u64>;
+    /// How many users belong to a group.
+    async fn count_group_members(&self, group: i32) -> anyhow::Result<i64>;
+    /// A group's members and their roles, owner first.
+    async fn get_group_members(&self, group: i32) -> anyhow::Result<Vec<GroupMember>>;
+    /// Which roles may do what in a group.
+    async fn get_group_permissions(&self, group: i32) -> anyhow::Result<PermissionMatrix>;
+    /// A group's name, description and the like.
+    async fn get_group_profile(&self, group: i32) -> anyhow::Result<GroupProfile>;
+    /// Save a group's profile, if the actor's role may edit it.
+    ///
+    /// * `profile`: Already validated by `GroupProfile::edit`.
+    async fn update_group_profile(
+        &self,
+        actor_id: &Uuid,
+        profile: &GroupProfile,
+    ) -> Result<(), RoleError>;
+    /// How a group works.
+    async fn get_group_settings(&self, group: i32) -> anyhow::Result<GroupSettings>;
+    /// Change how a group works, only its owner can.
+    async fn set_group_settings(
+        &self,
+        actor_id: &Uuid,
+        group: i32,
+        settings: &GroupSettings,
+    ) -> Result<(), RoleError>;
+    /// Change which roles may do what in a group, only its owner can.
+    async fn set_group_permissions(
+        &self,
+        actor_id: &Uuid,
+        group: i32,
+        permissions: &PermissionMatrix,
+    ) -> Result<(), RoleError>;
+    /// Make a member of the owner's group an admin, or a plain member again.
+    ///
+    /// * `role`: Can't be owner, that takes `transfer_group_ownership`.
+    async fn set_group_role(
+        &self,
+        actor_id: &Uuid,
+        group: i32,
+        member_id: &Uuid,
+        role: Role,
+    ) -> Result<(), RoleError>;
+    /// Hand the owner's group to another member, the old owner stays on as an admin.
+    async fn transfer_group_ownership(
+        &self,
+        actor_id: &Uuid,
+        group: i32,
+        new_owner_id: &Uuid,
+    ) -> Result<(), RoleError>;
+    /// Take someone out of the actor's group.
+    ///
+    /// The actor's role needs to allow removing members, and the member can't be
+    /// the owner or outrank the actor.
+    async fn remove_group_member(
+        &self,
+        actor_id: &Uuid,
+        group: i32,
+        member_id: &Uuid,
+    ) -> Result<(), RoleError>;
+    /// Delete the owner's group and its dates, members keep their other groups.
+    async fn delete_group(&self, actor_id: &Uuid, group: i32) -> Result<(), RoleError>;
     /// Get a user from the repository by id.
     ///
     /// * `user_id`: User's id.
     async fn get_user(&self, user_id: &Uuid)
         -> anyhow::Result<AuthorizedUser, UserValidationError>;
+    /// Get a user that is about to be signed in, without a password.
+    ///
+    /// Like `get_user`, but refuses accounts that have to reset their password first.
+    async fn get_login_user(
+        &self,
+        user_id: &Uuid,
+    ) -> anyhow::Result<AuthorizedUser, UserValidationError>;
 
     /// Utility method to get a user by email.
     async fn get_user_by_email(
@@ -87,8 +187,44 @@ pub trait UserRepository {
     /// Get a user that isn't authorized by their email.
     async fn get_unauthorized_user_id(&self, email: &str) -> Option<uuid::Uuid>;
 
-    /// Remove a user from a group.
-    async fn remove_user_from_group(&self, user_id: &Uuid) -> anyhow::Result<()>;
+    /// Remove a user from one of their groups, deleting it if they were its last member.
+    ///
+    /// If they owned it, the longest-standing admin, or else member, takes over.
+    async fn remove_user_from_group(&self, user_id: &Uuid, group: i32) -> anyhow::Result<()>;
+    /// Every group a user belongs to, oldest membership first.
+    async fn get_user_groups(&self, user_id: &Uuid) -> anyhow::Result<Vec<GroupMembership>>;
+    /// Switch the group a user has open, which has to be one they belong to.
+    async fn open_group(&self, user_id: &Uuid, group: i32) -> Result<GroupUser, RoleError>;
+    /// A user's role in a group, if they belong to it.
+    async fn find_group_role(&self, user_id: &Uuid, group: i32) -> anyhow::Result<Option<Role>>;
+
+    /// Store a newly registered passkey against a user.
+    ///
+    /// * `name`: What the user calls the passkey, eg the device it's on.
+    async fn add_passkey(
+        &self,
+        user_id: &Uuid,
+        credential: &NewCredential,
+        name: &str,
+    ) -> anyhow::Result<()>;
+    /// Get a passkey by the id the authenticator reports.
+    async fn get_passkey(&self, credential_id: &str) -> anyhow::Result<Option<Passkey>>;
+    /// A user's passkeys, oldest first.
+    async fn list_passkeys(&self, user_id: &Uuid) -> anyhow::Result<Vec<Passkey>>;
+    /// Rename one of a user's passkeys, false if they don't have it.
+    async fn rename_passkey(
+        &self,
+        user_id: &Uuid,
+        credential_id: &str,
+        name: &str,
+    ) -> anyhow::Result<bool>;
+    /// Remove one of a user's passkeys, false if they don't have it.
+    async fn revoke_passkey(&self, user_id: &Uuid, credential_id: &str) -> anyhow::Result<bool>;
+    /// Record a login with a passkey.
+    ///
+    /// False if the counter has already reached `sign_count`, as a
+    /// concurrent login with the same signature would have.
+    async fn use_passkey(&self, credential_id: &str, sign_count: i64) -> anyhow::Result<bool>;
 }
 
 #[derive(Error, Debug)]
@@ -97,6 +233,12 @@ pub enum UserValidationError {
     PasswordError(#[source] anyhow::Error),
     #[error("Unregisterd User.")]
     RegistrationError(#[source] anyhow::Error),
+    #[error("User hasn't verified their email.")]
+    Unverified,
+    #[error("An account already uses that email.")]
+    EmailTaken,
+    #[error("This account's password has to be reset before it can be used again.")]
+    PasswordResetRequired,
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -107,13 +249,13 @@ pub enum UserValidationError {
 /// * `email`:
 /// * `password`:
 pub struct UnRegisteredUser {
-    pub email: String,
+    pub email: EmailAddress,
     pub password: Secret<String>,
 }
 impl UnRegisteredUser {
-    pub fn new(email: impl Into<String>, password: impl Into<String>) -> Self {
+    pub fn new(email: EmailAddress, password: impl Into<String>) -> Self {
         UnRegisteredUser {
-            email: email.into(),
+            email,
             password: Secret::new(password.into()),
         }
     }
@@ -126,9 +268,10 @@ pub struct UnAuthorizedUser {
     pub password: Secret<String>,
 }
 impl UnAuthorizedUser {
-    pub fn new(email: impl Into<String>, password: impl Into<String>) -> Self {
+    /// The email is normalized, so it matches however the user typed it.
+    pub fn new(email: impl AsRef<str>, password: impl Into<String>) -> Self {
         UnAuthorizedUser {
-            email: email.into(),
+            email: EmailAddress::normalize(email.as_ref()),
             password: Secret::new(password.into()),
         }
     }
@@ -160,6 +303,15 @@ impl AuthorizedUser {
     }
 }
 
+/// Models a user that has given their password, but still owes a second factor.
+///
+/// Deliberately not an `AuthorizedUser`, so it can't reach anything behind a login.
+#[derive(Debug, Clone)]
+pub struct HalfAuthorizedUser {
+    pub user_id: Uuid,
+    pub email: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[allow(clippy::module_name_repetitions)]
 pub struct NoGroupUser {
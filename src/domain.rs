@@ -1,2 +1,3 @@
 pub mod dates;
+pub mod group;
 pub mod repository;
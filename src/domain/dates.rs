@@ -1,3 +1,4 @@
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
 use sqlx;
 use sqlx::sqlx_macros::Type;
@@ -38,9 +39,10 @@ impl Description {
     pub fn reject(&mut self) {
         self.status = Status::Rejected;
     }
-    pub fn render_date(&self) -> String {
+    /// The day as shown in the group's timezone.
+    pub fn render_date(&self, tz: &Tz) -> String {
         match self.day {
-            Some(day) => day.format("%H:%M %d/%m/%Y").to_string(),
+            Some(day) => day.with_timezone(tz).format("%H:%M %d/%m/%Y").to_string(),
             None => "No date set".into(),
         }
     }
@@ -57,11 +59,13 @@ impl Description {
 ///
 /// * `name`: The name of the date
 /// * `count`: The number of upvotes for the date.
+/// * `created_by`: Who suggested it, missing once they've left.
 pub struct Date {
     pub name: String,
     pub count: i32,
     pub id: Uuid,
     pub description: Description,
+    pub created_by: Option<Uuid>,
 }
 impl Date {
     pub fn new(name: impl Into<String>) -> Date {
@@ -70,6 +74,7 @@ impl Date {
             count: 0,
             id: uuid::Uuid::new_v4(),
             description: Description::default(),
+            created_by: None,
         }
     }
     pub fn add(&mut self) {
@@ -0,0 +1,243 @@
+//! A group's profile and settings.
+//!
+//! The profile is how the group presents itself, and can be edited by members whose
+//! role allows it. Its handle is what others use to ask to join. Settings change how
+//! the group works, and only the owner can change them.
+use std::cmp::Reverse;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use rand::RngCore;
+use serde::Serialize;
+use thiserror::Error;
+
+use super::dates::Date;
+
+/// Longest a group's name can be.
+pub const MAX_NAME_LENGTH: usize = 60;
+/// Longest a group's description can be.
+pub const MAX_DESCRIPTION_LENGTH: usize = 500;
+/// Most characters in a group's emoji, enough for flags and joined emoji.
+pub const MAX_EMOJI_LENGTH: usize = 8;
+/// Shortest and longest a group's handle can be.
+pub const HANDLE_LENGTH: std::ops::RangeInclusive<usize> = 3..=30;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum GroupProfileError {
+    #[error("The group needs a name.")]
+    MissingName,
+    #[error("The group's name can be at most {MAX_NAME_LENGTH} characters.")]
+    NameTooLong,
+    #[error("The description can be at most {MAX_DESCRIPTION_LENGTH} characters.")]
+    DescriptionTooLong,
+    #[error("The emoji can be at most {MAX_EMOJI_LENGTH} characters.")]
+    EmojiTooLong,
+    #[error("Unknown timezone {0}.")]
+    UnknownTimezone(String),
+    #[error("Handles are 3 to 30 lowercase letters, digits and dashes.")]
+    InvalidHandle,
+    #[error("Another group has that handle.")]
+    HandleTaken,
+}
+
+/// Handle new groups get, random so groups can't be found by counting through them.
+pub fn random_handle() -> String {
+    let mut bytes = [0u8; 6];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("group-{}", hex::encode(bytes))
+}
+
+/// How a group presents itself.
+///
+/// * `handle`: Unique, shared so people can ask to join.
+/// * `timezone`: Default for the group's dates, an IANA name such as `Europe/London`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GroupProfile {
+    pub id: i32,
+    pub handle: String,
+    pub name: String,
+    pub description: String,
+    pub emoji: String,
+    pub timezone: String,
+    pub created_at: DateTime<Utc>,
+}
+impl GroupProfile {
+    /// Change the editable parts of the profile, leaving it untouched if any are invalid.
+    ///
+    /// Whether another group has the handle is left to the caller.
+    pub fn edit(
+        &mut self,
+        handle: &str,
+        name: &str,
+        description: &str,
+        emoji: &str,
+        timezone: &str,
+    ) -> Result<(), GroupProfileError> {
+        let (name, description, emoji, timezone) = (
+            name.trim(),
+            description.trim(),
+            emoji.trim(),
+            timezone.trim(),
+        );
+        let handle = handle.trim().to_lowercase();
+        if !HANDLE_LENGTH.contains(&handle.len())
+            || !handle
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(GroupProfileError::InvalidHandle);
+        }
+        if name.is_empty() {
+            return Err(GroupProfileError::MissingName);
+        }
+        if name.chars().count() > MAX_NAME_LENGTH {
+            return Err(GroupProfileError::NameTooLong);
+        }
+        if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+            return Err(GroupProfileError::DescriptionTooLong);
+        }
+        if emoji.chars().count() > MAX_EMOJI_LENGTH {
+            return Err(GroupProfileError::EmojiTooLong);
+        }
+        let timezone: Tz = timezone
+            .parse()
+            .map_err(|_| GroupProfileError::UnknownTimezone(timezone.into()))?;
+        self.handle = handle;
+        self.name = name.into();
+        self.description = description.into();
+        self.emoji = emoji.into();
+        self.timezone = timezone.name().into();
+        Ok(())
+    }
+    /// Timezone the group's dates are set and shown in.
+    pub fn tz(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+}
+
+/// Order a group's dates are listed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateSort {
+    /// Most votes first.
+    #[default]
+    Votes,
+    /// Alphabetically by name.
+    Name,
+    /// Soonest first, dates without a day last.
+    Day,
+}
+impl DateSort {
+    pub const ALL: [DateSort; 3] = [Self::Votes, Self::Name, Self::Day];
+    /// Name the order is stored as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Votes => "votes",
+            Self::Name => "name",
+            Self::Day => "day",
+        }
+    }
+    pub fn parse(sort: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.as_str() == sort)
+    }
+    /// How the order is described on the settings page.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Votes => "Most votes first",
+            Self::Name => "By name",
+            Self::Day => "Soonest first",
+        }
+    }
+    pub fn sort(&self, dates: &mut [Date]) {
+        match self {
+            Self::Votes => dates.sort_by_key(|d| Reverse(d.count)),
+            Self::Name => dates.sort_by_cached_key(|d| d.name.to_lowercase()),
+            Self::Day => dates.sort_by_key(|d| (d.description.day.is_none(), d.description.day)),
+        }
+    }
+}
+
+/// How a group works, set by its owner.
+///
+/// Who may approve dates is set with the group's other permissions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GroupSettings {
+    pub date_sort: DateSort,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Local};
+
+    fn profile() -> GroupProfile {
+        GroupProfile {
+            id: 1,
+            handle: random_handle(),
+            name: "Group 1".into(),
+            description: "".into(),
+            emoji: "".into(),
+            timezone: "UTC".into(),
+            created_at: Utc::now(),
+        }
+    }
+    #[test]
+    fn test_edit_profile() {
+        let mut group = profile();
+        group
+            .edit(
+                " Climbers-2 ",
+                " Climbers ",
+                "Weekends out",
+                "🧗",
+                "Europe/London",
+            )
+            .unwrap();
+        assert_eq!(group.handle, "climbers-2");
+        assert_eq!(group.name, "Climbers");
+        assert_eq!(group.timezone, "Europe/London");
+        assert_eq!(group.tz(), chrono_tz::Europe::London);
+        let before = group.clone();
+        assert_eq!(
+            group.edit("climbers", "  ", "", "", "UTC"),
+            Err(GroupProfileError::MissingName)
+        );
+        assert_eq!(
+            group.edit("climbers", "Climbers", "", "", "Mars/Olympus"),
+            Err(GroupProfileError::UnknownTimezone("Mars/Olympus".into()))
+        );
+        assert_eq!(
+            group.edit("climbers", &"a".repeat(MAX_NAME_LENGTH + 1), "", "", "UTC"),
+            Err(GroupProfileError::NameTooLong)
+        );
+        for handle in ["no", "with space", "émoji"] {
+            assert!(group.edit(handle, "Climbers", "", "", "UTC").is_err());
+        }
+        assert_eq!(group, before);
+        group.edit("group-1", "Climbers", "", "", "UTC").unwrap();
+        let handle = random_handle();
+        assert_ne!(handle, random_handle());
+        group.edit(&handle, "Climbers", "", "", "UTC").unwrap();
+    }
+    #[test]
+    fn test_date_sort() {
+        let mut later = Date::new("b");
+        later.description.day = Some(Local::now() + Duration::days(2));
+        let mut sooner = Date::new("C");
+        sooner.description.day = Some(Local::now() + Duration::days(1));
+        sooner.count = 3;
+        let mut undated = Date::new("a");
+        undated.count = 1;
+        let mut dates = vec![later.clone(), sooner.clone(), undated.clone()];
+        let names = |dates: &[Date]| dates.iter().map(|d| d.name.clone()).collect::<Vec<_>>();
+        DateSort::Votes.sort(&mut dates);
+        assert_eq!(names(&dates), ["C", "a", "b"]);
+        DateSort::Name.sort(&mut dates);
+        assert_eq!(names(&dates), ["a", "b", "C"]);
+        DateSort::Day.sort(&mut dates);
+        assert_eq!(names(&dates), ["C", "b", "a"]);
+        for sort in DateSort::ALL {
+            assert_eq!(DateSort::parse(sort.as_str()), Some(sort));
+        }
+    }
+}
@@ -1,10 +1,26 @@
+use crate::auth::api_token::ApiTokenRepository;
+use crate::auth::audit::AuditRepository;
+use crate::auth::email_address::DisposableDomains;
+use crate::auth::invite::InviteRepository;
+use crate::auth::join_request::{JoinRequestRepository, JOIN_REQUEST_LENGTH_DAYS};
+use crate::auth::oidc::{IdentityRepository, OidcClient};
+use crate::auth::passkey::RelyingParty;
+use crate::auth::password_policy::PasswordPolicy;
+use crate::auth::proof_of_work::{ChallengeRepository, ProofOfWork};
+use crate::auth::role::RoleError;
+use crate::auth::session::SessionRepository;
+use crate::auth::throttle::ThrottleRepository;
+use crate::auth::token::TokenRepository;
+use crate::auth::totp::TotpRepository;
 use crate::auth::user::UserRepository;
 use crate::email::EmailClient;
 
-use super::dates::Date;
+use super::dates::{Date, Status};
+use actix_web::cookie::Key;
 use actix_web::web;
 use shuttle_runtime::async_trait;
 use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
 use std::sync::Mutex;
 use thiserror::Error;
 use tracing::info;
@@ -14,20 +30,49 @@ pub struct AppState {
     pub repo: Box<dyn Repository + Send + Sync>,
     pub cache: ExpansionCache,
     pub email_client: EmailClient,
+    /// Key used to sign session cookies.
+    pub session_key: Key,
+    /// OpenID Connect provider, if logging in through one is enabled.
+    pub oidc: Option<OidcClient>,
+    /// Site passkeys are registered for, if passkeys are enabled.
+    pub relying_party: Option<RelyingParty>,
+    /// Rules new passwords are checked against.
+    pub password_policy: PasswordPolicy,
+    /// Email domains new accounts can't use.
+    pub disposable_domains: DisposableDomains,
+    /// Challenges registering and logging in have to solve, if bot protection is enabled.
+    pub proof_of_work: Option<ProofOfWork>,
+    /// How long a request to join a group waits for an answer.
+    pub join_request_expiry: chrono::Duration,
+    /// Proxies whose forwarding headers give the client's address.
+    pub trusted_proxies: Vec<IpAddr>,
 }
 impl AppState {
-    pub fn new(repo: Box<dyn Repository + Send + Sync>, email_client: EmailClient) -> AppState {
+    pub fn new(
+        repo: Box<dyn Repository + Send + Sync>,
+        email_client: EmailClient,
+        session_key: Key,
+    ) -> AppState {
         AppState {
             repo,
             cache: ExpansionCache::new(),
             email_client,
+            session_key,
+            oidc: None,
+            relying_party: None,
+            password_policy: PasswordPolicy::default(),
+            disposable_domains: DisposableDomains::default(),
+            proof_of_work: None,
+            join_request_expiry: chrono::Duration::days(JOIN_REQUEST_LENGTH_DAYS),
+            trusted_proxies: Vec::new(),
         }
     }
     pub fn new_in_web_data(
         repo: Box<dyn Repository + Send + Sync>,
         email_client: EmailClient,
+        session_key: Key,
     ) -> web::Data<AppState> {
-        web::Data::new(AppState::new(repo, email_client))
+        web::Data::new(AppState::new(repo, email_client, session_key))
     }
 }
 
@@ -148,54 +193,89 @@ pub enum InsertDateError {
     QueryError,
     #[error("User isn't part of a group")]
     GroupMembershipError,
+    #[error("User's role doesn't allow suggesting dates")]
+    Forbidden,
 }
 #[async_trait]
-pub trait Repository: UserRepository + DateRepository {}
+pub trait Repository:
+    UserRepository
+    + DateRepository
+    + SessionRepository
+    + TokenRepository
+    + ThrottleRepository
+    + IdentityRepository
+    + TotpRepository
+    + ApiTokenRepository
+    + AuditRepository
+    + ChallengeRepository
+    + InviteRepository
+    + JoinRequestRepository
+{
+}
 #[async_trait]
 /// Abstraction over storage, so that it can be in memory or persistent.
 /// The repository shouldn't need to have mutable acess
 pub trait DateRepository {
-    /// Add a date to the repository.
+    /// Add a date to one of the user's groups, suggested by them.
     ///
     /// * `date`:
-    async fn add(&self, date: Date, user_id: Uuid) -> Result<(), InsertDateError>;
-    /// Remove a date from the repository.
+    async fn add(&self, date: Date, group: i32, user_id: Uuid) -> Result<(), InsertDateError>;
+    /// Remove a date from the repository, if the user's role may delete dates.
     ///
     /// * `date_id`:
     async fn remove<'a, 'ui, 'st>(
         &'a self,
         date_id: &'ui Uuid,
+        group: i32,
         user_id: &'st Uuid,
-    ) -> anyhow::Result<()>;
-    /// Return a copy of the all the user's dates in a sorted fashion.
-    async fn get_all(&self, user_id: &Uuid) -> Vec<Date>;
+    ) -> Result<(), RoleError>;
+    /// Return a copy of the all the group's dates in a sorted fashion.
+    ///
+    /// Empty unless the user belongs to the group.
+    async fn get_all(&self, group: i32, user_id: &Uuid) -> Vec<Date>;
     /// Update's the repository entry for a given date.
     ///
+    /// Users can edit dates they suggested, others only if their role may edit any date.
+    /// The status is left alone, it's changed with `set_date_status`.
+    /// Fails if the group has no such date.
+    ///
     /// * `date_name`:
-    async fn update(&self, date: Date, user_id: &Uuid) -> anyhow::Result<()>;
-    /// Increment the count of a given date.
+    async fn update(&self, date: Date, group: i32, user_id: &Uuid) -> Result<(), RoleError>;
+    /// Approve or reject a date, if the user's role may.
+    ///
+    /// * `status`: The date's new status.
+    async fn set_date_status(
+        &self,
+        date_id: &Uuid,
+        group: i32,
+        user_id: &Uuid,
+        status: Status,
+    ) -> Result<(), RoleError>;
+    /// Increment the count of a given date, if the user's role may vote.
     ///
     /// * `date_id`: date to increment
     async fn increment_date_count<'a, 'ui, 'st>(
         &'a self,
         date_id: &'ui Uuid,
+        group: i32,
         user_id: &'st Uuid,
-    ) -> anyhow::Result<()>;
-    /// Decrement the count of a given date.
+    ) -> Result<(), RoleError>;
+    /// Decrement the count of a given date, if the user's role may vote.
     ///
     /// * `date_id`: date to decrement
     async fn decrement_date_count<'a, 'ui, 'st>(
         &'a self,
         date_id: &'ui Uuid,
+        group: i32,
         user_id: &'st Uuid,
-    ) -> anyhow::Result<()>;
-    /// Get a date from the repository.
+    ) -> Result<(), RoleError>;
+    /// Get a date from one of the user's groups.
     ///
     /// * `date_id`:
-    async fn get<'a, 'ui, 'st>(&'a self, date_id: &'ui Uuid, user_id: &'st Uuid) -> Option<Date>;
-
-    /// Check that the user has access to the repository.
-    ///
-    /// * `user_id`:
-    async fn check_user_has_access(&self, user_id: &Uuid) -> bool;
+    async fn get<'a, 'ui, 'st>(
+        &'a self,
+        date_id: &'ui Uuid,
+        group: i32,
+        user_id: &'st Uuid,
+    ) -> Option<Date>;
 }
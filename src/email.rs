@@ -1,17 +1,34 @@
+use std::collections::HashMap;
 use std::fs;
 
 use actix_web::{
-    error::{ErrorInternalServerError, ErrorUnauthorized},
-    get, web, HttpResponse, Result,
+    error::ErrorInternalServerError,
+    get,
+    http::header,
+    post,
+    web::{self, Form},
+    HttpResponse, Result,
 };
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 // File to manage accepting email_confirmation.
 // I can use this an an excuse to make an email microservice.
 use reqwest::Client;
 use secrecy::{ExposeSecret, Secret};
 use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
 
+use crate::auth::csrf::CsrfToken;
+use crate::auth::email_address::EmailAddress;
+use crate::auth::session::SessionDevice;
+use crate::auth::throttle::ThrottleSubject;
+use crate::auth::token::{Token, TokenError, TokenPurpose};
 use crate::domain::repository::AppState;
+use crate::routes::proof_of_work::check_proof_of_work;
+
+/// How long a verification link stays valid.
+pub const VERIFICATION_LENGTH_HOURS: i64 = 24;
 
 #[derive(Clone)]
 pub struct EmailClient {
@@ -33,24 +50,263 @@ impl EmailClient {
             from_email: from_email.into(),
         }
     }
-    /// Send an email that.
-    pub async fn send_auth_email(&self, user_email: &str) -> anyhow::Result<reqwest::Response> {
+    /// Full url of a page in the app, built the same way as the links in emails.
+    ///
+    /// * `path`: Relative to the app's url.
+    pub fn app_link(&self, path: &str) -> String {
+        format!("{}/{}", self.app_url, path)
+    }
+    /// Send an email with a link that activates the user's account.
+    ///
+    /// * `user_email`: Address to send to.
+    /// * `token`: Verification token, that the link carries.
+    pub async fn send_auth_email(
+        &self,
+        user_email: &str,
+        token: &Token,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send(&PostMarkEmail::new_auth(
+            user_email,
+            token,
+            &self.app_url,
+            &self.from_email,
+        )?)
+        .await
+    }
+    /// Send an email with a link to set a new password.
+    ///
+    /// * `user_email`: Address to send to.
+    /// * `token`: Password reset token, that the link carries.
+    pub async fn send_password_reset_email(
+        &self,
+        user_email: &str,
+        token: &Token,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_action_email(
+            user_email,
+            ActionEmail {
+                subject: "Date.rs Password Reset",
+                heading: "Forgotten your password?",
+                message: "Follow the link below to choose a new one. \
+                    If you didn't ask for this you can ignore this email.",
+                action_text: "Reset Password",
+                action_path: format!("reset_password/{}", token.expose()),
+            },
+        )
+        .await
+    }
+    /// Send an email to a user's new address, with a link that confirms the change.
+    ///
+    /// * `new_email`: Address the user wants to move to.
+    /// * `token`: Email change token, that the link carries.
+    pub async fn send_email_change_email(
+        &self,
+        new_email: &str,
+        token: &Token,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_action_email(
+            new_email,
+            ActionEmail {
+                subject: "Date.rs Confirm Your New Email",
+                heading: "Confirm your new email",
+                message: "Follow the link below to start using this address for Date.rs. \
+                    If you didn't ask for this you can ignore this email.",
+                action_text: "Confirm Email",
+                action_path: format!("account/confirm_email/{}", token.expose()),
+            },
+        )
+        .await
+    }
+    /// Tell a user their account has been locked after failed logins.
+    ///
+    /// * `user_email`: Address of the locked account.
+    /// * `token`: Unlock token, that the link carries.
+    pub async fn send_account_locked_email(
+        &self,
+        user_email: &str,
+        token: &Token,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_action_email(
+            user_email,
+            ActionEmail {
+                subject: "Date.rs Account Locked",
+                heading: "Your account has been locked",
+                message: "There were too many failed attempts to log in to your account, \
+                    so it has been locked for a while. If this was you, follow the link below \
+                    to unlock it now. If it wasn't, consider resetting your password.",
+                action_text: "Unlock Account",
+                action_path: format!("unlock/{}", token.expose()),
+            },
+        )
+        .await
+    }
+    /// Send a link that logs the user in without their password.
+    ///
+    /// * `token`: Email login token, that the link carries.
+    pub async fn send_login_link_email(
+        &self,
+        user_email: &str,
+        token: &Token,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_action_email(
+            user_email,
+            ActionEmail {
+                subject: "Date.rs Sign-in Link",
+                heading: "Sign in to Date.rs",
+                message: "Follow the link below, in the browser you asked for it from, \
+                    to sign in. It works once, for a few minutes. \
+                    If you didn't ask for this you can ignore this email.",
+                action_text: "Sign In",
+                action_path: format!("login/email_link/{}", token.expose()),
+            },
+        )
+        .await
+    }
+    /// Tell a user their account is going to be deleted, with a link to keep it.
+    ///
+    /// * `token`: Cancellation token, that the link carries.
+    /// * `deletion_at`: When the account goes.
+    pub async fn send_account_deletion_email(
+        &self,
+        user_email: &str,
+        token: &Token,
+        deletion_at: DateTime<Utc>,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_action_email(
+            user_email,
+            ActionEmail {
+                subject: "Date.rs Account Deletion",
+                heading: "Your account is going to be deleted",
+                message: &format!(
+                    "You asked for your Date.rs account to be deleted, it will be on {}. \
+                    If you've changed your mind, or it wasn't you, follow the link below to keep it.",
+                    deletion_at.format("%Y-%m-%d %H:%M UTC")
+                ),
+                action_text: "Keep My Account",
+                action_path: format!("account/delete/cancel/{}", token.expose()),
+            },
+        )
+        .await
+    }
+    /// Invite someone to join a group.
+    ///
+    /// * `invitee_email`: Address the invite is for.
+    /// * `token`: Invite token, that the link carries.
+    /// * `inviter_email`: Member who sent it.
+    pub async fn send_group_invite_email(
+        &self,
+        invitee_email: &str,
+        token: &Token,
+        inviter_email: &str,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_action_email(
+            invitee_email,
+            ActionEmail {
+                subject: "Date.rs Group Invite",
+                heading: "You've been invited to a group",
+                message: &format!(
+                    "{} has invited you to plan dates with their group on Date.rs. \
+                    Follow the link below to join, you'll need an account with this address. \
+                    If you don't know them you can ignore this email.",
+                    inviter_email
+                ),
+                action_text: "Join Group",
+                action_path: format!("invite/{}", token.expose()),
+            },
+        )
+        .await
+    }
+    /// Tell a member someone has asked to join their group.
+    ///
+    /// * `member_email`: Member who can answer the request.
+    /// * `requester_email`: Who asked to join.
+    /// * `group`: The group's id, so the link opens it.
+    /// * `group_name`: The group's name.
+    pub async fn send_join_request_email(
+        &self,
+        member_email: &str,
+        requester_email: &str,
+        group: i32,
+        group_name: &str,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_action_email(
+            member_email,
+            ActionEmail {
+                subject: "Date.rs Request To Join",
+                heading: "Someone wants to join your group",
+                message: &join_request_message(requester_email, group_name),
+                action_text: "Answer Request",
+                action_path: format!("group/requests?group={}", group),
+            },
+        )
+        .await
+    }
+    /// Tell a user their account was signed in to from a device it hadn't been before.
+    ///
+    /// * `token`: Sign-in denial token, that the "this wasn't me" link carries.
+    /// * `device`: Where the sign-in came from.
+    /// * `at`: When it happened.
+    pub async fn send_new_device_email(
+        &self,
+        user_email: &str,
+        token: &Token,
+        device: &SessionDevice,
+        at: DateTime<Utc>,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_action_email(
+            user_email,
+            ActionEmail {
+                subject: "Date.rs New Sign-in",
+                heading: "New sign-in to your account",
+                message: &new_device_message(device, at),
+                action_text: "This Wasn't Me",
+                action_path: format!("account/not_me/{}", token.expose()),
+            },
+        )
+        .await
+    }
+    async fn send_action_email(
+        &self,
+        user_email: &str,
+        email: ActionEmail<'_>,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send(&PostMarkEmail {
+            html: render_action_email_html(&email, &self.app_url)?,
+            to: String::from(user_email),
+            subject: email.subject.into(),
+            from: self.from_email.clone(),
+        })
+        .await
+    }
+    async fn send(&self, email: &PostMarkEmail) -> anyhow::Result<reqwest::Response> {
         let request = self
             .c
             .post("https://api.postmarkapp.com/email")
             .header("X-Postmark-Server-Token", self.api_token.expose_secret())
-            .json(&PostMarkEmail::new_auth(
-                user_email,
-                &self.app_url,
-                &self.from_email,
-            )?)
+            .json(email)
             .build()?;
-        println!("{:?}", request);
-        let response = self.c.execute(request).await?;
+        // Postmark answers a refused email with an error status, which mustn't pass as sent.
+        let response = self
+            .c
+            .execute(request)
+            .await?
+            .error_for_status()
+            .context("Postmark refused the email")?;
         Ok(response)
     }
 }
 
+/// An email that asks the user to follow a single link.
+#[derive(Serialize)]
+struct ActionEmail<'a> {
+    subject: &'a str,
+    heading: &'a str,
+    message: &'a str,
+    action_text: &'a str,
+    /// Path of the link, relative to the app's url.
+    action_path: String,
+}
+
 #[derive(Serialize, Deserialize)]
 struct PostMarkEmail {
     #[serde(rename = "From")]
@@ -65,42 +321,153 @@ struct PostMarkEmail {
 impl PostMarkEmail {
     fn new_auth(
         user_email: &str,
+        token: &Token,
         app_url: &str,
         from_email: &str,
     ) -> anyhow::Result<PostMarkEmail> {
         Ok(PostMarkEmail {
-            html: render_email_html(user_email, app_url)?,
+            html: render_email_html(token, app_url)?,
             to: String::from(user_email),
             subject: "Date.rs Authentication".into(),
             from: from_email.into(),
         })
     }
 }
-#[get("/authenticate/{email}")]
+/// Issue a fresh verification token to a user and email it to them.
+pub async fn send_verification(app_state: &AppState, user_id: &Uuid, email: &str) -> Result<()> {
+    let token = app_state
+        .repo
+        .create_token(
+            user_id,
+            TokenPurpose::EmailVerification,
+            chrono::Duration::hours(VERIFICATION_LENGTH_HOURS),
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    app_state
+        .email_client
+        .send_auth_email(email, &token)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(())
+}
+
+#[get("/authenticate/{token}")]
 pub async fn authenticate_by_email(
     app_state: web::Data<AppState>,
-    email: web::Path<String>,
+    csrf_token: CsrfToken,
+    token: web::Path<String>,
 ) -> Result<HttpResponse> {
-    let id = app_state
+    let token = Token::from(token.into_inner());
+    let id = match app_state
         .repo
-        .get_unauthorized_user_id(&email)
+        .consume_token(&token, TokenPurpose::EmailVerification)
         .await
-        .ok_or(ErrorUnauthorized("No user found with this email."))?;
-
+    {
+        Ok(id) => id,
+        Err(TokenError::UnexpectedError(e)) => return Err(ErrorInternalServerError(e)),
+        Err(e) => {
+            info!("Verification failed: {}", e);
+            let page = render_verification_page(
+                &app_state,
+                &csrf_token,
+                &format!("{} Request a new link below.", e),
+                true,
+            )?;
+            return Ok(match e {
+                TokenError::Invalid => HttpResponse::NotFound().body(page),
+                _ => HttpResponse::Gone().body(page),
+            });
+        }
+    };
     app_state
         .repo
         .activate_user(&id)
         .await
         .map_err(ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().finish())
+    Ok(HttpResponse::Ok().body(render_verification_page(
+        &app_state,
+        &csrf_token,
+        "Your account is active, you can now log in.",
+        false,
+    )?))
+}
+
+/// Send a new verification link, behind the proof-of-work check and throttled per address.
+#[post("/resend_verification")]
+pub async fn resend_verification(
+    app_state: web::Data<AppState>,
+    csrf_token: CsrfToken,
+    mut form: Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let email = form.remove("email").unwrap_or_default();
+    if let Some(resp) = check_proof_of_work(&app_state, &csrf_token, &mut form, &email).await? {
+        return Ok(resp);
+    }
+    // Counted whether or not the account exists, so the wait gives nothing away either.
+    let email_key = EmailAddress::normalize(&email);
+    let subject = ThrottleSubject::Verification(&email_key);
+    let throttle = app_state
+        .repo
+        .get_throttle(&subject)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if let Some(wait) = throttle.retry_after(Utc::now()) {
+        let seconds = wait.num_seconds().max(1);
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, seconds.to_string()))
+            .body(render_verification_page(
+                &app_state,
+                &csrf_token,
+                &format!(
+                    "Too many links were asked for, try again in {} seconds.",
+                    seconds
+                ),
+                true,
+            )?));
+    }
+    app_state
+        .repo
+        .record_failure(&subject)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    // Reply the same way whether or not the account exists, so this can't be used to find users.
+    if let Some(id) = app_state.repo.get_unauthorized_user_id(&email_key).await {
+        send_verification(&app_state, &id, &email_key).await?;
+    }
+    Ok(HttpResponse::Ok().body(render_verification_page(
+        &app_state,
+        &csrf_token,
+        "If that address has an account waiting for activation, a new link is on its way.",
+        false,
+    )?))
+}
+
+fn render_verification_page(
+    app_state: &AppState,
+    csrf_token: &CsrfToken,
+    message: &str,
+    resend: bool,
+) -> Result<String> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("proof_of_work", &app_state.proof_of_work.is_some());
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", message);
+    ctx.insert("resend", &resend);
+    tera::Tera::one_off(
+        &fs::read_to_string("./pages/verification.html")?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
 }
 
-fn render_email_html(email: &str, app_url: &str) -> anyhow::Result<String> {
+fn render_email_html(token: &Token, app_url: &str) -> anyhow::Result<String> {
     let mut ctx = tera::Context::new();
     ctx.insert("daters_url", app_url);
     ctx.insert(
         "authentication_url",
-        &format!("{}/{}/{}", app_url, "authenticate", email),
+        &format!("{}/{}/{}", app_url, "authenticate", token.expose()),
     );
     tera::Tera::one_off(
         &fs::read_to_string("./pages/welcome_email.html")?,
@@ -109,6 +476,36 @@ fn render_email_html(email: &str, app_url: &str) -> anyhow::Result<String> {
     )
     .context("Rendering email template failed.")
 }
+/// Body of the join request email, both values come from the user asking.
+fn join_request_message(requester_email: &str, group_name: &str) -> String {
+    format!(
+        "{} has asked to join {} on Date.rs. \
+        Follow the link below to approve or reject their request.",
+        requester_email, group_name
+    )
+}
+/// Body of the new sign-in email, the device's description is whatever the client sent.
+fn new_device_message(device: &SessionDevice, at: DateTime<Utc>) -> String {
+    format!(
+        "Your Date.rs account was signed in to on {}, with {}. \
+        If this was you, there's nothing to do. If it wasn't, follow the link below \
+        to sign out every device, and choose a new password.",
+        at.format("%Y-%m-%d %H:%M UTC"),
+        device.describe()
+    )
+}
+/// Render an action email, escaping its text as messages can carry what users typed.
+fn render_action_email_html(email: &ActionEmail, app_url: &str) -> anyhow::Result<String> {
+    let mut ctx = tera::Context::from_serialize(email)?;
+    ctx.insert("daters_url", app_url);
+    ctx.insert("action_url", &format!("{}/{}", app_url, email.action_path));
+    tera::Tera::one_off(
+        &fs::read_to_string("./pages/action_email.html")?,
+        &ctx,
+        true,
+    )
+    .context("Rendering email template failed.")
+}
 #[cfg(test)]
 mod test {
     use std::fs;
@@ -117,8 +514,64 @@ mod test {
     use toml;
     #[test]
     fn test_html_render() -> anyhow::Result<()> {
-        let response_html = render_email_html("test@email.com", "test.com").unwrap();
-        assert!(response_html.contains("test@email.com"));
+        let token = Token::generate();
+        let response_html = render_email_html(&token, "test.com").unwrap();
+        assert!(response_html.contains(&format!("test.com/authenticate/{}", token.expose())));
+        Ok(())
+    }
+    #[test]
+    fn test_action_html_render() -> anyhow::Result<()> {
+        let email = ActionEmail {
+            subject: "Subject",
+            heading: "Heading",
+            message: "Message",
+            action_text: "Click",
+            action_path: "reset_password/abc".into(),
+        };
+        let html = render_action_email_html(&email, "test.com")?;
+        assert!(html.contains("Heading"));
+        assert!(html.contains("test.com/reset_password/abc"));
+        Ok(())
+    }
+    #[test]
+    fn test_join_request_email_escapes_names() -> anyhow::Result<()> {
+        let message = join_request_message(
+            "<b>asker</b>@test.com",
+            r#"<a href="https://evil.example">Climbers</a>"#,
+        );
+        let email = ActionEmail {
+            subject: "Subject",
+            heading: "Heading",
+            message: &message,
+            action_text: "Click",
+            action_path: "group/requests?group=1".into(),
+        };
+        let html = render_action_email_html(&email, "test.com")?;
+        assert!(!html.contains("<a href=\"https://evil.example\">"));
+        assert!(!html.contains("<b>asker</b>"));
+        assert!(html.contains("&lt;b&gt;asker"));
+        Ok(())
+    }
+    #[test]
+    fn test_new_device_email_escapes_user_agent() -> anyhow::Result<()> {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((
+                actix_web::http::header::USER_AGENT,
+                r#"<a href="https://evil.example">Reset here</a>"#,
+            ))
+            .to_http_request();
+        let message = new_device_message(&SessionDevice::from_request(&req), Utc::now());
+        let email = ActionEmail {
+            subject: "Subject",
+            heading: "Heading",
+            message: &message,
+            action_text: "Click",
+            action_path: "account/not_me/abc".into(),
+        };
+        let html = render_action_email_html(&email, "test.com")?;
+        assert!(!html.contains("<a href=\"https://evil.example\">"));
+        assert!(html.contains("&lt;a href="));
+        assert!(html.contains("test.com/account/not_me/abc"));
         Ok(())
     }
 
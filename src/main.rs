@@ -1,8 +1,21 @@
+use actix_web::cookie::Key;
 use actix_web::web::ServiceConfig;
 use anyhow::Context;
+use argon2::Params;
+use date_rs::auth::default_hash_params;
+use date_rs::auth::email_address::DisposableDomains;
+use date_rs::auth::join_request::JOIN_REQUEST_LENGTH_DAYS;
+use date_rs::auth::oidc::OidcConfig;
+use date_rs::auth::passkey::RelyingParty;
+use date_rs::auth::password_policy::{BreachedPasswords, PasswordPolicy, BREACHED_PASSWORDS_FILE};
+use date_rs::auth::proof_of_work::ProofOfWork;
+use date_rs::backend::postgres::PgRepo;
+use date_rs::routes::delete_account::remove_scheduled_accounts;
 use date_rs::routes::landing::MainService;
+use secrecy::Secret;
 use shuttle_actix_web::ShuttleActixWeb;
 use sqlx::{Pool, Postgres};
+use std::net::IpAddr;
 #[shuttle_runtime::main]
 async fn main(
     #[shuttle_shared_db::Postgres(
@@ -18,12 +31,115 @@ async fn main(
         secrets.get("url").expect("Set url"),
         secrets.get("from_email").expect("Set from_email"),
     );
+    let session_key = Key::derive_from(
+        secrets
+            .get("session_key")
+            .expect("Set session_key, at least 32 bytes")
+            .as_bytes(),
+    );
+    // Raising these rehashes each user's password the next time they log in.
+    let hash_params = match secrets.get("argon2_memory_kib") {
+        Some(memory_kib) => Params::new(
+            memory_kib
+                .parse()
+                .context("argon2_memory_kib must be a number")?,
+            secrets
+                .get("argon2_iterations")
+                .map_or(Ok(Params::DEFAULT_T_COST), |v| v.parse())
+                .context("argon2_iterations must be a number")?,
+            secrets
+                .get("argon2_parallelism")
+                .map_or(Ok(Params::DEFAULT_P_COST), |v| v.parse())
+                .context("argon2_parallelism must be a number")?,
+            None,
+        )
+        .map_err(|e| anyhow::anyhow!("Invalid argon2 parameters: {}", e))?,
+        None => default_hash_params(),
+    };
+    let oidc = match secrets.get("oidc_issuer") {
+        Some(issuer) => Some(OidcConfig {
+            name: secrets
+                .get("oidc_name")
+                .unwrap_or_else(|| "single sign-on".to_string()),
+            issuer,
+            client_id: secrets.get("oidc_client_id").expect("Set oidc_client_id"),
+            client_secret: Secret::new(
+                secrets
+                    .get("oidc_client_secret")
+                    .expect("Set oidc_client_secret"),
+            ),
+            redirect_url: format!("{}/oidc/callback", secrets.get("url").expect("Set url")),
+        }),
+        None => None,
+    };
+    let default_policy = PasswordPolicy::default();
+    let password_policy = PasswordPolicy {
+        min_length: secrets
+            .get("password_min_length")
+            .map_or(Ok(default_policy.min_length), |v| v.parse())
+            .context("password_min_length must be a number")?,
+        min_score: secrets
+            .get("password_min_score")
+            .map_or(Ok(default_policy.min_score), |v| v.parse())
+            .context("password_min_score must be a number from 0 to 4")?,
+        breached: BreachedPasswords::load(
+            secrets
+                .get("breached_passwords_file")
+                .unwrap_or_else(|| BREACHED_PASSWORDS_FILE.to_string()),
+        )?,
+    };
+    let default_pow = ProofOfWork::default();
+    let proof_of_work = ProofOfWork {
+        base_difficulty: secrets
+            .get("pow_difficulty")
+            .map_or(Ok(default_pow.base_difficulty), |v| v.parse())
+            .context("pow_difficulty must be a number of bits")?,
+        max_difficulty: secrets
+            .get("pow_max_difficulty")
+            .map_or(Ok(default_pow.max_difficulty), |v| v.parse())
+            .context("pow_max_difficulty must be a number of bits")?,
+        ..default_pow
+    };
+    let disposable_domains = match secrets.get("disposable_domains_file") {
+        Some(path) => DisposableDomains::load(path)?,
+        None => DisposableDomains::default(),
+    };
+    let join_request_expiry = chrono::Duration::days(
+        secrets
+            .get("join_request_days")
+            .map_or(Ok(JOIN_REQUEST_LENGTH_DAYS), |v| v.parse())
+            .context("join_request_days must be a number")?,
+    );
+    let trusted_proxies = secrets
+        .get("trusted_proxies")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| v.trim().parse())
+        .collect::<Result<Vec<IpAddr>, _>>()
+        .context("trusted_proxies must be a comma separated list of ip addresses")?;
+    let relying_party = RelyingParty::from_origin(&secrets.get("url").expect("Set url"))
+        .context("url must be a valid url")?;
     let pool = Pool::<Postgres>::connect(&conn_str)
         .await
         .context("Db connection failed")?;
     sqlx::migrate!().run(&pool).await.unwrap();
+    tokio::spawn(remove_scheduled_accounts(PgRepo::new(pool.clone())));
     let config = move |cfg: &mut ServiceConfig| {
-        MainService::new(pool, email_client.clone()).service_configuration(cfg)
+        let service = MainService::new(pool, email_client.clone())
+            .session_key(session_key.clone())
+            .hash_params(hash_params.clone())
+            .password_policy(password_policy.clone())
+            .disposable_domains(disposable_domains.clone())
+            .proof_of_work(proof_of_work.clone())
+            .join_request_expiry(join_request_expiry)
+            .trusted_proxies(trusted_proxies.clone())
+            .relying_party(relying_party.clone());
+        match oidc.clone() {
+            Some(oidc) => service.oidc(oidc),
+            None => service,
+        }
+        .service_configuration(cfg)
     };
     Ok(config.into())
 }
@@ -1,2 +1,15 @@
+pub mod account;
+pub mod api_tokens;
 pub mod dates_service;
+pub mod delete_account;
+pub mod email_link;
+pub mod group;
+pub mod invites;
+pub mod join_requests;
 pub mod landing;
+pub mod oidc;
+pub mod passkeys;
+pub mod password_reset;
+pub mod proof_of_work;
+pub mod sessions;
+pub mod two_factor;
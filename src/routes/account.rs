@@ -0,0 +1,338 @@
+//! Account settings for a logged in user.
+use std::fs;
+
+use actix_web::error::ErrorInternalServerError;
+use actix_web::web::{self, Data, Form, ServiceConfig};
+use actix_web::{get, post, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use secrecy::{ExposeSecret, Secret};
+use serde::Deserialize;
+use serde_json::json;
+use tera::{Context, Tera};
+use tracing::info;
+
+use crate::auth::audit::RECENT_EVENTS;
+use crate::auth::csrf::CsrfToken;
+use crate::auth::email_address::EmailAddress;
+use crate::auth::session::{session_cookie, session_token, SessionDevice};
+use crate::auth::token::{Token, TokenError, TokenPurpose};
+use crate::auth::user::{AuthorizedUser, UserValidationError};
+use crate::domain::repository::AppState;
+
+/// How long a link confirming a new email address stays valid.
+pub const EMAIL_CHANGE_LENGTH_HOURS: i64 = 24;
+/// How recently a user without a password has to have signed in to make sensitive changes.
+pub const REAUTH_MINUTES: i64 = 10;
+
+pub fn account_service(cfg: &mut ServiceConfig) {
+    cfg.service(account_page)
+        .service(change_password)
+        .service(change_email)
+        .service(confirm_email);
+}
+
+#[get("/account")]
+async fn account_page(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .body(render_account_page(&app_state, &user, &csrf_token, None, None).await?))
+}
+
+/// Check it's really the user asking for a sensitive change, giving the reason if it isn't.
+///
+/// Accounts with a password give it. Those without one have to have signed in within
+/// the last `REAUTH_MINUTES`, with a sign-in link, passkey or single sign-on.
+pub(crate) async fn confirm_identity(
+    app_state: &AppState,
+    req: &HttpRequest,
+    user: &AuthorizedUser,
+    password: Secret<String>,
+) -> Result<Option<&'static str>> {
+    match app_state
+        .repo
+        .check_password(&user.id(), password)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Some(true) => return Ok(None),
+        Some(false) => return Ok(Some("Your current password is incorrect.")),
+        None => {}
+    }
+    let Some(token) = session_token(req, &app_state.session_key) else {
+        return Ok(Some(REAUTH_REQUIRED));
+    };
+    let since = Utc::now() - chrono::Duration::minutes(REAUTH_MINUTES);
+    let fresh = app_state
+        .repo
+        .list_sessions(&user.id(), &token)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .iter()
+        .any(|s| s.current && s.created_at > since);
+    Ok((!fresh).then_some(REAUTH_REQUIRED))
+}
+const REAUTH_REQUIRED: &str =
+    "Your account has no password, sign in again to confirm it's you, then try again.";
+
+/// Whether the user confirms changes with a password, rather than by signing in again.
+pub(crate) async fn has_password(app_state: &AppState, user: &AuthorizedUser) -> Result<bool> {
+    app_state
+        .repo
+        .has_password(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)
+}
+
+/// Confirm the user's identity, rendering the account page with an error if it isn't.
+async fn check_current_password(
+    app_state: &AppState,
+    req: &HttpRequest,
+    user: &AuthorizedUser,
+    csrf_token: &CsrfToken,
+    password: Secret<String>,
+) -> Result<Option<HttpResponse>> {
+    let Some(error) = confirm_identity(app_state, req, user, password).await? else {
+        return Ok(None);
+    };
+    Ok(Some(HttpResponse::Unauthorized().body(
+        render_account_page(app_state, user, csrf_token, None, Some(error)).await?,
+    )))
+}
+
+#[derive(Deserialize)]
+struct ChangePasswordForm {
+    current_password: Secret<String>,
+    new_password: Secret<String>,
+    confirm_password: Secret<String>,
+}
+/// Change the user's password, signing them out everywhere but here.
+#[post("/account/password")]
+async fn change_password(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    form: Form<ChangePasswordForm>,
+) -> Result<HttpResponse> {
+    let form = form.into_inner();
+    if form.new_password.expose_secret() != form.confirm_password.expose_secret() {
+        return Ok(HttpResponse::BadRequest().body(
+            render_account_page(
+                &app_state,
+                &user,
+                &csrf_token,
+                None,
+                Some("New passwords don't match."),
+            )
+            .await?,
+        ));
+    }
+    if let Err(e) = app_state.password_policy.check(&form.new_password) {
+        return Ok(HttpResponse::BadRequest().body(
+            render_account_page(&app_state, &user, &csrf_token, None, Some(&e.to_string())).await?,
+        ));
+    }
+    if let Some(resp) =
+        check_current_password(&app_state, &req, &user, &csrf_token, form.current_password).await?
+    {
+        return Ok(resp);
+    }
+    app_state
+        .repo
+        .change_user_password(&user.id(), form.new_password)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Password changed for {}", user.id());
+    // Changing the password ended every session, this browser gets a new one.
+    let token = app_state
+        .repo
+        .create_session(&user.id(), &SessionDevice::from_request(&req))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .cookie(session_cookie(&token, &app_state.session_key))
+        .body(
+            render_account_page(
+                &app_state,
+                &user,
+                &CsrfToken::for_session(&token, &app_state.session_key),
+                Some("Your password has been changed, and every other device signed out."),
+                None,
+            )
+            .await?,
+        ))
+}
+
+#[derive(Deserialize)]
+struct ChangeEmailForm {
+    new_email: String,
+    current_password: Secret<String>,
+}
+#[post("/account/email")]
+async fn change_email(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    form: Form<ChangeEmailForm>,
+) -> Result<HttpResponse> {
+    let form = form.into_inner();
+    let new_email = match app_state.disposable_domains.parse_address(&form.new_email) {
+        Ok(email) => email,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().body(
+                render_account_page(&app_state, &user, &csrf_token, None, Some(&e.to_string()))
+                    .await?,
+            ))
+        }
+    };
+    if let Some(resp) =
+        check_current_password(&app_state, &req, &user, &csrf_token, form.current_password).await?
+    {
+        return Ok(resp);
+    }
+    if app_state
+        .repo
+        .get_user_by_email(new_email.as_str())
+        .await
+        .is_ok()
+        || app_state
+            .repo
+            .get_unauthorized_user_id(new_email.as_str())
+            .await
+            .is_some()
+    {
+        return Ok(HttpResponse::Conflict().body(
+            render_account_page(
+                &app_state,
+                &user,
+                &csrf_token,
+                None,
+                Some(&UserValidationError::EmailTaken.to_string()),
+            )
+            .await?,
+        ));
+    }
+    // The address only changes once the link sent to it is followed.
+    let token = app_state
+        .repo
+        .create_token_with_payload(
+            &user.id(),
+            TokenPurpose::EmailChange,
+            chrono::Duration::hours(EMAIL_CHANGE_LENGTH_HOURS),
+            Some(new_email.to_string()),
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    app_state
+        .email_client
+        .send_email_change_email(new_email.as_str(), &token)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().body(
+        render_account_page(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some(&format!(
+                "We've sent a link to {}, follow it to confirm the change.",
+                new_email
+            )),
+            None,
+        )
+        .await?,
+    ))
+}
+
+#[get("/account/confirm_email/{token}")]
+async fn confirm_email(
+    app_state: Data<AppState>,
+    token: web::Path<String>,
+) -> Result<HttpResponse> {
+    let token = Token::from(token.into_inner());
+    let (user_id, new_email) = match app_state
+        .repo
+        .consume_token_with_payload(&token, TokenPurpose::EmailChange)
+        .await
+    {
+        Ok((id, Some(email))) => (
+            id,
+            EmailAddress::parse(&email).map_err(ErrorInternalServerError)?,
+        ),
+        Ok((_, None)) => return Err(ErrorInternalServerError("Email change without an email.")),
+        Err(TokenError::UnexpectedError(e)) => return Err(ErrorInternalServerError(e)),
+        Err(e) => {
+            return Ok(
+                HttpResponse::Gone().body(render_email_confirmation(&format!(
+                    "{} Request the change again from your account page.",
+                    e
+                ))?),
+            )
+        }
+    };
+    match app_state.repo.change_user_email(&user_id, &new_email).await {
+        Ok(()) => Ok(HttpResponse::Ok().body(render_email_confirmation(&format!(
+            "Your email is now {}.",
+            new_email
+        ))?)),
+        Err(e @ UserValidationError::EmailTaken) => {
+            Ok(HttpResponse::Conflict().body(render_email_confirmation(&e.to_string())?))
+        }
+        Err(e) => Err(ErrorInternalServerError(e)),
+    }
+}
+
+fn render_email_confirmation(message: &str) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert("message", message);
+    Tera::one_off(
+        &fs::read_to_string("./pages/email_confirmation.html")?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
+}
+
+/// Render the account settings page, with the user's recent security events.
+///
+/// * `message`: Shown after a successful change.
+/// * `error`: Shown when a change was rejected.
+pub async fn render_account_page(
+    app_state: &AppState,
+    user: &AuthorizedUser,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+    error: Option<&str>,
+) -> Result<String> {
+    let events: Vec<_> = app_state
+        .repo
+        .list_user_events(&user.id(), RECENT_EVENTS)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|e| {
+            json!({
+                "description": e.kind.description(),
+                "ip": e.ip,
+                "created_at": e.created_at.format("%Y-%m-%d %H:%M").to_string(),
+            })
+        })
+        .collect();
+    let mut ctx = Context::new();
+    ctx.insert("user_email", user.email());
+    ctx.insert("events", &events);
+    ctx.insert(
+        "sso_name",
+        &app_state.oidc.as_ref().map(|oidc| &oidc.config.name),
+    );
+    ctx.insert("has_password", &has_password(app_state, user).await?);
+    ctx.insert("reauth_minutes", &REAUTH_MINUTES);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", &message);
+    ctx.insert("error", &error);
+    Tera::one_off(&fs::read_to_string("./pages/account.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
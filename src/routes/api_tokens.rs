@@ -0,0 +1,188 @@
+//! Creating and revoking personal API tokens from the account page.
+use std::collections::HashMap;
+use std::fs;
+
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound};
+use actix_web::web::{Data, Form, Path, ServiceConfig};
+use actix_web::{get, post, HttpResponse, Result};
+use chrono::Utc;
+use serde_json::json;
+use tera::{Context, Tera};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::auth::api_token::{ApiScope, API_TOKEN_MAX_DAYS};
+use crate::auth::csrf::CsrfToken;
+use crate::auth::user::AuthorizedUser;
+use crate::domain::repository::AppState;
+
+/// Longest name a token can be given.
+pub const API_TOKEN_NAME_LENGTH: usize = 64;
+
+pub fn api_token_service(cfg: &mut ServiceConfig) {
+    cfg.service(api_tokens_page)
+        .service(create_api_token)
+        .service(revoke_api_token);
+}
+
+/// What the tokens page shows.
+#[derive(Default)]
+struct ApiTokensPage<'a> {
+    /// Shown once, right after the token is created.
+    new_token: Option<&'a str>,
+    message: Option<&'a str>,
+    error: Option<&'a str>,
+}
+
+#[get("/account/api_tokens")]
+async fn api_tokens_page(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .body(render_api_tokens(&app_state, &user, &csrf_token, ApiTokensPage::default()).await?))
+}
+
+/// Create a token from the page's form.
+///
+/// Each scope is a checkbox named after it, at least one has to be ticked.
+#[post("/account/api_tokens")]
+async fn create_api_token(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    form: Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let name = form
+        .get("name")
+        .map(|n| n.trim())
+        .filter(|n| !n.is_empty() && n.chars().count() <= API_TOKEN_NAME_LENGTH);
+    let days = form
+        .get("expires_in_days")
+        .and_then(|d| d.parse::<i64>().ok())
+        .filter(|d| (1..=API_TOKEN_MAX_DAYS).contains(d));
+    let (Some(name), Some(days)) = (name, days) else {
+        return Ok(HttpResponse::BadRequest().body(
+            render_api_tokens(
+                &app_state,
+                &user,
+                &csrf_token,
+                ApiTokensPage {
+                    error: Some("Give the token a name, and an expiry of up to a year."),
+                    ..Default::default()
+                },
+            )
+            .await?,
+        ));
+    };
+    let scopes: Vec<ApiScope> = ApiScope::ALL
+        .into_iter()
+        .filter(|s| form.contains_key(s.as_str()))
+        .collect();
+    if scopes.is_empty() {
+        return Ok(HttpResponse::BadRequest().body(
+            render_api_tokens(
+                &app_state,
+                &user,
+                &csrf_token,
+                ApiTokensPage {
+                    error: Some("Pick at least one scope for the token."),
+                    ..Default::default()
+                },
+            )
+            .await?,
+        ));
+    }
+    let token = app_state
+        .repo
+        .create_api_token(
+            &user.id(),
+            name,
+            &scopes,
+            Utc::now() + chrono::Duration::days(days),
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("API token created for {}", user.id());
+    Ok(HttpResponse::Created().body(
+        render_api_tokens(
+            &app_state,
+            &user,
+            &csrf_token,
+            ApiTokensPage {
+                new_token: Some(token.expose()),
+                message: Some("Copy your token now, it won't be shown again."),
+                ..Default::default()
+            },
+        )
+        .await?,
+    ))
+}
+
+#[post("/account/api_tokens/{token_id}/revoke")]
+async fn revoke_api_token(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    token_id: Path<Uuid>,
+) -> Result<HttpResponse> {
+    if !app_state
+        .repo
+        .revoke_api_token(&user.id(), &token_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound("No such token."));
+    }
+    info!("API token revoked for {}", user.id());
+    Ok(HttpResponse::Ok().body(
+        render_api_tokens(
+            &app_state,
+            &user,
+            &csrf_token,
+            ApiTokensPage {
+                message: Some("Token revoked."),
+                ..Default::default()
+            },
+        )
+        .await?,
+    ))
+}
+
+async fn render_api_tokens(
+    app_state: &AppState,
+    user: &AuthorizedUser,
+    csrf_token: &CsrfToken,
+    page: ApiTokensPage<'_>,
+) -> Result<String> {
+    let now = Utc::now();
+    let tokens: Vec<_> = app_state
+        .repo
+        .list_api_tokens(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|t| {
+            json!({
+                "id": t.token_id,
+                "name": t.name,
+                "scopes": t.scopes.join(", "),
+                "created_at": t.created_at.format("%Y-%m-%d").to_string(),
+                "expires_at": t.expires_at.format("%Y-%m-%d").to_string(),
+                "expired": t.expires_at <= now,
+                "last_used_at": t.last_used_at.map(|t| t.format("%Y-%m-%d %H:%M").to_string()),
+            })
+        })
+        .collect();
+    let mut ctx = Context::new();
+    ctx.insert("tokens", &tokens);
+    ctx.insert("scopes", &ApiScope::ALL.map(|s| s.as_str()).to_vec());
+    ctx.insert("max_days", &API_TOKEN_MAX_DAYS);
+    ctx.insert("new_token", &page.new_token);
+    ctx.insert("message", &page.message);
+    ctx.insert("error", &page.error);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    Tera::one_off(&fs::read_to_string("./pages/api_tokens.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
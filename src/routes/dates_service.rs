@@ -1,8 +1,12 @@
+use crate::auth::csrf::CsrfToken;
+use crate::auth::role::{GroupMembership, Permission, RoleError};
+use crate::auth::user::GroupUser;
 use crate::domain::dates::Date;
 use crate::domain::dates::Status;
 use crate::domain::repository::InsertDateError;
 use crate::domain::repository::{AppState, ExpansionCache};
 use crate::routes::landing::unauthorized;
+use actix_web::error::ErrorBadRequest;
 use actix_web::error::ErrorForbidden;
 use actix_web::error::ErrorInternalServerError;
 use actix_web::web::Form;
@@ -12,6 +16,7 @@ use actix_web::{delete, HttpResponse, Responder};
 use actix_web::{get, post, web, web::Data};
 use anyhow::anyhow;
 use chrono::{Local, NaiveDateTime};
+use chrono_tz::Tz;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs::read;
@@ -28,110 +33,160 @@ pub fn dates_service(cfg: &mut ServiceConfig) {
         .service(date_remove)
         .service(date_expand)
         .service(date_collapse)
+        .service(approve_date)
+        .service(reject_date)
         .service(edit_description)
         .service(get_description)
         .service(update_description);
 }
-#[get("/{user_id}")]
+#[get("")]
 pub async fn date_page(
     app_state: Data<AppState>,
-    user_id: web::Path<Uuid>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
 ) -> Result<HttpResponse> {
-    date_page_inner(app_state.into_inner(), *user_id).await
+    date_page_inner(app_state.into_inner(), &user, &csrf_token).await
 }
 
-pub async fn date_page_inner(app_state: Arc<AppState>, user_id: Uuid) -> Result<HttpResponse> {
-    if !app_state.repo.check_user_has_access(&user_id).await {
-        info!("Unauthorized user {:?} attempted access", user_id);
-        return unauthorized();
-    }
+pub async fn date_page_inner(
+    app_state: Arc<AppState>,
+    user: &GroupUser,
+    csrf_token: &CsrfToken,
+) -> Result<HttpResponse> {
+    let user_id = user.user_id;
     if app_state.cache.reset(&user_id).is_err() {
         debug!("Cache doesn't contain {:?}", user_id);
     };
+    let groups = app_state
+        .repo
+        .get_user_groups(&user_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let join_requests = app_state
+        .repo
+        .list_group_join_requests(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .len();
+    let display = date_display(&app_state, user).await?;
     Ok(HttpResponse::Ok().body(index_template_load(
-        app_state.repo.get_all(&user_id).await,
+        app_state.repo.get_all(user.user_group, &user_id).await,
         &app_state.cache,
-        &user_id,
+        user,
+        &groups,
+        join_requests,
+        csrf_token,
+        &display,
     )?))
 }
-fn index_template_load(dates: Vec<Date>, cache: &ExpansionCache, user_id: &Uuid) -> Result<String> {
+/// Render the dates page, with a switcher if the user is in more than one group.
+///
+/// * `groups`: Every group the user belongs to.
+/// * `join_requests`: How many are waiting to join the open group.
+fn index_template_load(
+    dates: Vec<Date>,
+    cache: &ExpansionCache,
+    user: &GroupUser,
+    groups: &[GroupMembership],
+    join_requests: usize,
+    csrf_token: &CsrfToken,
+    display: &DateDisplay,
+) -> Result<String> {
     let mut ctx = Context::new();
-    let buttons = render_dates(dates, cache, user_id)?;
+    let buttons = render_dates(dates, cache, &user.user_id, csrf_token, display)?;
     ctx.insert("buttons", &buttons);
-    Tera::one_off(&std::fs::read_to_string("./pages/index.html")?, &ctx, false)
+    ctx.insert("group", &user.user_group);
+    ctx.insert("groups", groups);
+    ctx.insert("join_requests", &join_requests);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    Tera::one_off(&std::fs::read_to_string("./pages/index.html")?, &ctx, true)
         .map_err(ErrorInternalServerError)
 }
 
-#[post("/{user_id}/{date_id}/increment")]
+#[post("/{date_id}/increment")]
 async fn date_count_increment(
-    ids: web::Path<(Uuid, Uuid)>,
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
     app_state: Data<AppState>,
+    csrf_token: CsrfToken,
 ) -> Result<impl Responder> {
-    let (user_id, date_id) = *ids;
+    let (user_id, date_id) = (user.user_id, *date_id);
     tracing::info!("Increment pushed on: {}", &date_id);
     app_state
         .repo
-        .increment_date_count(&date_id, &user_id)
-        .await
-        .map_err(ErrorInternalServerError)?;
+        .increment_date_count(&date_id, user.user_group, &user_id)
+        .await?;
     Ok(HttpResponse::Ok().body(render_dates(
-        app_state.repo.get_all(&user_id).await,
+        app_state.repo.get_all(user.user_group, &user_id).await,
         &app_state.cache,
         &user_id,
+        &csrf_token,
+        &date_display(&app_state, &user).await?,
     )?))
 }
-#[post("/{user_id}/{date_id}/decrement")]
+#[post("/{date_id}/decrement")]
 async fn date_count_decrement(
-    ids: web::Path<(Uuid, Uuid)>,
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
     app_state: Data<AppState>,
+    csrf_token: CsrfToken,
 ) -> Result<impl Responder> {
-    let (user_id, date_id) = *ids;
+    let (user_id, date_id) = (user.user_id, *date_id);
     let date_id = &date_id;
     tracing::info!("Decrement pushed on: {}", &date_id);
     app_state
         .repo
-        .decrement_date_count(date_id, &user_id)
-        .await
-        .map_err(ErrorInternalServerError)?;
+        .decrement_date_count(date_id, user.user_group, &user_id)
+        .await?;
     Ok(HttpResponse::Ok().body(render_dates(
-        app_state.repo.get_all(&user_id).await,
+        app_state.repo.get_all(user.user_group, &user_id).await,
         &app_state.cache,
         &user_id,
+        &csrf_token,
+        &date_display(&app_state, &user).await?,
     )?))
 }
-#[post("/{user_id}/{date_id}/remove")]
+#[post("/{date_id}/remove")]
 async fn date_remove(
-    ids: web::Path<(Uuid, Uuid)>,
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
     app_state: Data<AppState>,
+    csrf_token: CsrfToken,
 ) -> Result<impl Responder> {
-    let (user_id, date_id) = *ids;
+    let (user_id, date_id) = (user.user_id, *date_id);
     let date_id = &date_id;
     tracing::info!("Collapse pushed on: {}", &date_id);
     app_state
         .repo
-        .remove(date_id, &user_id)
-        .await
-        .map_err(ErrorInternalServerError)?;
+        .remove(date_id, user.user_group, &user_id)
+        .await?;
     Ok(HttpResponse::Ok().body(render_dates(
-        app_state.repo.get_all(&user_id).await,
+        app_state.repo.get_all(user.user_group, &user_id).await,
         &app_state.cache,
         &user_id,
+        &csrf_token,
+        &date_display(&app_state, &user).await?,
     )?))
 }
-#[get("/{user_id}/{date_id}")]
+#[get("/{date_id}")]
 async fn date_expand(
-    ids: web::Path<(Uuid, Uuid)>,
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
     app_state: Data<AppState>,
 ) -> Result<impl Responder> {
-    let (user_id, date_id) = *ids;
+    let (user_id, date_id) = (user.user_id, *date_id);
     tracing::info!("Expand pushed on: {}", date_id);
-    let Some(date) = app_state.repo.get(&date_id, &user_id).await else {
+    let Some(date) = app_state
+        .repo
+        .get(&date_id, user.user_group, &user_id)
+        .await
+    else {
         return Err(ErrorInternalServerError("Date not found"));
     };
     let mut ctx = Context::new();
-    ctx.insert("description", &render_description(&date, &user_id)?);
+    let display = date_display(&app_state, &user).await?;
+    ctx.insert("description", &render_description(&date, &display)?);
     ctx.insert("date", &date);
-    ctx.insert("user_id", &user_id);
     let tera = Tera::new("./pages/button/*.html").unwrap();
     let resp = tera
         .render("button_expanded.html", &ctx)
@@ -139,18 +194,22 @@ async fn date_expand(
     app_state.cache.add(date_id, &user_id);
     Ok(HttpResponse::Ok().body(resp))
 }
-#[post("/{user_id}/{date_id}")]
+#[post("/{date_id}")]
 async fn date_collapse(
-    ids: web::Path<(Uuid, Uuid)>,
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
     app_state: Data<AppState>,
 ) -> Result<impl Responder> {
-    let (user_id, date_id) = *ids;
+    let (user_id, date_id) = (user.user_id, *date_id);
     tracing::info!("Collapse pushed on: {}", &date_id);
-    match app_state.repo.get(&date_id, &user_id).await {
+    match app_state
+        .repo
+        .get(&date_id, user.user_group, &user_id)
+        .await
+    {
         Some(date) => {
             let mut ctx = Context::new();
             ctx.insert("date", &date);
-            ctx.insert("user_id", &user_id);
             let tera = Tera::new("./pages/button/*.html").map_err(ErrorInternalServerError)?;
             let resp = tera
                 .render("button_collapsed.html", &ctx)
@@ -164,80 +223,152 @@ async fn date_collapse(
     }
 }
 
-#[post("/{user_id}/{date_id}/description")]
+#[post("/{date_id}/description")]
 async fn update_description(
     mut map: web::Form<HashMap<String, String>>,
-    ids: web::Path<(Uuid, Uuid)>,
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
     app_state: Data<AppState>,
 ) -> Result<impl Responder> {
-    let (user_id, date_id) = *ids;
+    let (user_id, date_id) = (user.user_id, *date_id);
     info!("Edit description pushed on: {} {}", user_id, date_id);
-    let Some(mut date) = app_state.repo.get(&date_id, &user_id).await else {
+    let Some(mut date) = app_state
+        .repo
+        .get(&date_id, user.user_group, &user_id)
+        .await
+    else {
         return Err(ErrorInternalServerError("Date not found"));
     };
-    let hrs = map.remove("time").unwrap();
-    let day = map.remove("day").unwrap();
+    let display = date_display(&app_state, &user).await?;
+    let (Some(hrs), Some(day), Some(text)) = (
+        map.remove("time"),
+        map.remove("day"),
+        map.remove("description_text"),
+    ) else {
+        return Err(ErrorBadRequest("Missing time, day or description."));
+    };
     if let Ok(naive_date_time) =
         NaiveDateTime::parse_from_str(&format!("{} {}", hrs, day), "%H:%M %Y-%m-%d")
     {
         tracing::debug!("Date time updated: {}:{}", hrs, day);
-        date.description.day = Some(naive_date_time.and_local_timezone(Local).unwrap());
+        // Entered in the group's timezone, a time skipped by a clock change doesn't exist.
+        let Some(day) = naive_date_time.and_local_timezone(display.tz).earliest() else {
+            return Err(ErrorForbidden(
+                "That time doesn't exist in the group's timezone.",
+            ));
+        };
+        date.description.day = Some(day.with_timezone(&Local));
     } else if hrs.is_empty() || day.is_empty() {
         error!("Cant't parse date {:?} from {} {}", date, hrs, day);
         return Err(ErrorForbidden("Cant parse date"));
     };
-    tracing::debug!(
-        "Date description updated: {}",
-        map.get("description_text").unwrap()
-    );
-    date.description.text = map.remove("description_text").unwrap();
-    app_state.repo.update(date.clone(), &user_id).await.unwrap();
-    Ok(HttpResponse::Ok().body(render_description(&date, &user_id)?))
+    tracing::debug!("Date description updated: {}", text);
+    date.description.text = text;
+    app_state
+        .repo
+        .update(date.clone(), user.user_group, &user_id)
+        .await?;
+    Ok(HttpResponse::Ok().body(render_description(&date, &display)?))
 }
-#[delete("/{user_id}/{date_id}/description")]
+#[delete("/{date_id}/description")]
 async fn edit_description(
-    ids: web::Path<(Uuid, Uuid)>,
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
     app_state: Data<AppState>,
 ) -> Result<impl Responder> {
-    let (user_id, date_id) = *ids;
+    let (user_id, date_id) = (user.user_id, *date_id);
     info!("Edit description pushed on: {} {}", user_id, date_id);
-    match app_state.repo.get(&date_id, &user_id).await {
-        Some(date) => Ok(HttpResponse::Ok().body(render_editable_description(&date, &user_id)?)),
+    match app_state
+        .repo
+        .get(&date_id, user.user_group, &user_id)
+        .await
+    {
+        Some(date) => Ok(HttpResponse::Ok().body(render_editable_description(
+            &date,
+            &date_display(&app_state, &user).await?,
+        )?)),
         None => Err(ErrorInternalServerError("Date not found")),
     }
 }
-#[get("/{user_id}/{date_id}/description")]
+#[get("/{date_id}/description")]
 async fn get_description(
-    ids: web::Path<(Uuid, Uuid)>,
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
     app_state: Data<AppState>,
 ) -> Result<impl Responder> {
-    let (user_id, date_id) = *ids;
+    let (user_id, date_id) = (user.user_id, *date_id);
     info!("Get description pushed on: {} {}", user_id, date_id);
-    match app_state.repo.get(&date_id, &user_id).await {
-        Some(date) => Ok(HttpResponse::Ok().body(render_description(&date, &user_id)?)),
+    match app_state
+        .repo
+        .get(&date_id, user.user_group, &user_id)
+        .await
+    {
+        Some(date) => Ok(HttpResponse::Ok().body(render_description(
+            &date,
+            &date_display(&app_state, &user).await?,
+        )?)),
         None => Err(ErrorInternalServerError("Date not found")),
     }
 }
+#[post("/{date_id}/approve")]
+async fn approve_date(
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
+    app_state: Data<AppState>,
+) -> Result<impl Responder> {
+    set_status(&app_state, &user, *date_id, Status::Approved).await
+}
+#[post("/{date_id}/reject")]
+async fn reject_date(
+    date_id: web::Path<Uuid>,
+    user: GroupUser,
+    app_state: Data<AppState>,
+) -> Result<impl Responder> {
+    set_status(&app_state, &user, *date_id, Status::Rejected).await
+}
+/// Approve or reject a date, then show its description again.
+async fn set_status(
+    app_state: &AppState,
+    user: &GroupUser,
+    date_id: Uuid,
+    status: Status,
+) -> Result<HttpResponse> {
+    info!("{:?} pushed on: {} {}", status, user.user_id, date_id);
+    app_state
+        .repo
+        .set_date_status(&date_id, user.user_group, &user.user_id, status)
+        .await?;
+    let Some(date) = app_state
+        .repo
+        .get(&date_id, user.user_group, &user.user_id)
+        .await
+    else {
+        return Err(ErrorInternalServerError("Date not found"));
+    };
+    Ok(HttpResponse::Ok().body(render_description(
+        &date,
+        &date_display(app_state, user).await?,
+    )?))
+}
 #[derive(Deserialize)]
 struct NewDate {
     name: String,
 }
-#[post("/{user_id}/new_date")]
+#[post("/new_date")]
 async fn add_new_date(
     new_date: Form<NewDate>,
-    user_id: web::Path<Uuid>,
+    user: GroupUser,
     app_state: Data<AppState>,
+    csrf_token: CsrfToken,
 ) -> Result<impl Responder> {
-    if !app_state.repo.check_user_has_access(&user_id).await {
-        return unauthorized();
-    }
+    let user_id = user.user_id;
     if new_date.name.is_empty() {
         return Err(ErrorForbidden("Date must have a value."));
     }
 
     match app_state
         .repo
-        .add(Date::new(&*new_date.name), *user_id)
+        .add(Date::new(&*new_date.name), user.user_group, user_id)
         .await
     {
         Ok(_) => (),
@@ -248,16 +379,53 @@ async fn add_new_date(
                     return Err(ErrorInternalServerError("Database Error."))
                 }
                 InsertDateError::GroupMembershipError => return unauthorized(),
+                InsertDateError::Forbidden => {
+                    return Err(ErrorForbidden("Your role in the group doesn't allow that."))
+                }
             }
         }
     }
     Ok(HttpResponse::Ok().body(render_dates(
-        app_state.repo.get_all(&user_id).await,
+        app_state.repo.get_all(user.user_group, &user_id).await,
         &app_state.cache,
         &user_id,
+        &csrf_token,
+        &date_display(&app_state, &user).await?,
     )?))
 }
 
+/// How the open group's dates are shown to the user.
+pub struct DateDisplay {
+    /// Dates are entered and shown in the group's timezone.
+    pub tz: Tz,
+    /// Whether the user's role may approve or reject dates.
+    pub can_approve: bool,
+}
+pub async fn date_display(app_state: &AppState, user: &GroupUser) -> Result<DateDisplay> {
+    let tz = app_state
+        .repo
+        .get_group_profile(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .tz();
+    let role = app_state
+        .repo
+        .get_group_members(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .find(|m| m.user_id == user.user_id)
+        .map(|m| m.role)
+        .ok_or(RoleError::NotMember)?;
+    let can_approve = app_state
+        .repo
+        .get_group_permissions(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .allows(role, Permission::ApproveDates);
+    Ok(DateDisplay { tz, can_approve })
+}
+
 // TODO: Make this only take in a repository and a user id.
 // Further I think the error logging should be morved to the caller.
 //
@@ -269,16 +437,23 @@ async fn add_new_date(
 /// * `dates`: List of dates to render.
 /// * `cache`: A cache of which dates a user has expanded.
 /// * `user_id`: The user id to render the dates for.
-pub fn render_dates(dates: Vec<Date>, cache: &ExpansionCache, user_id: &Uuid) -> Result<String> {
+/// * `csrf_token`: Sent back by htmx with every change to the dates.
+/// * `display`: The group's timezone, and whether the user may approve dates.
+pub fn render_dates(
+    dates: Vec<Date>,
+    cache: &ExpansionCache,
+    user_id: &Uuid,
+    csrf_token: &CsrfToken,
+    display: &DateDisplay,
+) -> Result<String> {
     let mut rendered_dates = vec![];
     for date in dates {
         let mut ctx = Context::new();
         ctx.insert("date", &date);
-        ctx.insert("user_id", user_id);
         let tera = Tera::new("./pages/button/*.html").map_err(ErrorInternalServerError)?;
         // info!("{:?}", tera.get_template_names().collect::<Vec<&str>>());
         if cache.contains(&date.id, user_id).unwrap_or(false) {
-            ctx.insert("description", &render_description(&date, user_id)?);
+            ctx.insert("description", &render_description(&date, display)?);
             rendered_dates.push(tera.render("button_expanded.html", &ctx).map_err(|e| {
                 error!("{:?}", e);
                 ErrorInternalServerError(e)
@@ -292,7 +467,7 @@ pub fn render_dates(dates: Vec<Date>, cache: &ExpansionCache, user_id: &Uuid) ->
     }
     let mut ctx = Context::new();
     ctx.insert("dates", &rendered_dates);
-    ctx.insert("user_id", user_id);
+    ctx.insert("csrf_token", csrf_token.as_str());
     Tera::one_off(
         std::str::from_utf8(&read("./pages/buttons.html")?)?,
         &ctx,
@@ -301,9 +476,9 @@ pub fn render_dates(dates: Vec<Date>, cache: &ExpansionCache, user_id: &Uuid) ->
     .map_err(ErrorInternalServerError)
 }
 
-fn render_description(date: &Date, user_id: &Uuid) -> Result<String> {
+fn render_description(date: &Date, display: &DateDisplay) -> Result<String> {
     let mut ctx = Context::new();
-    let date_str = date.description.render_date();
+    let date_str = date.description.render_date(&display.tz);
     let status_str = date.description.render_status();
     let color = String::from(match date.description.status {
         Status::Suggested => "bg-cyan-50",
@@ -312,8 +487,8 @@ fn render_description(date: &Date, user_id: &Uuid) -> Result<String> {
     });
     ctx.insert("date", &date);
     ctx.insert("status", &status_str);
-    ctx.insert("user_id", user_id);
     ctx.insert("status_color", &color);
+    ctx.insert("can_approve", &display.can_approve);
     if date.description.text.is_empty() {
         ctx.insert("text", "Enter a description!");
     } else {
@@ -327,9 +502,9 @@ fn render_description(date: &Date, user_id: &Uuid) -> Result<String> {
     )
     .map_err(ErrorInternalServerError)
 }
-fn render_editable_description(date: &Date, user_id: &Uuid) -> Result<String> {
+fn render_editable_description(date: &Date, display: &DateDisplay) -> Result<String> {
     let mut ctx = Context::new();
-    let date_str = date.description.render_date();
+    let date_str = date.description.render_date(&display.tz);
     let status_str = date.description.render_status();
     let color = String::from(match date.description.status {
         Status::Suggested => "bg-cyan-50",
@@ -338,7 +513,6 @@ fn render_editable_description(date: &Date, user_id: &Uuid) -> Result<String> {
     });
     ctx.insert("date", &date);
     ctx.insert("status", &status_str);
-    ctx.insert("user_id", user_id);
     ctx.insert("status_color", &color);
     ctx.insert("date_time", &date_str);
     Tera::one_off(
@@ -0,0 +1,224 @@
+//! Deleting your own account.
+//! 1) User confirms with their password, after seeing what happens to their groups.
+//! 2) The account is scheduled for deletion, and a link to cancel is emailed.
+//! 3) Once the grace period is over the account goes, with any group no one is left in.
+use std::fs;
+use std::time::Duration;
+
+use actix_web::error::ErrorInternalServerError;
+use actix_web::web::{self, Data, Form, ServiceConfig};
+use actix_web::{get, post, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use secrecy::Secret;
+use serde::Deserialize;
+use serde_json::json;
+use tera::{Context, Tera};
+use tracing::{error, info};
+
+use crate::auth::csrf::CsrfToken;
+use crate::auth::token::{Token, TokenError, TokenPurpose};
+use crate::auth::user::{AuthorizedUser, UserRepository};
+use crate::domain::repository::AppState;
+use crate::routes::account::{confirm_identity, has_password, REAUTH_MINUTES};
+
+/// How long an account can still be saved after asking for it to be deleted.
+pub const DELETION_GRACE_DAYS: i64 = 7;
+/// How often accounts past their grace period are looked for.
+pub const DELETION_CHECK_MINUTES: u64 = 60;
+
+pub fn delete_account_service(cfg: &mut ServiceConfig) {
+    cfg.service(delete_account_page)
+        .service(delete_account)
+        .service(cancel_deletion)
+        .service(cancel_deletion_by_email);
+}
+
+#[get("/account/delete")]
+async fn delete_account_page(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .body(render_delete_account(&app_state, &user, &csrf_token, None, None).await?))
+}
+
+#[derive(Deserialize)]
+struct DeleteAccountForm {
+    password: Secret<String>,
+}
+#[post("/account/delete")]
+async fn delete_account(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    form: Form<DeleteAccountForm>,
+) -> Result<HttpResponse> {
+    let password = form.into_inner().password;
+    if let Some(error) = confirm_identity(&app_state, &req, &user, password).await? {
+        return Ok(HttpResponse::Unauthorized().body(
+            render_delete_account(&app_state, &user, &csrf_token, None, Some(error)).await?,
+        ));
+    }
+    let deletion_at = Utc::now() + chrono::Duration::days(DELETION_GRACE_DAYS);
+    let token = app_state
+        .repo
+        .create_token(
+            &user.id(),
+            TokenPurpose::AccountDeletionCancel,
+            chrono::Duration::days(DELETION_GRACE_DAYS),
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    // Only schedule once the way to cancel has been sent.
+    app_state
+        .email_client
+        .send_account_deletion_email(user.email(), &token, deletion_at)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    app_state
+        .repo
+        .schedule_user_deletion(&user.id(), deletion_at)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Account deletion scheduled for {}", user.id());
+    Ok(HttpResponse::Ok().body(
+        render_delete_account(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some("Your account is scheduled for deletion, we've emailed you a link to cancel."),
+            None,
+        )
+        .await?,
+    ))
+}
+
+#[post("/account/delete/cancel")]
+async fn cancel_deletion(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    let message = if app_state
+        .repo
+        .cancel_user_deletion(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        info!("Account deletion cancelled for {}", user.id());
+        "Your account is no longer going to be deleted."
+    } else {
+        "Your account wasn't going to be deleted."
+    };
+    Ok(HttpResponse::Ok()
+        .body(render_delete_account(&app_state, &user, &csrf_token, Some(message), None).await?))
+}
+
+/// Cancel from the link in the email, which works without logging in.
+#[get("/account/delete/cancel/{token}")]
+async fn cancel_deletion_by_email(
+    app_state: Data<AppState>,
+    token: web::Path<String>,
+) -> Result<HttpResponse> {
+    let token = Token::from(token.into_inner());
+    let user_id = match app_state
+        .repo
+        .consume_token(&token, TokenPurpose::AccountDeletionCancel)
+        .await
+    {
+        Ok(id) => id,
+        Err(TokenError::UnexpectedError(e)) => return Err(ErrorInternalServerError(e)),
+        Err(e) => return Ok(HttpResponse::Gone().body(render_cancelled(&e.to_string())?)),
+    };
+    app_state
+        .repo
+        .cancel_user_deletion(&user_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Account deletion cancelled for {}", user_id);
+    Ok(HttpResponse::Ok().body(render_cancelled(
+        "Your account is no longer going to be deleted.",
+    )?))
+}
+
+/// Delete accounts as their grace periods end, for as long as the app runs.
+pub async fn remove_scheduled_accounts(repo: impl UserRepository) {
+    let mut interval = tokio::time::interval(Duration::from_secs(DELETION_CHECK_MINUTES * 60));
+    loop {
+        interval.tick().await;
+        match repo.remove_scheduled_users().await {
+            Ok(0) => (),
+            Ok(removed) => info!("Deleted {} scheduled accounts", removed),
+            Err(e) => error!("Failed to delete scheduled accounts: {:?}", e),
+        }
+    }
+}
+
+fn render_cancelled(message: &str) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert("message", message);
+    Tera::one_off(
+        &fs::read_to_string("./pages/email_confirmation.html")?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
+}
+
+/// Render the deletion page, explaining what happens to each of the user's groups.
+///
+/// * `message`: Shown after a successful change.
+/// * `error`: Shown when a change was rejected.
+async fn render_delete_account(
+    app_state: &AppState,
+    user: &AuthorizedUser,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+    error: Option<&str>,
+) -> Result<String> {
+    let mut ctx = Context::new();
+    let mut groups = vec![];
+    for membership in app_state
+        .repo
+        .get_user_groups(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        let group = membership.user_group;
+        let members = app_state
+            .repo
+            .count_group_members(group)
+            .await
+            .map_err(ErrorInternalServerError)?;
+        groups.push(json!({
+            "name": membership.name,
+            "other_members": members - 1,
+            "dates": app_state.repo.get_all(group, &user.id()).await.len(),
+        }));
+    }
+    ctx.insert("groups", &groups);
+    let deletion_at = app_state
+        .repo
+        .get_user_deletion(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    ctx.insert(
+        "deletion_at",
+        &deletion_at.map(|at| at.format("%Y-%m-%d %H:%M UTC").to_string()),
+    );
+    ctx.insert("user_email", user.email());
+    ctx.insert("grace_days", &DELETION_GRACE_DAYS);
+    ctx.insert("has_password", &has_password(app_state, user).await?);
+    ctx.insert("reauth_minutes", &REAUTH_MINUTES);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", &message);
+    ctx.insert("error", &error);
+    Tera::one_off(
+        &fs::read_to_string("./pages/delete_account.html")?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
+}
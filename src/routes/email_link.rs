@@ -0,0 +1,248 @@
+//! Logging in with a link sent by email, instead of a password.
+//! 1) User asks for a link, and their browser is handed a random nonce in a cookie.
+//!    Asking is behind the proof-of-work check, and throttled per address.
+//! 2) The link's token carries the nonce's hash, so only that browser can use it.
+//! 3) Following the link logs in the same way a password would.
+use std::collections::HashMap;
+use std::fs;
+
+use actix_web::cookie::Cookie;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header;
+use actix_web::web::{self, Data, Form, ServiceConfig};
+use actix_web::{get, post, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use tera::{Context, Tera};
+use tracing::info;
+
+use crate::auth::csrf::CsrfToken;
+use crate::auth::email_address::EmailAddress;
+use crate::auth::session::{
+    private_cookie, private_removal_cookie, read_private_cookie, session_cookie, SessionDevice,
+};
+use crate::auth::throttle::ThrottleSubject;
+use crate::auth::token::{Token, TokenError, TokenPurpose};
+use crate::auth::user::UserValidationError;
+use crate::domain::repository::AppState;
+use crate::routes::landing::{requires_two_factor, RESET_REQUIRED};
+use crate::routes::proof_of_work::check_proof_of_work;
+
+pub const EMAIL_LINK_COOKIE: &str = "date_rs_email_link";
+/// How long a sign-in link stays valid.
+pub const EMAIL_LINK_MINUTES: i64 = 15;
+
+pub fn email_link_service(cfg: &mut ServiceConfig) {
+    cfg.service(email_link_page)
+        .service(send_email_link)
+        .service(email_link_login);
+}
+
+fn nonce_removal_cookie() -> Cookie<'static> {
+    private_removal_cookie(EMAIL_LINK_COOKIE, "/login/email_link")
+}
+
+#[get("/login/email_link")]
+async fn email_link_page(app_state: Data<AppState>, csrf_token: CsrfToken) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(render_email_link(&app_state, &csrf_token, None)?))
+}
+
+/// Email a sign-in link, takes the landing page's login form.
+#[post("/login/email_link")]
+async fn send_email_link(
+    app_state: Data<AppState>,
+    csrf_token: CsrfToken,
+    mut form: Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let email = form.remove("email").unwrap_or_default();
+    if let Some(resp) = check_proof_of_work(&app_state, &csrf_token, &mut form, &email).await? {
+        return Ok(resp);
+    }
+    // Counted whether or not the account exists, so the wait gives nothing away either.
+    let email_key = EmailAddress::normalize(&email);
+    let subject = ThrottleSubject::EmailLink(&email_key);
+    let throttle = app_state
+        .repo
+        .get_throttle(&subject)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if let Some(wait) = throttle.retry_after(Utc::now()) {
+        let seconds = wait.num_seconds().max(1);
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, seconds.to_string()))
+            .body(render_email_link(
+                &app_state,
+                &csrf_token,
+                Some(&format!(
+                    "Too many sign-in links were asked for, try again in {} seconds.",
+                    seconds
+                )),
+            )?));
+    }
+    app_state
+        .repo
+        .record_failure(&subject)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let nonce = Token::generate();
+    // Reply the same way whether or not the account exists, so this can't be used to find users.
+    if let Ok(user) = app_state.repo.get_user_by_email(&email_key).await {
+        let token = app_state
+            .repo
+            .create_token_with_payload(
+                &user.id(),
+                TokenPurpose::EmailLogin,
+                chrono::Duration::minutes(EMAIL_LINK_MINUTES),
+                Some(nonce.hash()),
+            )
+            .await
+            .map_err(ErrorInternalServerError)?;
+        app_state
+            .email_client
+            .send_login_link_email(user.email(), &token)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    Ok(HttpResponse::Ok()
+        .cookie(private_cookie(
+            EMAIL_LINK_COOKIE,
+            nonce.expose().to_string(),
+            "/login/email_link",
+            EMAIL_LINK_MINUTES,
+            &app_state.session_key,
+        ))
+        .body(render_email_link(
+            &app_state,
+            &csrf_token,
+            Some(
+                "If that address has an account, a sign-in link is on its way. \
+            Open it in this browser.",
+            ),
+        )?))
+}
+
+#[get("/login/email_link/{token}")]
+async fn email_link_login(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    csrf_token: CsrfToken,
+    token: web::Path<String>,
+) -> Result<HttpResponse> {
+    // Checked before the token is looked at, so a link opened elsewhere, or by a
+    // mail scanner, doesn't use it up.
+    let Some(nonce) = read_private_cookie(&req, EMAIL_LINK_COOKIE, &app_state.session_key) else {
+        return Ok(HttpResponse::Forbidden().body(render_email_link(
+            &app_state,
+            &csrf_token,
+            Some("Open the link in the browser you asked for it from, or request a new one below."),
+        )?));
+    };
+    let token = Token::from(token.into_inner());
+    let (user_id, browser) = match app_state
+        .repo
+        .check_token_with_payload(&token, TokenPurpose::EmailLogin)
+        .await
+    {
+        Ok(found) => found,
+        Err(e) => return token_failure(&app_state, &csrf_token, e),
+    };
+    if browser != Some(Token::from(nonce).hash()) {
+        info!("Sign-in link for {} used from another browser", user_id);
+        app_state
+            .repo
+            .record_login(&user_id, false)
+            .await
+            .map_err(ErrorInternalServerError)?;
+        return Ok(HttpResponse::Forbidden()
+            .cookie(nonce_removal_cookie())
+            .body(render_email_link(
+                &app_state,
+                &csrf_token,
+                Some("This link was requested from another browser, request a new one below."),
+            )?));
+    }
+    // Only used up once it's known to be the right browser, so a stray open leaves it working.
+    if let Err(e) = app_state
+        .repo
+        .consume_token(&token, TokenPurpose::EmailLogin)
+        .await
+    {
+        return token_failure(&app_state, &csrf_token, e);
+    }
+    let user = match app_state.repo.get_login_user(&user_id).await {
+        Ok(user) => user,
+        Err(UserValidationError::Unverified) => {
+            return Ok(HttpResponse::Forbidden()
+                .cookie(nonce_removal_cookie())
+                .body("Check your email for a link to activate your account."))
+        }
+        Err(UserValidationError::PasswordResetRequired) => {
+            return Ok(HttpResponse::Forbidden()
+                .cookie(nonce_removal_cookie())
+                .body(RESET_REQUIRED))
+        }
+        Err(e) => return Err(ErrorInternalServerError(e)),
+    };
+    if requires_two_factor(&app_state, &user.id()).await? {
+        let token = app_state
+            .repo
+            .create_pending_session(&user.id())
+            .await
+            .map_err(ErrorInternalServerError)?;
+        return Ok(HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, "/login/two_factor"))
+            .cookie(nonce_removal_cookie())
+            .cookie(session_cookie(&token, &app_state.session_key))
+            .finish());
+    }
+    app_state
+        .repo
+        .record_login(&user.id(), true)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let token = app_state
+        .repo
+        .create_session(&user.id(), &SessionDevice::from_request(&req))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Logged in {} with a sign-in link", user.id());
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/user"))
+        .cookie(nonce_removal_cookie())
+        .cookie(session_cookie(&token, &app_state.session_key))
+        .finish())
+}
+
+fn token_failure(
+    app_state: &AppState,
+    csrf_token: &CsrfToken,
+    e: TokenError,
+) -> Result<HttpResponse> {
+    let message = match e {
+        TokenError::UnexpectedError(e) => return Err(ErrorInternalServerError(e)),
+        TokenError::Invalid => "This sign-in link isn't valid.",
+        TokenError::Expired => "This sign-in link has expired.",
+        TokenError::Used => "This sign-in link has already been used.",
+    };
+    let page = render_email_link(
+        app_state,
+        csrf_token,
+        Some(&format!("{} Request a new one below.", message)),
+    )?;
+    Ok(match e {
+        TokenError::Invalid => HttpResponse::NotFound().body(page),
+        _ => HttpResponse::Gone().body(page),
+    })
+}
+
+fn render_email_link(
+    app_state: &AppState,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert("message", &message);
+    ctx.insert("proof_of_work", &app_state.proof_of_work.is_some());
+    ctx.insert("csrf_token", csrf_token.as_str());
+    Tera::one_off(&fs::read_to_string("./pages/email_link.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
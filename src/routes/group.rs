@@ -0,0 +1,416 @@
+//! A group's page, where members see each other's roles and the owner runs the group,
+//! and its settings page, where the group's profile, permissions and settings are changed.
+//!
+//! Users can belong to several groups, these routes work on the one they have open.
+//! The checks themselves are made by the repository, these routes only show what it refused.
+use std::collections::HashMap;
+use std::fs;
+
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError};
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Form, Path, ServiceConfig};
+use actix_web::{get, post, HttpResponse, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tera::{Context, Tera};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::auth::csrf::CsrfToken;
+use crate::auth::role::{Permission, PermissionMatrix, Role, RoleError};
+use crate::auth::user::{AuthorizedUser, GroupUser};
+use crate::domain::group::{DateSort, GroupProfileError, GroupSettings};
+use crate::domain::repository::AppState;
+
+pub fn group_service(cfg: &mut ServiceConfig) {
+    cfg.service(group_page)
+        .service(settings_page)
+        .service(update_profile)
+        .service(update_settings)
+        .service(set_permissions)
+        .service(set_role)
+        .service(transfer_ownership)
+        .service(remove_member)
+        .service(delete_group)
+        .service(open_group);
+}
+
+#[get("/group")]
+async fn group_page(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(render_group(&app_state, &user, &csrf_token, None).await?))
+}
+
+#[get("/group/settings")]
+async fn settings_page(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(render_settings(&app_state, &user, &csrf_token, None, None).await?))
+}
+
+#[derive(Deserialize)]
+struct ProfileForm {
+    handle: String,
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    emoji: String,
+    timezone: String,
+}
+#[post("/group/profile")]
+async fn update_profile(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    form: Form<ProfileForm>,
+) -> Result<HttpResponse> {
+    let mut profile = app_state
+        .repo
+        .get_group_profile(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let mut edited = profile.edit(
+        &form.handle,
+        &form.name,
+        &form.description,
+        &form.emoji,
+        &form.timezone,
+    );
+    if edited.is_ok()
+        && app_state
+            .repo
+            .find_group_by_handle(&profile.handle)
+            .await
+            .map_err(ErrorInternalServerError)?
+            .is_some_and(|group| group != user.user_group)
+    {
+        edited = Err(GroupProfileError::HandleTaken);
+    }
+    if let Err(e) = edited {
+        return Ok(HttpResponse::build(StatusCode::BAD_REQUEST).body(
+            render_settings(&app_state, &user, &csrf_token, None, Some(&e.to_string())).await?,
+        ));
+    }
+    app_state
+        .repo
+        .update_group_profile(&user.user_id, &profile)
+        .await?;
+    Ok(HttpResponse::Ok()
+        .body(render_settings(&app_state, &user, &csrf_token, Some("Profile saved."), None).await?))
+}
+
+#[derive(Deserialize)]
+struct SettingsForm {
+    date_sort: String,
+}
+#[post("/group/settings")]
+async fn update_settings(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    form: Form<SettingsForm>,
+) -> Result<HttpResponse> {
+    let settings = GroupSettings {
+        date_sort: DateSort::parse(&form.date_sort).ok_or(ErrorBadRequest("Unknown order."))?,
+    };
+    app_state
+        .repo
+        .set_group_settings(&user.user_id, user.user_group, &settings)
+        .await?;
+    info!("Settings changed for group {}", user.user_group);
+    Ok(HttpResponse::Ok().body(
+        render_settings(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some("Settings saved."),
+            None,
+        )
+        .await?,
+    ))
+}
+
+/// Least role for each permission, keyed by the permission's name.
+#[post("/group/permissions")]
+async fn set_permissions(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    form: Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let mut permissions = PermissionMatrix::default();
+    for permission in Permission::ALL {
+        if let Some(role) = form.get(permission.as_str()) {
+            permissions.set(permission, parse_role(role)?);
+        }
+    }
+    app_state
+        .repo
+        .set_group_permissions(&user.user_id, user.user_group, &permissions)
+        .await?;
+    info!("Permissions changed for group {}", user.user_group);
+    Ok(HttpResponse::Ok().body(
+        render_settings(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some("Permissions saved."),
+            None,
+        )
+        .await?,
+    ))
+}
+
+#[derive(Deserialize)]
+struct RoleForm {
+    role: String,
+}
+#[post("/group/members/{member_id}/role")]
+async fn set_role(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    member_id: Path<Uuid>,
+    form: Form<RoleForm>,
+) -> Result<HttpResponse> {
+    app_state
+        .repo
+        .set_group_role(
+            &user.user_id,
+            user.user_group,
+            &member_id,
+            parse_role(&form.role)?,
+        )
+        .await?;
+    Ok(HttpResponse::Ok()
+        .body(render_group(&app_state, &user, &csrf_token, Some("Role changed.")).await?))
+}
+
+#[post("/group/members/{member_id}/transfer")]
+async fn transfer_ownership(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    member_id: Path<Uuid>,
+) -> Result<HttpResponse> {
+    app_state
+        .repo
+        .transfer_group_ownership(&user.user_id, user.user_group, &member_id)
+        .await?;
+    info!("Group {} handed to {}", user.user_group, member_id);
+    Ok(HttpResponse::Ok().body(
+        render_group(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some("You've handed the group over, you're now an admin."),
+        )
+        .await?,
+    ))
+}
+
+#[post("/group/members/{member_id}/remove")]
+async fn remove_member(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    member_id: Path<Uuid>,
+) -> Result<HttpResponse> {
+    app_state
+        .repo
+        .remove_group_member(&user.user_id, user.user_group, &member_id)
+        .await?;
+    info!(
+        "{} removed {} from group {}",
+        user.user_id, member_id, user.user_group
+    );
+    Ok(HttpResponse::Ok()
+        .body(render_group(&app_state, &user, &csrf_token, Some("Member removed.")).await?))
+}
+
+#[post("/group/delete")]
+async fn delete_group(app_state: Data<AppState>, user: GroupUser) -> Result<HttpResponse> {
+    app_state
+        .repo
+        .delete_group(&user.user_id, user.user_group)
+        .await?;
+    info!("Group {} deleted by {}", user.user_group, user.user_id);
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/user"))
+        .finish())
+}
+
+/// Page shown once a group is opened.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum OpenGroupNext {
+    #[default]
+    Dates,
+    Requests,
+}
+#[derive(Deserialize)]
+struct OpenGroupForm {
+    group: i32,
+    #[serde(default)]
+    next: OpenGroupNext,
+}
+/// Switch to another of the user's groups, then show its dates, or its requests to join.
+#[post("/group/open")]
+async fn open_group(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    form: Form<OpenGroupForm>,
+) -> Result<HttpResponse> {
+    app_state.repo.open_group(&user.id(), form.group).await?;
+    let next = match form.next {
+        OpenGroupNext::Dates => "/dates",
+        OpenGroupNext::Requests => "/group/requests",
+    };
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, next))
+        .finish())
+}
+
+fn parse_role(role: &str) -> Result<Role> {
+    Role::parse(role).ok_or(ErrorBadRequest("Unknown role."))
+}
+
+/// Render the group's page, listing its members.
+///
+/// * `message`: Shown after a successful change.
+async fn render_group(
+    app_state: &AppState,
+    user: &GroupUser,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+) -> Result<String> {
+    let members = app_state
+        .repo
+        .get_group_members(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let role = members
+        .iter()
+        .find(|m| m.user_id == user.user_id)
+        .map(|m| m.role)
+        .ok_or(RoleError::NotMember)?;
+    let matrix = app_state
+        .repo
+        .get_group_permissions(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let join_requests = app_state
+        .repo
+        .list_group_join_requests(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .len();
+    let mut ctx = group_context(app_state, user.user_group).await?;
+    ctx.insert("join_requests", &join_requests);
+    ctx.insert("user_id", &user.user_id);
+    ctx.insert("role", &role);
+    ctx.insert(
+        "can_remove",
+        &matrix.allows(role, Permission::RemoveMembers),
+    );
+    ctx.insert("members", &members);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", &message);
+    Tera::one_off(&fs::read_to_string("./pages/group.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
+
+/// Render the group's settings page, with its profile, permissions and settings.
+///
+/// * `message`: Shown after a successful change.
+/// * `error`: Shown when a change was rejected.
+async fn render_settings(
+    app_state: &AppState,
+    user: &GroupUser,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+    error: Option<&str>,
+) -> Result<String> {
+    let role = app_state
+        .repo
+        .get_group_members(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .find(|m| m.user_id == user.user_id)
+        .map(|m| m.role)
+        .ok_or(RoleError::NotMember)?;
+    let matrix = app_state
+        .repo
+        .get_group_permissions(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let settings = app_state
+        .repo
+        .get_group_settings(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let sorts: Vec<_> = DateSort::ALL
+        .into_iter()
+        .map(|s| json!({"name": s.as_str(), "description": s.description()}))
+        .collect();
+    let timezones: Vec<_> = chrono_tz::TZ_VARIANTS.iter().map(|tz| tz.name()).collect();
+    let permissions: Vec<_> = Permission::ALL
+        .into_iter()
+        .map(|p| {
+            json!({
+                "name": p.as_str(),
+                "description": p.description(),
+                "role": matrix.required(p),
+            })
+        })
+        .collect();
+    let mut ctx = group_context(app_state, user.user_group).await?;
+    ctx.insert("role", &role);
+    ctx.insert(
+        "can_edit_profile",
+        &matrix.allows(role, Permission::EditGroupProfile),
+    );
+    ctx.insert("timezones", &timezones);
+    ctx.insert("permissions", &permissions);
+    ctx.insert("roles", &Role::ALL);
+    ctx.insert("date_sort", &settings.date_sort);
+    ctx.insert("sorts", &sorts);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", &message);
+    ctx.insert("error", &error);
+    Tera::one_off(
+        &fs::read_to_string("./pages/group_settings.html")?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
+}
+
+/// Context both group pages share, with the group's profile.
+async fn group_context(app_state: &AppState, group: i32) -> Result<Context> {
+    let profile = app_state
+        .repo
+        .get_group_profile(group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let mut ctx = Context::new();
+    ctx.insert("group", &profile.id);
+    ctx.insert("handle", &profile.handle);
+    ctx.insert("name", &profile.name);
+    ctx.insert("description", &profile.description);
+    ctx.insert("emoji", &profile.emoji);
+    ctx.insert("timezone", &profile.timezone);
+    ctx.insert(
+        "created_at",
+        &profile.created_at.format("%Y-%m-%d").to_string(),
+    );
+    Ok(ctx)
+}
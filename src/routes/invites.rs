@@ -0,0 +1,279 @@
+//! Inviting people to a group, and joining one from an invite.
+//!
+//! Members create invites from the invites page, as a link with a QR code to share
+//! in person, or emailed to an address. Joining a group always takes an invite.
+use std::fs;
+
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound};
+use actix_web::web::{Data, Form, Path, ServiceConfig};
+use actix_web::{get, post, HttpResponse, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tera::{Context, Tera};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::auth::api_token::{ApiScope, ApiUser};
+use crate::auth::csrf::CsrfToken;
+use crate::auth::invite::{qr_code_svg, Invite, InviteError, INVITE_LENGTH_DAYS};
+use crate::auth::token::Token;
+use crate::auth::user::{AuthorizedUser, GroupUser};
+use crate::domain::repository::AppState;
+use crate::routes::dates_service::date_page_inner;
+
+pub fn invite_service(cfg: &mut ServiceConfig) {
+    cfg.service(invites_page)
+        .service(create_invite)
+        .service(revoke_invite)
+        .service(invite_page)
+        .service(accept_invite);
+}
+
+#[get("/group/invites")]
+async fn invites_page(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .body(render_invites(&app_state, &user, &csrf_token, None, None, None).await?))
+}
+
+#[derive(Deserialize)]
+struct InviteForm {
+    /// Emailed to this address if given, otherwise a link is shown to share.
+    #[serde(default)]
+    email: String,
+}
+#[post("/group/invites")]
+async fn create_invite(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    form: Form<InviteForm>,
+) -> Result<HttpResponse> {
+    let valid_for = chrono::Duration::days(INVITE_LENGTH_DAYS);
+    if form.email.trim().is_empty() {
+        let token = app_state
+            .repo
+            .create_invite(user.user_group, &user.user_id, None, valid_for)
+            .await
+            .map_err(ErrorInternalServerError)?;
+        info!("Invite link created for group {}", user.user_group);
+        let url = invite_url(&app_state, &token);
+        let qr_code = qr_code_svg(&url).map_err(ErrorInternalServerError)?;
+        return Ok(HttpResponse::Ok().body(
+            render_invites(
+                &app_state,
+                &user,
+                &csrf_token,
+                Some((&url, &qr_code)),
+                None,
+                None,
+            )
+            .await?,
+        ));
+    }
+    let email = match app_state.disposable_domains.parse_address(&form.email) {
+        Ok(email) => email,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().body(
+                render_invites(
+                    &app_state,
+                    &user,
+                    &csrf_token,
+                    None,
+                    None,
+                    Some(&e.to_string()),
+                )
+                .await?,
+            ))
+        }
+    };
+    let token = app_state
+        .repo
+        .create_invite(user.user_group, &user.user_id, Some(&email), valid_for)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    app_state
+        .email_client
+        .send_group_invite_email(email.as_str(), &token, &user.email)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Invite emailed for group {}", user.user_group);
+    Ok(HttpResponse::Ok().body(
+        render_invites(
+            &app_state,
+            &user,
+            &csrf_token,
+            None,
+            Some(&format!("We've emailed an invite to {}.", email)),
+            None,
+        )
+        .await?,
+    ))
+}
+
+#[post("/group/invites/{invite_id}/revoke")]
+async fn revoke_invite(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    invite_id: Path<Uuid>,
+) -> Result<HttpResponse> {
+    if !app_state
+        .repo
+        .revoke_invite(user.user_group, &invite_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound("No such invite."));
+    }
+    Ok(HttpResponse::Ok().body(
+        render_invites(
+            &app_state,
+            &user,
+            &csrf_token,
+            None,
+            Some("The invite can no longer be used."),
+            None,
+        )
+        .await?,
+    ))
+}
+
+/// Where an invite link is opened, works without logging in so the invitee can see what it is.
+#[get("/invite/{token}")]
+async fn invite_page(
+    app_state: Data<AppState>,
+    user: Option<AuthorizedUser>,
+    csrf_token: CsrfToken,
+    token: Path<String>,
+) -> Result<HttpResponse> {
+    let token = Token::from(token.into_inner());
+    match app_state.repo.get_invite(&token).await {
+        Ok(invite) => Ok(HttpResponse::Ok().body(render_invite(
+            &token,
+            Some(&invite),
+            user.as_ref(),
+            &csrf_token,
+            None,
+        )?)),
+        Err(e) => invite_failure(&token, user.as_ref(), &csrf_token, e),
+    }
+}
+
+#[post("/invite/{token}")]
+async fn accept_invite(
+    app_state: Data<AppState>,
+    user: ApiUser,
+    csrf_token: CsrfToken,
+    token: Path<String>,
+) -> Result<HttpResponse> {
+    let user = user.require(ApiScope::ManageGroup)?;
+    let token = Token::from(token.into_inner());
+    match app_state.repo.accept_invite(&token, &user.id()).await {
+        Ok(group_user) => {
+            info!("{} joined group {}", user.id(), group_user.user_group);
+            date_page_inner(app_state.into_inner(), &group_user, &csrf_token).await
+        }
+        Err(e) => invite_failure(&token, Some(&user), &csrf_token, e),
+    }
+}
+
+fn invite_failure(
+    token: &Token,
+    user: Option<&AuthorizedUser>,
+    csrf_token: &CsrfToken,
+    e: InviteError,
+) -> Result<HttpResponse> {
+    let mut response = match e {
+        InviteError::UnexpectedError(e) => return Err(ErrorInternalServerError(e)),
+        InviteError::Invalid => HttpResponse::NotFound(),
+        InviteError::WrongAccount => HttpResponse::Forbidden(),
+        InviteError::Expired | InviteError::Used => HttpResponse::Gone(),
+    };
+    let error = match e {
+        InviteError::WrongAccount => e.to_string(),
+        e => format!("{} Ask a member of the group for a new one.", e),
+    };
+    Ok(response.body(render_invite(token, None, user, csrf_token, Some(&error))?))
+}
+
+/// Full link to an invite, for sharing and the QR code.
+///
+/// Built from the configured url rather than the request's host, which the client chooses.
+fn invite_url(app_state: &AppState, token: &Token) -> String {
+    app_state
+        .email_client
+        .app_link(&format!("invite/{}", token.expose()))
+}
+
+/// Render the page where a member manages their group's invites.
+///
+/// * `new_link`: Url and QR code of an invite link that was just created.
+/// * `message`: Shown after a successful change.
+/// * `error`: Shown when a change was rejected.
+async fn render_invites(
+    app_state: &AppState,
+    user: &GroupUser,
+    csrf_token: &CsrfToken,
+    new_link: Option<(&str, &str)>,
+    message: Option<&str>,
+    error: Option<&str>,
+) -> Result<String> {
+    let invites: Vec<_> = app_state
+        .repo
+        .list_invites(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|i| {
+            json!({
+                "id": i.invite_id,
+                "email": i.email,
+                "created_at": i.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                "expires_at": i.expires_at.format("%Y-%m-%d %H:%M").to_string(),
+            })
+        })
+        .collect();
+    let group = app_state
+        .repo
+        .get_group_profile(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let mut ctx = Context::new();
+    ctx.insert("group", &group.name);
+    ctx.insert("invites", &invites);
+    ctx.insert("invite_days", &INVITE_LENGTH_DAYS);
+    ctx.insert("new_link", &new_link.map(|(url, _)| url));
+    ctx.insert("qr_code", &new_link.map(|(_, qr_code)| qr_code));
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", &message);
+    ctx.insert("error", &error);
+    Tera::one_off(&fs::read_to_string("./pages/invites.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
+
+/// Render the page an invite link opens.
+///
+/// * `invite`: The invite, if it can still be used.
+/// * `user`: Who opened it, if they're logged in.
+/// * `error`: Why the invite can't be used.
+fn render_invite(
+    token: &Token,
+    invite: Option<&Invite>,
+    user: Option<&AuthorizedUser>,
+    csrf_token: &CsrfToken,
+    error: Option<&str>,
+) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert("uri", &format!("/invite/{}", token.expose()));
+    ctx.insert("group", &invite.map(|i| &i.group_name));
+    ctx.insert("invited_email", &invite.and_then(|i| i.email.as_ref()));
+    ctx.insert("user_email", &user.map(AuthorizedUser::email));
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("error", &error);
+    Tera::one_off(&fs::read_to_string("./pages/invite.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
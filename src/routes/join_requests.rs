@@ -0,0 +1,336 @@
+//! Asking to join a group by its handle, and answering those requests.
+//!
+//! Users ask from the join page, where they also see how their requests stand. Every
+//! member of the group sees waiting requests, and those whose role allows it are
+//! emailed and can approve or reject them.
+use std::fs;
+
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound};
+use actix_web::web::{Data, Form, Path, Query, ServiceConfig};
+use actix_web::{get, post, HttpResponse, ResponseError, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tera::{Context, Tera};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::auth::api_token::{ApiScope, ApiUser};
+use crate::auth::csrf::CsrfToken;
+use crate::auth::join_request::{JoinRequest, JoinRequestError, JoinRequestStatus};
+use crate::auth::role::{GroupMembership, Permission, RoleError};
+use crate::auth::user::{AuthorizedUser, GroupUser};
+use crate::domain::repository::AppState;
+
+pub fn join_request_service(cfg: &mut ServiceConfig) {
+    cfg.service(join_page)
+        .service(request_to_join)
+        .service(cancel_request)
+        .service(group_requests_page)
+        .service(approve_request)
+        .service(reject_request);
+}
+
+#[get("/join")]
+async fn join_page(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(render_join(&app_state, &user, &csrf_token, None, None).await?))
+}
+
+#[derive(Deserialize)]
+struct JoinForm {
+    handle: String,
+}
+#[post("/join")]
+async fn request_to_join(
+    app_state: Data<AppState>,
+    user: ApiUser,
+    csrf_token: CsrfToken,
+    form: Form<JoinForm>,
+) -> Result<HttpResponse> {
+    let user = user.require(ApiScope::ManageGroup)?;
+    let request = match app_state
+        .repo
+        .find_group_by_handle(&form.handle)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Some(group) => {
+            app_state
+                .repo
+                .create_join_request(&user.id(), group, app_state.join_request_expiry)
+                .await
+        }
+        None => Err(JoinRequestError::UnknownGroup),
+    };
+    let request = match request {
+        Ok(request) => request,
+        Err(JoinRequestError::UnexpectedError(e)) => return Err(ErrorInternalServerError(e)),
+        Err(e) => {
+            return Ok(HttpResponse::build(e.status_code()).body(
+                render_join(&app_state, &user, &csrf_token, None, Some(&e.to_string())).await?,
+            ))
+        }
+    };
+    info!("{} asked to join group {}", user.id(), request.user_group);
+    notify_members(&app_state, &request).await?;
+    Ok(HttpResponse::Ok().body(
+        render_join(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some(&format!(
+                "You've asked to join {}, its members will let you know.",
+                request.group_name
+            )),
+            None,
+        )
+        .await?,
+    ))
+}
+
+/// Email the members who can answer a new request.
+///
+/// A member who can't be emailed still sees the request in the app.
+async fn notify_members(app_state: &AppState, request: &JoinRequest) -> Result<()> {
+    let members = app_state
+        .repo
+        .get_group_members(request.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let permissions = app_state
+        .repo
+        .get_group_permissions(request.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    for member in members
+        .iter()
+        .filter(|m| permissions.allows(m.role, Permission::ApproveMembers))
+    {
+        if let Err(e) = app_state
+            .email_client
+            .send_join_request_email(
+                &member.email,
+                &request.email,
+                request.user_group,
+                &request.group_name,
+            )
+            .await
+        {
+            error!(
+                "Couldn't tell {} about a join request: {:?}",
+                member.user_id, e
+            );
+        }
+    }
+    Ok(())
+}
+
+#[post("/join/{request_id}/cancel")]
+async fn cancel_request(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    request_id: Path<Uuid>,
+) -> Result<HttpResponse> {
+    if !app_state
+        .repo
+        .cancel_join_request(&user.id(), &request_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound("No such request."));
+    }
+    Ok(HttpResponse::Ok().body(
+        render_join(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some("Your request has been withdrawn."),
+            None,
+        )
+        .await?,
+    ))
+}
+
+#[derive(Deserialize)]
+struct GroupQuery {
+    /// Group the link was about, from emails sent while the user may have another open.
+    group: Option<i32>,
+}
+#[get("/group/requests")]
+async fn group_requests_page(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    query: Query<GroupQuery>,
+) -> Result<HttpResponse> {
+    // Opening a group changes what the user sees elsewhere, so it's left to them to confirm.
+    let elsewhere = match query.group {
+        Some(group) if group != user.user_group => Some(
+            app_state
+                .repo
+                .get_user_groups(&user.user_id)
+                .await
+                .map_err(ErrorInternalServerError)?
+                .into_iter()
+                .find(|g| g.user_group == group)
+                .ok_or(RoleError::NotMember)?,
+        ),
+        _ => None,
+    };
+    Ok(HttpResponse::Ok()
+        .body(render_requests(&app_state, &user, &csrf_token, None, elsewhere.as_ref()).await?))
+}
+
+#[post("/group/requests/{request_id}/approve")]
+async fn approve_request(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    request_id: Path<Uuid>,
+) -> Result<HttpResponse> {
+    let request = app_state
+        .repo
+        .decide_join_request(&user.user_id, user.user_group, &request_id, true)
+        .await?;
+    info!(
+        "{} let {} join group {}",
+        user.user_id, request.user_id, request.user_group
+    );
+    Ok(HttpResponse::Ok().body(
+        render_requests(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some(&format!("{} has joined the group.", request.email)),
+            None,
+        )
+        .await?,
+    ))
+}
+
+#[post("/group/requests/{request_id}/reject")]
+async fn reject_request(
+    app_state: Data<AppState>,
+    user: GroupUser,
+    csrf_token: CsrfToken,
+    request_id: Path<Uuid>,
+) -> Result<HttpResponse> {
+    let request = app_state
+        .repo
+        .decide_join_request(&user.user_id, user.user_group, &request_id, false)
+        .await?;
+    Ok(HttpResponse::Ok().body(
+        render_requests(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some(&format!("{}'s request was rejected.", request.email)),
+            None,
+        )
+        .await?,
+    ))
+}
+
+/// Render the page where a user asks to join groups, and sees how their requests stand.
+///
+/// * `message`: Shown after a successful change.
+/// * `error`: Shown when a request was refused.
+async fn render_join(
+    app_state: &AppState,
+    user: &AuthorizedUser,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+    error: Option<&str>,
+) -> Result<String> {
+    let requests: Vec<_> = app_state
+        .repo
+        .list_user_join_requests(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|r| {
+            json!({
+                "id": r.request_id,
+                "group": r.group_name,
+                "pending": r.status == JoinRequestStatus::Pending,
+                "status": r.status,
+                "created_at": r.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                "expires_at": r.expires_at.format("%Y-%m-%d %H:%M").to_string(),
+            })
+        })
+        .collect();
+    let mut ctx = Context::new();
+    ctx.insert("requests", &requests);
+    ctx.insert("expiry_days", &app_state.join_request_expiry.num_days());
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", &message);
+    ctx.insert("error", &error);
+    Tera::one_off(&fs::read_to_string("./pages/join.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
+
+/// Render the requests waiting to join the user's group.
+///
+/// * `message`: Shown after a request was answered.
+/// * `elsewhere`: Another of the user's groups the link was about, offered to be opened.
+async fn render_requests(
+    app_state: &AppState,
+    user: &GroupUser,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+    elsewhere: Option<&GroupMembership>,
+) -> Result<String> {
+    let role = app_state
+        .repo
+        .get_group_members(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .find(|m| m.user_id == user.user_id)
+        .map(|m| m.role)
+        .ok_or(RoleError::NotMember)?;
+    let can_answer = app_state
+        .repo
+        .get_group_permissions(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .allows(role, Permission::ApproveMembers);
+    let profile = app_state
+        .repo
+        .get_group_profile(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let requests: Vec<_> = app_state
+        .repo
+        .list_group_join_requests(user.user_group)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|r| {
+            json!({
+                "id": r.request_id,
+                "email": r.email,
+                "created_at": r.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                "expires_at": r.expires_at.format("%Y-%m-%d %H:%M").to_string(),
+            })
+        })
+        .collect();
+    let mut ctx = Context::new();
+    ctx.insert("name", &profile.name);
+    ctx.insert("handle", &profile.handle);
+    ctx.insert("requests", &requests);
+    ctx.insert("can_answer", &can_answer);
+    ctx.insert("elsewhere", &elsewhere);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", &message);
+    Tera::one_off(
+        &fs::read_to_string("./pages/join_requests.html")?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
+}
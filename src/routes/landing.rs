@@ -1,51 +1,192 @@
 use std::collections::HashMap;
 use std::fs;
+use std::net::IpAddr;
 
-use crate::auth::user::{AuthorizedUser, UnAuthorizedUser, UnRegisteredUser, UserValidationError};
+use crate::auth::api_token::{ApiScope, ApiUser};
+use crate::auth::audit::AuditContext;
+use crate::auth::csrf::{Csrf, CsrfToken};
+use crate::auth::default_hash_params;
+use crate::auth::email_address::{DisposableDomains, EmailAddress};
+use crate::auth::join_request::JOIN_REQUEST_LENGTH_DAYS;
+use crate::auth::oidc::{OidcClient, OidcConfig};
+use crate::auth::passkey::RelyingParty;
+use crate::auth::password_policy::PasswordPolicy;
+use crate::auth::proof_of_work::ProofOfWork;
+use crate::auth::session::{
+    client_ip, removal_cookie, session_cookie, session_token, SessionDevice,
+};
+use crate::auth::throttle::ThrottleSubject;
+use crate::auth::token::{Token, TokenError, TokenPurpose};
+use crate::auth::totp::{hash_recovery_code, verify_code};
+use crate::auth::user::{
+    AuthorizedUser, HalfAuthorizedUser, NoGroupUser, UnAuthorizedUser, UnRegisteredUser,
+    UserValidationError,
+};
 use crate::backend::postgres::PgRepo;
 use crate::domain::repository::AppState;
-use crate::email::{authenticate_by_email, EmailClient};
+use crate::email::{authenticate_by_email, resend_verification, send_verification, EmailClient};
+use crate::routes::account::account_service;
+use crate::routes::api_tokens::api_token_service;
 use crate::routes::dates_service::{date_page_inner, dates_service};
-use actix_web::error::{
-    ErrorForbidden, ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized,
-};
+use crate::routes::delete_account::delete_account_service;
+use crate::routes::email_link::email_link_service;
+use crate::routes::group::group_service;
+use crate::routes::invites::invite_service;
+use crate::routes::join_requests::join_request_service;
+use crate::routes::oidc::oidc_service;
+use crate::routes::passkeys::passkey_service;
+use crate::routes::password_reset::password_reset_service;
+use crate::routes::proof_of_work::{check_proof_of_work, proof_of_work_service};
+use crate::routes::sessions::{alert_new_device, sessions_service};
+use crate::routes::two_factor::two_factor_service;
+use actix_web::cookie::Key;
+use actix_web::error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized};
+use actix_web::http::header;
 use actix_web::middleware::Logger;
-use actix_web::{delete, Result};
 use actix_web::{
     get, post,
     web::{self, Data, Path, ServiceConfig},
     HttpResponse, Responder,
 };
+use actix_web::{HttpRequest, Result};
 
+use argon2::Params;
+use chrono::Utc;
+use secrecy::Secret;
 use sqlx::PgPool;
 use tera::{Context, Tera};
+use tracing::{error, info};
 use uuid::Uuid;
 
+/// Why a login was refused while the account's password has to be reset.
+pub const RESET_REQUIRED: &str =
+    "This account's password has to be reset, use Forgot password to choose a new one.";
+/// How long the unlock link sent with a lockout stays valid.
+pub const UNLOCK_LENGTH_HOURS: i64 = 24;
+
 pub struct MainService {
     pool: PgPool,
     email_client: EmailClient,
+    session_key: Key,
+    hash_params: Params,
+    oidc: Option<OidcConfig>,
+    relying_party: Option<RelyingParty>,
+    password_policy: PasswordPolicy,
+    disposable_domains: DisposableDomains,
+    proof_of_work: Option<ProofOfWork>,
+    join_request_expiry: chrono::Duration,
+    trusted_proxies: Vec<IpAddr>,
 }
 impl MainService {
+    /// Sessions are signed with a random key, unless one is set with `session_key`.
     pub fn new(pool: PgPool, email_client: EmailClient) -> Self {
-        Self { pool, email_client }
+        Self {
+            pool,
+            email_client,
+            session_key: Key::generate(),
+            hash_params: default_hash_params(),
+            oidc: None,
+            relying_party: None,
+            password_policy: PasswordPolicy::default(),
+            disposable_domains: DisposableDomains::default(),
+            proof_of_work: None,
+            join_request_expiry: chrono::Duration::days(JOIN_REQUEST_LENGTH_DAYS),
+            trusted_proxies: Vec::new(),
+        }
+    }
+    /// Sign session cookies with a fixed key, so sessions survive restarts.
+    pub fn session_key(mut self, key: Key) -> Self {
+        self.session_key = key;
+        self
+    }
+    /// Hash passwords with a different Argon2 cost, existing users are rehashed as they log in.
+    pub fn hash_params(mut self, params: Params) -> Self {
+        self.hash_params = params;
+        self
+    }
+    /// Offer logging in through an OpenID Connect provider, alongside passwords.
+    pub fn oidc(mut self, config: OidcConfig) -> Self {
+        self.oidc = Some(config);
+        self
+    }
+    /// Let users register passkeys, and log in with them instead of a password.
+    pub fn relying_party(mut self, relying_party: RelyingParty) -> Self {
+        self.relying_party = Some(relying_party);
+        self
+    }
+    /// Check new passwords against a different policy, the default has no breached list.
+    pub fn password_policy(mut self, policy: PasswordPolicy) -> Self {
+        self.password_policy = policy;
+        self
+    }
+    /// Refuse registrations from throwaway email providers, none are refused by default.
+    pub fn disposable_domains(mut self, domains: DisposableDomains) -> Self {
+        self.disposable_domains = domains;
+        self
+    }
+    /// Make registering and logging in solve a proof-of-work challenge first.
+    pub fn proof_of_work(mut self, proof_of_work: ProofOfWork) -> Self {
+        self.proof_of_work = Some(proof_of_work);
+        self
+    }
+    /// Give groups a different time to answer requests to join.
+    pub fn join_request_expiry(mut self, expiry: chrono::Duration) -> Self {
+        self.join_request_expiry = expiry;
+        self
+    }
+    /// Believe the forwarding headers of requests coming from these proxies,
+    /// otherwise a client's address is the one it connected from.
+    pub fn trusted_proxies(mut self, proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = proxies;
+        self
     }
     pub fn service_configuration(self, cfg: &mut ServiceConfig) {
-        cfg.app_data(AppState::new_in_web_data(
-            Box::new(PgRepo { pool: self.pool }),
+        let mut app_state = AppState::new(
+            Box::new(PgRepo {
+                hash_params: self.hash_params,
+                ..PgRepo::new(self.pool)
+            }),
             self.email_client,
-        ))
-        .service(
+            self.session_key,
+        );
+        app_state.oidc = self.oidc.map(OidcClient::new);
+        app_state.relying_party = self.relying_party;
+        app_state.password_policy = self.password_policy;
+        app_state.disposable_domains = self.disposable_domains;
+        app_state.proof_of_work = self.proof_of_work;
+        app_state.join_request_expiry = self.join_request_expiry;
+        app_state.trusted_proxies = self.trusted_proxies;
+        cfg.app_data(Data::new(app_state)).service(
             web::scope("")
+                .wrap(Csrf)
+                .wrap(AuditContext)
                 .wrap(Logger::default())
                 .service(web::redirect("", "/"))
                 .service(landing)
                 .service(login)
-                .service(join_group_by_email)
+                .service(two_factor_login_page)
+                .service(two_factor_login)
+                .service(logout)
+                .service(unlock_account)
+                .service(user_page)
                 .service(register)
                 .service(create_group)
                 .service(search_verification)
                 .service(authenticate_by_email)
-                .service(remove_user)
+                .service(resend_verification)
+                .configure(password_reset_service)
+                .configure(account_service)
+                .configure(delete_account_service)
+                .configure(oidc_service)
+                .configure(email_link_service)
+                .configure(two_factor_service)
+                .configure(passkey_service)
+                .configure(api_token_service)
+                .configure(sessions_service)
+                .configure(proof_of_work_service)
+                .configure(invite_service)
+                .configure(join_request_service)
+                .configure(group_service)
                 .service(web::scope("/dates").configure(dates_service)),
         );
     }
@@ -57,170 +198,470 @@ pub fn unauthorized() -> Result<HttpResponse> {
 }
 
 #[get("/")]
-pub async fn landing() -> Result<impl Responder> {
-    Ok(HttpResponse::Ok()
-        .body(fs::read_to_string("./pages/landing.html").map_err(ErrorInternalServerError)?))
+pub async fn landing(app_state: Data<AppState>, csrf_token: CsrfToken) -> Result<impl Responder> {
+    Ok(HttpResponse::Ok().body(render_landing(
+        &app_state,
+        &csrf_token,
+        "",
+        &HashMap::new(),
+    )?))
+}
+
+/// Render the landing page's login and registration form.
+///
+/// * `email`: Filled back in, after a rejected registration.
+/// * `errors`: Messages shown under the fields they are keyed by.
+pub fn render_landing(
+    app_state: &AppState,
+    csrf_token: &CsrfToken,
+    email: &str,
+    errors: &HashMap<&str, String>,
+) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert(
+        "sso_name",
+        &app_state.oidc.as_ref().map(|oidc| &oidc.config.name),
+    );
+    ctx.insert("passkeys", &app_state.relying_party.is_some());
+    ctx.insert("proof_of_work", &app_state.proof_of_work.is_some());
+    ctx.insert("email", email);
+    ctx.insert("errors", errors);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    Tera::one_off(
+        &fs::read_to_string("./pages/landing.html").map_err(ErrorInternalServerError)?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
 }
 #[post("/login")]
 async fn login(
     app_state: Data<AppState>,
+    req: HttpRequest,
+    csrf_token: CsrfToken,
     mut form: web::Form<HashMap<String, String>>,
 ) -> Result<HttpResponse> {
-    let u_user = UnAuthorizedUser::new(
-        form.remove("email").unwrap(),
-        form.remove("password").unwrap(),
-    );
-    let user = app_state
+    let email = form.remove("email").unwrap_or_default();
+    if let Some(resp) = check_proof_of_work(&app_state, &csrf_token, &mut form, &email).await? {
+        return Ok(resp);
+    }
+    let u_user = UnAuthorizedUser::new(email, form.remove("password").unwrap_or_default());
+    let ip = client_ip(&req);
+    // Keyed like the stored address, so every spelling of it counts against the same account.
+    let email_key = EmailAddress::normalize(&u_user.email);
+    let subjects = throttle_subjects(&email_key, &ip);
+    if let Some(resp) = check_throttle(&app_state, &subjects).await? {
+        return Ok(resp);
+    }
+    let user = match app_state.repo.validate_user(&u_user).await {
+        Ok(user) => user,
+        Err(e) => {
+            if !matches!(
+                e,
+                UserValidationError::UnexpectedError(_)
+                    | UserValidationError::PasswordResetRequired
+            ) {
+                record_login_failure(&app_state, &subjects).await?;
+            }
+            // One answer for every credential failure, so it can't be used to find accounts.
+            return Err(match e {
+                UserValidationError::PasswordError(_)
+                | UserValidationError::RegistrationError(_)
+                | UserValidationError::Unverified => {
+                    info!("Login failed: {}", e);
+                    ErrorUnauthorized("Wrong email or password.")
+                }
+                UserValidationError::PasswordResetRequired => ErrorForbidden(RESET_REQUIRED),
+                _ => ErrorInternalServerError("Server Error."),
+            });
+        }
+    };
+    // Failures keep counting until the second factor is given too.
+    if requires_two_factor(&app_state, &user.id()).await? {
+        let token = app_state
+            .repo
+            .create_pending_session(&user.id())
+            .await
+            .map_err(ErrorInternalServerError)?;
+        return Ok(HttpResponse::Ok()
+            .cookie(session_cookie(&token, &app_state.session_key))
+            .body(render_two_factor_login(
+                &CsrfToken::for_session(&token, &app_state.session_key),
+                None,
+            )?));
+    }
+    app_state
         .repo
-        .validate_user(&u_user)
+        .clear_throttle(&ThrottleSubject::Email(&email_key))
         .await
-        .map_err(|e| match e {
-            UserValidationError::PasswordError(_) => ErrorUnauthorized("User Password failed."),
-            UserValidationError::RegistrationError(e) => ErrorNotFound(e),
-            _ => ErrorInternalServerError("Server Error."),
-        })?;
-
-    Ok(HttpResponse::Ok().body(render_user_page(user)?))
+        .map_err(ErrorInternalServerError)?;
+    // Only once every factor is given, so a stolen password alone can't mark the device known.
+    let device = SessionDevice::from_request(&req);
+    alert_new_device(&app_state, &user, &device).await?;
+    let token = app_state
+        .repo
+        .create_session(&user.id(), &device)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .cookie(session_cookie(&token, &app_state.session_key))
+        .body(
+            render_user_page(
+                &app_state,
+                user,
+                &CsrfToken::for_session(&token, &app_state.session_key),
+            )
+            .await?,
+        ))
 }
-fn render_user_page(user: AuthorizedUser) -> Result<String> {
-    let mut ctx = Context::new();
-    if let Some(group) = user.group() {
-        ctx.insert("group", &group);
-        ctx.insert("user_uri", &format!("/dates/{:?}", &user.id()));
+fn throttle_subjects<'a>(email: &'a str, ip: &'a Option<String>) -> Vec<ThrottleSubject<'a>> {
+    let mut subjects = vec![ThrottleSubject::Email(email)];
+    if let Some(ip) = ip {
+        subjects.push(ThrottleSubject::Ip(ip));
     }
-    ctx.insert("user_id", &user.id());
-    ctx.insert("user_email", &user.email());
-    ctx.insert("method", "post");
-    ctx.insert("by_email_method", "post");
-    ctx.insert(
-        "by_email_uri",
-        &format!("{:?}/join_group_by_email", &user.id()),
-    );
-    ctx.insert("uri", &format!("{:?}/create_group", &user.id()));
-    Ok(Tera::one_off(&fs::read_to_string("./pages/user.html")?, &ctx, false).unwrap())
-    // .map_err(ErrorInternalServerError)
+    subjects
+}
+/// Whether a user has to give a second factor before getting a full session.
+pub(crate) async fn requires_two_factor(app_state: &AppState, user_id: &Uuid) -> Result<bool> {
+    Ok(app_state
+        .repo
+        .get_totp(user_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .is_some_and(|t| t.enabled))
+}
+#[get("/login/two_factor")]
+async fn two_factor_login_page(
+    _user: HalfAuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(render_two_factor_login(&csrf_token, None)?))
 }
-#[post("{user_id}/join_group_by_email")]
-async fn join_group_by_email(
+/// Finish logging in with a code from the user's authenticator, or a recovery code.
+#[post("/login/two_factor")]
+async fn two_factor_login(
     app_state: Data<AppState>,
-    user_id: Path<Uuid>,
+    req: HttpRequest,
+    user: HalfAuthorizedUser,
+    csrf_token: CsrfToken,
     mut form: web::Form<HashMap<String, String>>,
 ) -> Result<HttpResponse> {
-    let email = form.remove("email").unwrap();
-    let group = app_state
+    let code = form.remove("code").unwrap_or_default();
+    let ip = client_ip(&req);
+    let subjects = throttle_subjects(&user.email, &ip);
+    if let Some(resp) = check_throttle(&app_state, &subjects).await? {
+        return Ok(resp);
+    }
+    let Some(totp) = app_state
         .repo
-        .get_group_by_email(&email)
+        .get_totp(&user.user_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .filter(|t| t.enabled)
+    else {
+        return unauthorized();
+    };
+    let accepted = match verify_code(&totp.secret, &code, Utc::now().timestamp(), totp.last_step) {
+        Some(step) => app_state.repo.record_totp_step(&user.user_id, step).await,
+        None => {
+            app_state
+                .repo
+                .use_recovery_code(&user.user_id, &hash_recovery_code(&code))
+                .await
+        }
+    }
+    .map_err(ErrorInternalServerError)?;
+    app_state
+        .repo
+        .record_login(&user.user_id, accepted)
         .await
         .map_err(ErrorInternalServerError)?;
+    if !accepted {
+        record_login_failure(&app_state, &subjects).await?;
+        return Ok(HttpResponse::Unauthorized().body(render_two_factor_login(
+            &csrf_token,
+            Some("That code isn't right."),
+        )?));
+    }
+    app_state
+        .repo
+        .clear_throttle(&ThrottleSubject::Email(&user.email))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    // Swap the pending session for a new full one.
+    if let Some(pending) = session_token(&req, &app_state.session_key) {
+        app_state
+            .repo
+            .remove_session(&pending)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
     let user = app_state
         .repo
-        .get_user(&user_id)
+        .get_user(&user.user_id)
         .await
         .map_err(ErrorInternalServerError)?;
-    let group_user = match user {
-        AuthorizedUser::NoGroupUser(g) => app_state
+    let device = SessionDevice::from_request(&req);
+    alert_new_device(&app_state, &user, &device).await?;
+    let token = app_state
+        .repo
+        .create_session(&user.id(), &device)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .cookie(session_cookie(&token, &app_state.session_key))
+        .body(
+            render_user_page(
+                &app_state,
+                user,
+                &CsrfToken::for_session(&token, &app_state.session_key),
+            )
+            .await?,
+        ))
+}
+fn render_two_factor_login(csrf_token: &CsrfToken, error: Option<&str>) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert("error", &error);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    Tera::one_off(
+        &fs::read_to_string("./pages/two_factor_login.html")?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
+}
+/// Refuse a login that has failed too often, before any password hashing is done.
+async fn check_throttle(
+    app_state: &AppState,
+    subjects: &[ThrottleSubject<'_>],
+) -> Result<Option<HttpResponse>> {
+    let now = Utc::now();
+    let mut wait = None;
+    let mut locked = false;
+    for subject in subjects {
+        let state = app_state
             .repo
-            .add_user_to_group(g, group)
+            .get_throttle(subject)
             .await
-            .map_err(ErrorInternalServerError)?,
-        AuthorizedUser::GroupUser(g) => {
-            app_state
-                .repo
-                .remove_user_from_group(&g.user_id)
-                .await
-                .map_err(ErrorInternalServerError)?;
-            match app_state
-                .repo
-                .get_user(&user_id)
-                .await
-                .map_err(ErrorInternalServerError)?
-            {
-                AuthorizedUser::NoGroupUser(g) => app_state
-                    .repo
-                    .add_user_to_group(g, group)
-                    .await
-                    .map_err(ErrorInternalServerError)?,
-
-                AuthorizedUser::GroupUser(_) => {
-                    return Err(ErrorInternalServerError("Unexpected Error."));
-                }
-            }
-        }
+            .map_err(ErrorInternalServerError)?;
+        locked |= matches!(subject, ThrottleSubject::Email(_)) && state.is_locked(now);
+        wait = wait.max(state.retry_after(now));
+    }
+    let Some(wait) = wait else {
+        return Ok(None);
+    };
+    let message = if locked {
+        "Too many failed logins, this account is locked for a while. \
+            Check your email for a link to unlock it."
+            .to_string()
+    } else {
+        format!(
+            "Too many failed logins, try again in {} seconds.",
+            wait.num_seconds().max(1)
+        )
     };
-    date_page_inner(app_state.into_inner(), group_user.user_id).await
+    Ok(Some(
+        HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, wait.num_seconds().max(1).to_string()))
+            .body(message),
+    ))
 }
-
-#[post("/register")]
-async fn register(
-    app_state: Data<AppState>,
-    mut form: web::Form<HashMap<String, String>>,
-) -> Result<HttpResponse> {
-    let u_user = UnRegisteredUser::new(
-        form.remove("email").unwrap(),
-        form.remove("password").unwrap(),
-    );
-    app_state
+/// Count a failed login, and email the account owner if it has just been locked.
+async fn record_login_failure(
+    app_state: &AppState,
+    subjects: &[ThrottleSubject<'_>],
+) -> Result<()> {
+    for subject in subjects {
+        let state = app_state
+            .repo
+            .record_failure(subject)
+            .await
+            .map_err(ErrorInternalServerError)?;
+        let ThrottleSubject::Email(email) = subject else {
+            continue;
+        };
+        if !state.just_locked() {
+            continue;
+        }
+        info!("Locking account {} after failed logins", email);
+        let Ok(user) = app_state.repo.get_user_by_email(email).await else {
+            continue;
+        };
+        let token = app_state
+            .repo
+            .create_token(
+                &user.id(),
+                TokenPurpose::AccountUnlock,
+                chrono::Duration::hours(UNLOCK_LENGTH_HOURS),
+            )
+            .await
+            .map_err(ErrorInternalServerError)?;
+        if let Err(e) = app_state
+            .email_client
+            .send_account_locked_email(email, &token)
+            .await
+        {
+            error!("Failed to send lockout email: {}", e);
+        }
+    }
+    Ok(())
+}
+#[get("/unlock/{token}")]
+async fn unlock_account(app_state: Data<AppState>, token: Path<String>) -> Result<HttpResponse> {
+    let token = Token::from(token.into_inner());
+    let user_id = match app_state
         .repo
-        .register_user(u_user.clone())
+        .consume_token(&token, TokenPurpose::AccountUnlock)
+        .await
+    {
+        Ok(id) => id,
+        Err(TokenError::UnexpectedError(e)) => return Err(ErrorInternalServerError(e)),
+        Err(e) => return Ok(HttpResponse::Gone().body(e.to_string())),
+    };
+    let user = app_state
+        .repo
+        .get_user(&user_id)
         .await
         .map_err(ErrorInternalServerError)?;
     app_state
-        .email_client
-        .send_auth_email(&u_user.email)
+        .repo
+        .clear_throttle(&ThrottleSubject::Email(user.email()))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().body("Your account is unlocked, you can log in again."))
+}
+#[post("/logout")]
+async fn logout(app_state: Data<AppState>, req: HttpRequest) -> Result<HttpResponse> {
+    if let Some(token) = session_token(&req, &app_state.session_key) {
+        app_state
+            .repo
+            .remove_session(&token)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/"))
+        .cookie(removal_cookie())
+        .finish())
+}
+#[get("/user")]
+async fn user_page(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(render_user_page(&app_state, user, &csrf_token).await?))
+}
+/// Render the user's page, listing their groups to switch between.
+async fn render_user_page(
+    app_state: &AppState,
+    user: AuthorizedUser,
+    csrf_token: &CsrfToken,
+) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert("csrf_token", csrf_token.as_str());
+    if let Some(group) = user.group() {
+        ctx.insert("group", &group);
+        ctx.insert("user_uri", "/dates");
+    }
+    let groups = app_state
+        .repo
+        .get_user_groups(&user.id())
         .await
         .map_err(ErrorInternalServerError)?;
+    ctx.insert("groups", &groups);
+    ctx.insert("user_email", &user.email());
+    ctx.insert("method", "post");
+    ctx.insert("uri", "/create_group");
+    Tera::one_off(&fs::read_to_string("./pages/user.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
+#[post("/register")]
+async fn register(
+    app_state: Data<AppState>,
+    csrf_token: CsrfToken,
+    mut form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let email = form.remove("email").unwrap_or_default();
+    if let Some(resp) = check_proof_of_work(&app_state, &csrf_token, &mut form, &email).await? {
+        return Ok(resp);
+    }
+    let password = Secret::new(form.remove("password").unwrap_or_default());
+    // Every field is checked, so all their errors show at once.
+    let mut errors = HashMap::new();
+    let parsed = match app_state.disposable_domains.parse_address(&email) {
+        Ok(email) => Some(email),
+        Err(e) => {
+            errors.insert("email", e.to_string());
+            None
+        }
+    };
+    if let Err(e) = app_state.password_policy.check(&password) {
+        errors.insert("password", e.to_string());
+    }
+    let (Some(email), true) = (parsed, errors.is_empty()) else {
+        return Ok(HttpResponse::BadRequest().body(render_landing(
+            &app_state,
+            &csrf_token,
+            &email,
+            &errors,
+        )?));
+    };
+    let u_user = UnRegisteredUser { email, password };
+    let user_id = match app_state.repo.register_user(u_user.clone()).await {
+        Ok(id) => id,
+        Err(UserValidationError::EmailTaken) => {
+            let errors = HashMap::from([(
+                "email",
+                "An account already uses that email, try logging in instead.".to_string(),
+            )]);
+            return Ok(HttpResponse::Conflict().body(render_landing(
+                &app_state,
+                &csrf_token,
+                u_user.email.as_str(),
+                &errors,
+            )?));
+        }
+        Err(e) => return Err(ErrorInternalServerError(e)),
+    };
+    send_verification(&app_state, &user_id, u_user.email.as_str()).await?;
     Ok(HttpResponse::Ok().body("Check your email for a link to activate your account."))
 }
 
 #[post("/authorize/{user_id}")]
-async fn authorize(app_state: Data<AppState>, user_id: Path<Uuid>) -> Result<HttpResponse> {
+async fn authorize(
+    app_state: Data<AppState>,
+    user_id: Path<Uuid>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
     let user = app_state
         .repo
         .activate_user(&user_id)
         .await
         .map_err(ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().body(render_user_page(AuthorizedUser::NoGroupUser(user))?))
+    Ok(HttpResponse::Ok()
+        .body(render_user_page(&app_state, AuthorizedUser::NoGroupUser(user), &csrf_token).await?))
 }
-#[post("/{user_id}/create_group")]
-async fn create_group(app_state: Data<AppState>, user_id: Path<Uuid>) -> Result<HttpResponse> {
+#[post("/create_group")]
+async fn create_group(
+    app_state: Data<AppState>,
+    user: ApiUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    let user = user.require(ApiScope::ManageGroup)?;
+    let user = NoGroupUser::new(user.id(), user.email());
     let group = app_state
         .repo
         .create_group()
         .await
         .map_err(ErrorInternalServerError)?;
-    let AuthorizedUser::NoGroupUser(user) = app_state
-        .repo
-        .get_user(&user_id)
-        .await
-        .map_err(ErrorInternalServerError)?
-    else {
-        return Err(ErrorForbidden("Cant Change Group."));
-    };
     let group_user = app_state
         .repo
         .add_user_to_group(user, group)
         .await
         .map_err(ErrorInternalServerError)?;
-    date_page_inner(app_state.into_inner(), group_user.user_id).await
-}
-#[delete("/{user_email}")]
-async fn remove_user(app_state: Data<AppState>, user_email: Path<String>) -> Result<HttpResponse> {
-    let user_id = match app_state.repo.get_user_by_email(&user_email).await {
-        Ok(id) => id.id(),
-        Err(_) => app_state
-            .repo
-            .get_unauthorized_user_id(&user_email)
-            .await
-            .ok_or(ErrorInternalServerError("Unexpected failure."))?,
-    };
-    app_state
-        .repo
-        .remove_user(&user_id)
-        .await
-        .map_err(ErrorInternalServerError)?;
-    Ok(HttpResponse::Ok().finish())
+    date_page_inner(app_state.into_inner(), &group_user, &csrf_token).await
 }
-
 #[get("googleb0081feae6701197.html")]
 async fn search_verification() -> Result<impl Responder> {
     Ok(HttpResponse::Ok().body(
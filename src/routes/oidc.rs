@@ -0,0 +1,268 @@
+//! Logging in through an OpenID Connect provider.
+//!
+//! The routes 404 unless a provider is configured.
+use actix_web::cookie::{Cookie, Key};
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
+use actix_web::http::header;
+use actix_web::web::{Data, Query, ServiceConfig};
+use actix_web::{get, post, HttpRequest, HttpResponse, Result};
+use serde::Deserialize;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::auth::email_address::EmailAddress;
+use crate::auth::oidc::{IdTokenClaims, OidcClient, OidcError, OidcFlow};
+use crate::auth::session::{
+    private_cookie, private_removal_cookie, read_private_cookie, session_cookie, SessionDevice,
+};
+use crate::auth::user::{AuthorizedUser, UserValidationError};
+use crate::domain::repository::AppState;
+use crate::email::send_verification;
+use crate::routes::landing::{requires_two_factor, RESET_REQUIRED};
+
+pub const OIDC_COOKIE: &str = "date_rs_oidc";
+/// How long a user has to finish logging in at the provider.
+pub const OIDC_FLOW_MINUTES: i64 = 10;
+
+pub fn oidc_service(cfg: &mut ServiceConfig) {
+    cfg.service(oidc_login)
+        .service(oidc_connect)
+        .service(oidc_callback);
+}
+
+fn oidc_client(app_state: &AppState) -> Result<&OidcClient> {
+    app_state
+        .oidc
+        .as_ref()
+        .ok_or(ErrorNotFound("Single sign-on isn't enabled."))
+}
+
+/// Encrypted cookie holding a login attempt's state, nonce and PKCE verifier.
+fn flow_cookie(flow: &OidcFlow, key: &Key) -> Result<Cookie<'static>> {
+    Ok(private_cookie(
+        OIDC_COOKIE,
+        serde_json::to_string(flow).map_err(ErrorInternalServerError)?,
+        "/oidc",
+        OIDC_FLOW_MINUTES,
+        key,
+    ))
+}
+
+fn flow_removal_cookie() -> Cookie<'static> {
+    private_removal_cookie(OIDC_COOKIE, "/oidc")
+}
+
+fn read_flow(req: &HttpRequest, key: &Key) -> Option<OidcFlow> {
+    serde_json::from_str(&read_private_cookie(req, OIDC_COOKIE, key)?).ok()
+}
+
+#[get("/oidc/login")]
+async fn oidc_login(app_state: Data<AppState>) -> Result<HttpResponse> {
+    start_flow(&app_state, OidcFlow::new()).await
+}
+
+/// Connect the provider to the logged in user's account, from the account page.
+#[post("/oidc/connect")]
+async fn oidc_connect(app_state: Data<AppState>, user: AuthorizedUser) -> Result<HttpResponse> {
+    start_flow(&app_state, OidcFlow::linking(user.id())).await
+}
+
+/// Send the browser to the provider, keeping the flow's secrets for its return.
+async fn start_flow(app_state: &AppState, flow: OidcFlow) -> Result<HttpResponse> {
+    let client = oidc_client(app_state)?;
+    let metadata = client.discover().await.map_err(|e| {
+        error!("OIDC discovery failed: {}", e);
+        ErrorInternalServerError("Couldn't reach the login provider.")
+    })?;
+    let url = client
+        .authorization_url(&metadata, &flow)
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, url.to_string()))
+        .cookie(flow_cookie(&flow, &app_state.session_key)?)
+        .finish())
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+#[get("/oidc/callback")]
+async fn oidc_callback(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    session_user: Option<AuthorizedUser>,
+    query: Query<CallbackQuery>,
+) -> Result<HttpResponse> {
+    let client = oidc_client(&app_state)?;
+    if let Some(e) = &query.error {
+        info!("Provider refused login: {}", e);
+        return Ok(HttpResponse::Unauthorized()
+            .cookie(flow_removal_cookie())
+            .body("Logging in with the provider was cancelled."));
+    }
+    // The state ties the callback to a login this browser started.
+    let flow = read_flow(&req, &app_state.session_key)
+        .filter(|flow| query.state.as_deref() == Some(flow.state.as_str()))
+        .ok_or(ErrorBadRequest("Login attempt not recognised, try again."))?;
+    let code = query
+        .code
+        .as_deref()
+        .ok_or(ErrorBadRequest("Missing code."))?;
+    let metadata = client.discover().await.map_err(|e| {
+        error!("OIDC discovery failed: {}", e);
+        ErrorInternalServerError("Couldn't reach the login provider.")
+    })?;
+    let claims = match client.exchange_code(&metadata, code, &flow).await {
+        Ok(claims) => claims,
+        Err(e @ OidcError::InvalidToken(_)) => {
+            info!("{}", e);
+            return Ok(HttpResponse::Unauthorized()
+                .cookie(flow_removal_cookie())
+                .body("The login provider's response was invalid, try again."));
+        }
+        Err(e) => return Err(ErrorInternalServerError(e)),
+    };
+    if let Some(linking) = flow.linking {
+        return connect_identity(&app_state, linking, session_user, &claims).await;
+    }
+    let user_id = match identity_user(&app_state, &claims).await? {
+        Ok(user_id) => user_id,
+        Err(resp) => return Ok(resp),
+    };
+    let user = match app_state.repo.get_login_user(&user_id).await {
+        Ok(user) => user,
+        Err(UserValidationError::Unverified) => {
+            return Ok(HttpResponse::Forbidden()
+                .cookie(flow_removal_cookie())
+                .body("Check your email for a link to activate your account."))
+        }
+        Err(UserValidationError::PasswordResetRequired) => {
+            return Ok(HttpResponse::Forbidden()
+                .cookie(flow_removal_cookie())
+                .body(RESET_REQUIRED))
+        }
+        Err(e) => return Err(ErrorInternalServerError(e)),
+    };
+    if requires_two_factor(&app_state, &user.id()).await? {
+        let token = app_state
+            .repo
+            .create_pending_session(&user.id())
+            .await
+            .map_err(ErrorInternalServerError)?;
+        return Ok(HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, "/login/two_factor"))
+            .cookie(flow_removal_cookie())
+            .cookie(session_cookie(&token, &app_state.session_key))
+            .finish());
+    }
+    app_state
+        .repo
+        .record_login(&user.id(), true)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let token = app_state
+        .repo
+        .create_session(&user.id(), &SessionDevice::from_request(&req))
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/user"))
+        .cookie(flow_removal_cookie())
+        .cookie(session_cookie(&token, &app_state.session_key))
+        .finish())
+}
+
+/// Link the identity to the user who started connecting it, if they're still logged in.
+async fn connect_identity(
+    app_state: &AppState,
+    linking: Uuid,
+    session_user: Option<AuthorizedUser>,
+    claims: &IdTokenClaims,
+) -> Result<HttpResponse> {
+    if session_user.map(|u| u.id()) != Some(linking) {
+        return Ok(HttpResponse::Unauthorized()
+            .cookie(flow_removal_cookie())
+            .body("Log in again, then connect the provider from your account page."));
+    }
+    match app_state
+        .repo
+        .get_identity_user(&claims.iss, &claims.sub)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Some(user_id) if user_id == linking => {}
+        Some(_) => {
+            return Ok(HttpResponse::Conflict()
+                .cookie(flow_removal_cookie())
+                .body("That login is already connected to another account."))
+        }
+        None => {
+            app_state
+                .repo
+                .link_identity(&linking, &claims.iss, &claims.sub)
+                .await
+                .map_err(ErrorInternalServerError)?;
+            info!("Linked {} identity to {}", claims.iss, linking);
+        }
+    }
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/account"))
+        .cookie(flow_removal_cookie())
+        .finish())
+}
+
+/// Find or create the user an id token belongs to.
+///
+/// Returns a response instead when the identity can't be used to log in.
+async fn identity_user(
+    app_state: &AppState,
+    claims: &IdTokenClaims,
+) -> Result<std::result::Result<Uuid, HttpResponse>> {
+    if let Some(user_id) = app_state
+        .repo
+        .get_identity_user(&claims.iss, &claims.sub)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Ok(Ok(user_id));
+    }
+    let Some(email) = claims
+        .email
+        .as_deref()
+        .and_then(|e| EmailAddress::parse(e).ok())
+    else {
+        return Ok(Err(HttpResponse::BadRequest()
+            .body("The login provider didn't share a usable email address.")));
+    };
+    let taken = || {
+        HttpResponse::Conflict().body(
+            "An account already uses that email, log in to it and connect the provider \
+            from your account page.",
+        )
+    };
+    // Logging in here would skip the account's password, two-factor and passkeys.
+    if app_state
+        .repo
+        .get_user_by_email(email.as_str())
+        .await
+        .is_ok()
+    {
+        return Ok(Err(taken()));
+    }
+    let user_id = match app_state
+        .repo
+        .register_identity_user(&email, &claims.iss, &claims.sub, claims.email_verified)
+        .await
+    {
+        Ok(id) => id,
+        Err(UserValidationError::EmailTaken) => return Ok(Err(taken())),
+        Err(e) => return Err(ErrorInternalServerError(e)),
+    };
+    if !claims.email_verified {
+        send_verification(app_state, &user_id, email.as_str()).await?;
+    }
+    Ok(Ok(user_id))
+}
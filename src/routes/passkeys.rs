@@ -0,0 +1,419 @@
+//! Registering passkeys from the account page, and logging in with them.
+//!
+//! The ceremonies are driven from the browser's javascript, so they speak json.
+//! The routes 404 unless a relying party is configured.
+use std::fs;
+
+use actix_web::cookie::{Cookie, Key};
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
+use actix_web::web::{Data, Form, Json, Path, ServiceConfig};
+use actix_web::{get, post, HttpRequest, HttpResponse, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use tera::{Context, Tera};
+use tracing::info;
+
+use crate::auth::csrf::CsrfToken;
+use crate::auth::passkey::{
+    verify_assertion, verify_registration, PasskeyChallenge, PasskeyError, RelyingParty,
+    PASSKEY_CHALLENGE_MINUTES, SUPPORTED_ALGORITHMS,
+};
+use crate::auth::session::{
+    private_cookie, private_removal_cookie, read_private_cookie, session_cookie, SessionDevice,
+};
+use crate::auth::user::{AuthorizedUser, UserValidationError};
+use crate::domain::repository::AppState;
+use crate::routes::landing::RESET_REQUIRED;
+use crate::routes::sessions::alert_new_device;
+
+pub const PASSKEY_COOKIE: &str = "date_rs_passkey";
+/// Longest name a passkey can be given.
+pub const PASSKEY_NAME_LENGTH: usize = 64;
+
+pub fn passkey_service(cfg: &mut ServiceConfig) {
+    cfg.service(passkeys_script)
+        .service(passkeys_page)
+        .service(start_registration)
+        .service(finish_registration)
+        .service(rename_passkey)
+        .service(revoke_passkey)
+        .service(start_login)
+        .service(finish_login);
+}
+
+fn relying_party(app_state: &AppState) -> Result<&RelyingParty> {
+    app_state
+        .relying_party
+        .as_ref()
+        .ok_or(ErrorNotFound("Passkeys aren't enabled."))
+}
+
+/// Encrypted cookie holding the challenge a ceremony was started with.
+fn challenge_cookie(challenge: &PasskeyChallenge, key: &Key) -> Result<Cookie<'static>> {
+    Ok(private_cookie(
+        PASSKEY_COOKIE,
+        serde_json::to_string(challenge).map_err(ErrorInternalServerError)?,
+        "/",
+        PASSKEY_CHALLENGE_MINUTES,
+        key,
+    ))
+}
+
+fn challenge_removal_cookie() -> Cookie<'static> {
+    private_removal_cookie(PASSKEY_COOKIE, "/")
+}
+
+fn read_challenge(req: &HttpRequest, key: &Key) -> Option<PasskeyChallenge> {
+    serde_json::from_str(&read_private_cookie(req, PASSKEY_COOKIE, key)?).ok()
+}
+
+/// Spend the challenge a ceremony was started with, false if it expired or was already answered.
+async fn spend_challenge(app_state: &AppState, challenge: &PasskeyChallenge) -> Result<bool> {
+    if challenge.expires_at <= Utc::now() {
+        return Ok(false);
+    }
+    app_state
+        .repo
+        .spend_challenge(&challenge.challenge, challenge.expires_at)
+        .await
+        .map_err(ErrorInternalServerError)
+}
+
+fn decode(field: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(field)
+        .map_err(|_| ErrorBadRequest("Fields must be base64url encoded."))
+}
+
+/// Turn a name the user typed into one that can be stored.
+fn clean_name(name: Option<&str>) -> String {
+    name.map(str::trim)
+        .filter(|n| !n.is_empty())
+        .unwrap_or("Passkey")
+        .chars()
+        .take(PASSKEY_NAME_LENGTH)
+        .collect()
+}
+
+/// Browser side of the ceremonies, shared by the login and account pages.
+#[get("/passkeys.js")]
+async fn passkeys_script() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/javascript")
+        .body(fs::read_to_string("./pages/passkeys.js")?))
+}
+
+#[post("/account/passkeys/register/start")]
+async fn start_registration(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+) -> Result<HttpResponse> {
+    let rp = relying_party(&app_state)?;
+    let existing = app_state
+        .repo
+        .list_passkeys(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let challenge = PasskeyChallenge::new(Some(user.id()));
+    let options = json!({
+        "challenge": challenge.challenge,
+        "rp": { "id": rp.id, "name": rp.name },
+        "user": {
+            "id": URL_SAFE_NO_PAD.encode(user.id().as_bytes()),
+            "name": user.email(),
+            "displayName": user.email(),
+        },
+        "pubKeyCredParams": SUPPORTED_ALGORITHMS
+            .iter()
+            .map(|alg| json!({ "type": "public-key", "alg": alg }))
+            .collect::<Vec<_>>(),
+        "timeout": PASSKEY_CHALLENGE_MINUTES * 60 * 1000,
+        "attestation": "none",
+        "authenticatorSelection": { "residentKey": "required", "userVerification": "required" },
+        // Stops the same authenticator being registered twice.
+        "excludeCredentials": existing
+            .iter()
+            .map(|p| json!({ "type": "public-key", "id": p.credential_id }))
+            .collect::<Vec<_>>(),
+    });
+    Ok(HttpResponse::Ok()
+        .cookie(challenge_cookie(&challenge, &app_state.session_key)?)
+        .json(options))
+}
+
+/// A new credential, with its binary fields base64url encoded.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegistrationResponse {
+    id: String,
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    attestation_object: String,
+    name: Option<String>,
+}
+#[post("/account/passkeys/register/finish")]
+async fn finish_registration(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    req: HttpRequest,
+    body: Json<RegistrationResponse>,
+) -> Result<HttpResponse> {
+    let rp = relying_party(&app_state)?;
+    let challenge = read_challenge(&req, &app_state.session_key)
+        .filter(|c| c.registering == Some(user.id()))
+        .ok_or(ErrorBadRequest("Registration not recognised, try again."))?;
+    if !spend_challenge(&app_state, &challenge).await? {
+        return Ok(HttpResponse::BadRequest()
+            .cookie(challenge_removal_cookie())
+            .json(json!({ "error": "Registration not recognised, try again." })));
+    }
+    let credential = match verify_registration(
+        rp,
+        &challenge,
+        &decode(&body.client_data_json)?,
+        &decode(&body.attestation_object)?,
+    ) {
+        Ok(credential) if credential.credential_id == body.id => credential,
+        Ok(_) => return Err(ErrorBadRequest("Credential id doesn't match.")),
+        Err(e @ PasskeyError::Invalid(_)) => {
+            info!("{}", e);
+            return Ok(HttpResponse::BadRequest()
+                .cookie(challenge_removal_cookie())
+                .json(json!({ "error": "The passkey couldn't be registered, try again." })));
+        }
+        Err(e) => return Err(ErrorInternalServerError(e)),
+    };
+    let name = clean_name(body.name.as_deref());
+    app_state
+        .repo
+        .add_passkey(&user.id(), &credential, &name)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Passkey registered for {}", user.id());
+    Ok(HttpResponse::Created()
+        .cookie(challenge_removal_cookie())
+        .json(json!({ "id": credential.credential_id, "name": name })))
+}
+
+#[get("/account/passkeys")]
+async fn passkeys_page(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    relying_party(&app_state)?;
+    Ok(HttpResponse::Ok().body(render_passkeys(&app_state, &user, &csrf_token, None).await?))
+}
+
+#[derive(Deserialize)]
+struct RenameForm {
+    name: String,
+}
+#[post("/account/passkeys/{credential_id}/rename")]
+async fn rename_passkey(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    path: Path<String>,
+    form: Form<RenameForm>,
+) -> Result<HttpResponse> {
+    relying_party(&app_state)?;
+    if !app_state
+        .repo
+        .rename_passkey(&user.id(), &path, &clean_name(Some(&form.name)))
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound("No such passkey."));
+    }
+    Ok(HttpResponse::Ok()
+        .body(render_passkeys(&app_state, &user, &csrf_token, Some("Passkey renamed.")).await?))
+}
+
+#[post("/account/passkeys/{credential_id}/revoke")]
+async fn revoke_passkey(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    path: Path<String>,
+) -> Result<HttpResponse> {
+    relying_party(&app_state)?;
+    if !app_state
+        .repo
+        .revoke_passkey(&user.id(), &path)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound("No such passkey."));
+    }
+    info!("Passkey revoked for {}", user.id());
+    Ok(HttpResponse::Ok().body(
+        render_passkeys(
+            &app_state,
+            &user,
+            &csrf_token,
+            Some("Passkey removed, it can't be used to log in any more."),
+        )
+        .await?,
+    ))
+}
+
+async fn render_passkeys(
+    app_state: &AppState,
+    user: &AuthorizedUser,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+) -> Result<String> {
+    let passkeys: Vec<_> = app_state
+        .repo
+        .list_passkeys(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|p| {
+            json!({
+                "id": p.credential_id,
+                "name": p.name,
+                "created_at": p.created_at.format("%Y-%m-%d").to_string(),
+                "last_used_at": p.last_used_at.map(|t| t.format("%Y-%m-%d").to_string()),
+            })
+        })
+        .collect();
+    let mut ctx = Context::new();
+    ctx.insert("passkeys", &passkeys);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", &message);
+    Tera::one_off(&fs::read_to_string("./pages/passkeys.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
+
+#[post("/passkeys/login/start")]
+async fn start_login(app_state: Data<AppState>) -> Result<HttpResponse> {
+    let rp = relying_party(&app_state)?;
+    let challenge = PasskeyChallenge::new(None);
+    // No credentials are listed, so the authenticator offers whichever it has for the site.
+    let options = json!({
+        "challenge": challenge.challenge,
+        "rpId": rp.id,
+        "timeout": PASSKEY_CHALLENGE_MINUTES * 60 * 1000,
+        "userVerification": "required",
+    });
+    Ok(HttpResponse::Ok()
+        .cookie(challenge_cookie(&challenge, &app_state.session_key)?)
+        .json(options))
+}
+
+/// A signed challenge, with its binary fields base64url encoded.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AssertionResponse {
+    id: String,
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+    user_handle: Option<String>,
+}
+#[post("/passkeys/login/finish")]
+async fn finish_login(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    body: Json<AssertionResponse>,
+) -> Result<HttpResponse> {
+    let rp = relying_party(&app_state)?;
+    let challenge = read_challenge(&req, &app_state.session_key)
+        .filter(|c| c.registering.is_none())
+        .ok_or(ErrorBadRequest("Login attempt not recognised, try again."))?;
+    let rejected = || {
+        HttpResponse::Unauthorized()
+            .cookie(challenge_removal_cookie())
+            .json(json!({ "error": "That passkey wasn't accepted." }))
+    };
+    if !spend_challenge(&app_state, &challenge).await? {
+        return Ok(rejected());
+    }
+    let Some(passkey) = app_state
+        .repo
+        .get_passkey(&body.id)
+        .await
+        .map_err(ErrorInternalServerError)?
+    else {
+        return Ok(rejected());
+    };
+    let failed = || async {
+        app_state
+            .repo
+            .record_login(&passkey.user_id, false)
+            .await
+            .map_err(ErrorInternalServerError)
+    };
+    if let Some(handle) = &body.user_handle {
+        if decode(handle)? != passkey.user_id.as_bytes() {
+            failed().await?;
+            return Ok(rejected());
+        }
+    }
+    let sign_count = match verify_assertion(
+        rp,
+        &challenge,
+        &passkey,
+        &decode(&body.client_data_json)?,
+        &decode(&body.authenticator_data)?,
+        &decode(&body.signature)?,
+    ) {
+        Ok(sign_count) => sign_count,
+        Err(e @ PasskeyError::Invalid(_)) => {
+            info!("{}", e);
+            failed().await?;
+            return Ok(rejected());
+        }
+        Err(e) => return Err(ErrorInternalServerError(e)),
+    };
+    if !app_state
+        .repo
+        .use_passkey(&passkey.credential_id, sign_count)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        info!(
+            "Passkey {} reused a signature counter",
+            passkey.credential_id
+        );
+        failed().await?;
+        return Ok(rejected());
+    }
+    let user = match app_state.repo.get_login_user(&passkey.user_id).await {
+        Ok(user) => user,
+        Err(UserValidationError::Unverified) => {
+            return Ok(HttpResponse::Forbidden()
+                .cookie(challenge_removal_cookie())
+                .json(json!({ "error": "Check your email for a link to activate your account." })))
+        }
+        Err(UserValidationError::PasswordResetRequired) => {
+            return Ok(HttpResponse::Forbidden()
+                .cookie(challenge_removal_cookie())
+                .json(json!({ "error": RESET_REQUIRED })))
+        }
+        Err(e) => return Err(ErrorInternalServerError(e)),
+    };
+    // A verified passkey is both factors at once, so two-factor isn't asked for.
+    app_state
+        .repo
+        .record_login(&user.id(), true)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let device = SessionDevice::from_request(&req);
+    alert_new_device(&app_state, &user, &device).await?;
+    let token = app_state
+        .repo
+        .create_session(&user.id(), &device)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .cookie(challenge_removal_cookie())
+        .cookie(session_cookie(&token, &app_state.session_key))
+        .json(json!({ "redirect": "/user" })))
+}
@@ -0,0 +1,251 @@
+//! Forgotten password flow.
+//! 1) User asks for a reset link by email.
+//!    Asking is behind the proof-of-work check, and throttled per address and per ip.
+//! 2) User follows the link and picks a new password.
+//! 3) Every session and outstanding token the user had is revoked.
+use std::collections::HashMap;
+use std::fs;
+
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header;
+use actix_web::web::{self, Data, Form, ServiceConfig};
+use actix_web::{get, post, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use secrecy::{ExposeSecret, Secret};
+use serde::Deserialize;
+use tera::{Context, Tera};
+use tracing::info;
+
+use crate::auth::csrf::CsrfToken;
+use crate::auth::email_address::EmailAddress;
+use crate::auth::session::client_ip;
+use crate::auth::throttle::ThrottleSubject;
+use crate::auth::token::{Token, TokenError, TokenPurpose};
+use crate::domain::repository::AppState;
+use crate::routes::proof_of_work::check_proof_of_work;
+
+/// How long a password reset link stays valid.
+pub const RESET_LENGTH_MINUTES: i64 = 60;
+
+pub fn password_reset_service(cfg: &mut ServiceConfig) {
+    cfg.service(forgot_password_page)
+        .service(forgot_password)
+        .service(reset_password_page)
+        .service(reset_password);
+}
+
+#[get("/forgot_password")]
+async fn forgot_password_page(
+    app_state: Data<AppState>,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(render_forgot_password(&app_state, &csrf_token, None)?))
+}
+
+/// Email a reset link, behind the proof-of-work check and throttled per address and per ip.
+#[post("/forgot_password")]
+async fn forgot_password(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    csrf_token: CsrfToken,
+    mut form: Form<HashMap<String, String>>,
+) -> Result<HttpResponse> {
+    let email = form.remove("email").unwrap_or_default();
+    if let Some(resp) = check_proof_of_work(&app_state, &csrf_token, &mut form, &email).await? {
+        return Ok(resp);
+    }
+    // Counted whether or not the account exists, so the wait gives nothing away either.
+    let email_key = EmailAddress::normalize(&email);
+    let ip = client_ip(&req);
+    let mut subjects = vec![ThrottleSubject::PasswordReset(&email_key)];
+    if let Some(ip) = &ip {
+        subjects.push(ThrottleSubject::PasswordResetIp(ip));
+    }
+    let now = Utc::now();
+    let mut wait = None;
+    for subject in &subjects {
+        let throttle = app_state
+            .repo
+            .get_throttle(subject)
+            .await
+            .map_err(ErrorInternalServerError)?;
+        wait = wait.max(throttle.retry_after(now));
+    }
+    if let Some(wait) = wait {
+        let seconds = wait.num_seconds().max(1);
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, seconds.to_string()))
+            .body(render_forgot_password(
+                &app_state,
+                &csrf_token,
+                Some(&format!(
+                    "Too many reset links were asked for, try again in {} seconds.",
+                    seconds
+                )),
+            )?));
+    }
+    for subject in &subjects {
+        app_state
+            .repo
+            .record_failure(subject)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    // Reply the same way whether or not the account exists, so this can't be used to find users.
+    if let Ok(user) = app_state.repo.get_user_by_email(&email_key).await {
+        let token = app_state
+            .repo
+            .create_token(
+                &user.id(),
+                TokenPurpose::PasswordReset,
+                chrono::Duration::minutes(RESET_LENGTH_MINUTES),
+            )
+            .await
+            .map_err(ErrorInternalServerError)?;
+        app_state
+            .email_client
+            .send_password_reset_email(user.email(), &token)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    Ok(HttpResponse::Ok().body(render_forgot_password(
+        &app_state,
+        &csrf_token,
+        Some("If that address has an account, a reset link is on its way."),
+    )?))
+}
+
+#[get("/reset_password/{token}")]
+async fn reset_password_page(
+    app_state: Data<AppState>,
+    csrf_token: CsrfToken,
+    token: web::Path<String>,
+) -> Result<HttpResponse> {
+    let token = Token::from(token.into_inner());
+    match app_state
+        .repo
+        .check_token(&token, TokenPurpose::PasswordReset)
+        .await
+    {
+        Ok(_) => {
+            Ok(HttpResponse::Ok().body(render_reset_password(&token, &csrf_token, None, false)?))
+        }
+        Err(e) => token_failure(&app_state, &csrf_token, e),
+    }
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordForm {
+    password: Secret<String>,
+    confirm_password: Secret<String>,
+}
+#[post("/reset_password/{token}")]
+async fn reset_password(
+    app_state: Data<AppState>,
+    csrf_token: CsrfToken,
+    token: web::Path<String>,
+    form: Form<ResetPasswordForm>,
+) -> Result<HttpResponse> {
+    let token = Token::from(token.into_inner());
+    let form = form.into_inner();
+    if form.password.expose_secret() != form.confirm_password.expose_secret() {
+        return Ok(HttpResponse::BadRequest().body(render_reset_password(
+            &token,
+            &csrf_token,
+            Some("Passwords don't match."),
+            false,
+        )?));
+    }
+    if let Err(e) = app_state.password_policy.check(&form.password) {
+        return Ok(HttpResponse::BadRequest().body(render_reset_password(
+            &token,
+            &csrf_token,
+            Some(&e.to_string()),
+            false,
+        )?));
+    }
+    let user_id = match app_state
+        .repo
+        .consume_token(&token, TokenPurpose::PasswordReset)
+        .await
+    {
+        Ok(id) => id,
+        Err(e) => return token_failure(&app_state, &csrf_token, e),
+    };
+    app_state
+        .repo
+        .change_user_password(&user_id, form.password)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    // Whoever had access before the reset shouldn't keep it.
+    app_state
+        .repo
+        .remove_user_sessions(&user_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    app_state
+        .repo
+        .revoke_user_tokens(&user_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Password reset for {}", user_id);
+    Ok(HttpResponse::Ok().body(render_reset_password(&token, &csrf_token, None, true)?))
+}
+
+fn token_failure(
+    app_state: &AppState,
+    csrf_token: &CsrfToken,
+    e: TokenError,
+) -> Result<HttpResponse> {
+    let message = match e {
+        TokenError::UnexpectedError(e) => return Err(ErrorInternalServerError(e)),
+        TokenError::Invalid => "This reset link isn't valid.",
+        TokenError::Expired => "This reset link has expired.",
+        TokenError::Used => "This reset link has already been used.",
+    };
+    let page = render_forgot_password(
+        app_state,
+        csrf_token,
+        Some(&format!("{} Request a new one below.", message)),
+    )?;
+    Ok(match e {
+        TokenError::Invalid => HttpResponse::NotFound().body(page),
+        _ => HttpResponse::Gone().body(page),
+    })
+}
+
+fn render_forgot_password(
+    app_state: &AppState,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert("proof_of_work", &app_state.proof_of_work.is_some());
+    ctx.insert("message", &message);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    Tera::one_off(
+        &fs::read_to_string("./pages/forgot_password.html")?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
+}
+
+fn render_reset_password(
+    token: &Token,
+    csrf_token: &CsrfToken,
+    error: Option<&str>,
+    done: bool,
+) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("uri", &format!("/reset_password/{}", token.expose()));
+    ctx.insert("error", &error);
+    ctx.insert("done", &done);
+    Tera::one_off(
+        &fs::read_to_string("./pages/reset_password.html")?,
+        &ctx,
+        true,
+    )
+    .map_err(ErrorInternalServerError)
+}
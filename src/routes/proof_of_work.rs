@@ -0,0 +1,125 @@
+//! Handing out proof-of-work challenges, and checking them on registration and login.
+//!
+//! The landing page's `pow.js` fetches a challenge, solves it and adds the solution to the form.
+//! The routes 404, and nothing is checked, unless proof of work is configured.
+use std::collections::HashMap;
+use std::fs;
+
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound};
+use actix_web::http::header;
+use actix_web::web::{Data, ServiceConfig};
+use actix_web::{get, HttpResponse, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+use tracing::info;
+
+use crate::auth::csrf::CsrfToken;
+use crate::auth::proof_of_work::{
+    is_solution, Challenge, ProofOfWork, ProofOfWorkError, CHALLENGE_FIELD, LOAD_WINDOW_MINUTES,
+    SOLUTION_FIELD,
+};
+use crate::domain::repository::AppState;
+use crate::routes::landing::render_landing;
+
+pub fn proof_of_work_service(cfg: &mut ServiceConfig) {
+    cfg.service(proof_of_work_script).service(issue_challenge);
+}
+
+/// Browser side of the challenge, loaded by the landing page.
+#[get("/pow.js")]
+async fn proof_of_work_script() -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/javascript")
+        .body(fs::read_to_string("./pages/pow.js")?))
+}
+
+/// A fresh challenge, as hard as the current load calls for.
+#[get("/challenge")]
+async fn issue_challenge(app_state: Data<AppState>) -> Result<HttpResponse> {
+    let Some(proof_of_work) = &app_state.proof_of_work else {
+        return Err(ErrorNotFound("Proof of work isn't enabled."));
+    };
+    let now = Utc::now();
+    let difficulty = current_difficulty(app_state.get_ref(), proof_of_work, now)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let challenge = Challenge::new(difficulty, now);
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .json(json!({
+            "challenge": challenge.sign(&app_state.session_key),
+            "difficulty": challenge.difficulty,
+        })))
+}
+
+/// Difficulty the load in the last window calls for.
+async fn current_difficulty(
+    app_state: &AppState,
+    proof_of_work: &ProofOfWork,
+    now: DateTime<Utc>,
+) -> anyhow::Result<u8> {
+    let recent = app_state
+        .repo
+        .count_spent_challenges(now - Duration::minutes(LOAD_WINDOW_MINUTES))
+        .await?;
+    Ok(proof_of_work.difficulty(recent))
+}
+
+/// Check and spend the challenge a form was sent with.
+///
+/// Renders the landing page with an error if it wasn't solved.
+///
+/// * `email`: Filled back in on the landing page.
+pub(crate) async fn check_proof_of_work(
+    app_state: &AppState,
+    csrf_token: &CsrfToken,
+    form: &mut HashMap<String, String>,
+    email: &str,
+) -> Result<Option<HttpResponse>> {
+    if app_state.proof_of_work.is_none() {
+        return Ok(None);
+    }
+    let signed = form.remove(CHALLENGE_FIELD).unwrap_or_default();
+    let solution = form.remove(SOLUTION_FIELD).unwrap_or_default();
+    let e = match spend_solution(app_state, &signed, &solution).await {
+        Ok(()) => return Ok(None),
+        Err(ProofOfWorkError::UnexpectedError(e)) => return Err(ErrorInternalServerError(e)),
+        Err(e) => e,
+    };
+    info!("Rejected a form without a proof of work: {}", e);
+    let errors = HashMap::from([("pow", e.to_string())]);
+    Ok(Some(HttpResponse::Forbidden().body(render_landing(
+        app_state, csrf_token, email, &errors,
+    )?)))
+}
+
+async fn spend_solution(
+    app_state: &AppState,
+    signed: &str,
+    solution: &str,
+) -> Result<(), ProofOfWorkError> {
+    if signed.is_empty() || solution.is_empty() {
+        return Err(ProofOfWorkError::Missing);
+    }
+    let Some(proof_of_work) = &app_state.proof_of_work else {
+        return Ok(());
+    };
+    let now = Utc::now();
+    let challenge = Challenge::verify(signed, &app_state.session_key, now)?;
+    if !is_solution(signed, solution, challenge.difficulty) {
+        return Err(ProofOfWorkError::Unsolved);
+    }
+    // Challenges fetched while the site was quiet can't be stockpiled for a burst.
+    let required = current_difficulty(app_state, proof_of_work, now).await?;
+    if !is_solution(signed, solution, required) {
+        return Err(ProofOfWorkError::TooEasy);
+    }
+    if !app_state
+        .repo
+        .spend_challenge(&challenge.nonce, challenge.expires_at())
+        .await?
+    {
+        return Err(ProofOfWorkError::Spent);
+    }
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! Listing the devices a user is logged in on, and signing them out.
+//!
+//! Signing in from a device the user hasn't used before emails them, with a link
+//! that locks the account down if it wasn't them.
+use std::fs;
+
+use actix_web::error::{ErrorInternalServerError, ErrorNotFound};
+use actix_web::http::header;
+use actix_web::web::{Data, Path, ServiceConfig};
+use actix_web::{get, post, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use serde_json::json;
+use tera::{Context, Tera};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::auth::csrf::CsrfToken;
+use crate::auth::session::{removal_cookie, session_token, DeviceHistory, SessionDevice};
+use crate::auth::token::{Token, TokenError, TokenPurpose};
+use crate::auth::user::AuthorizedUser;
+use crate::domain::repository::AppState;
+use crate::routes::password_reset::RESET_LENGTH_MINUTES;
+
+/// How long the "this wasn't me" link in a new device alert stays valid.
+pub const NOT_ME_LENGTH_DAYS: i64 = 7;
+
+pub fn sessions_service(cfg: &mut ServiceConfig) {
+    cfg.service(sessions_page)
+        .service(revoke_session)
+        .service(revoke_all_sessions)
+        .service(not_me_page)
+        .service(deny_sign_in);
+}
+
+#[get("/account/sessions")]
+async fn sessions_page(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().body(render_sessions(&app_state, &req, &user, &csrf_token, None).await?))
+}
+
+/// Sign out a single device, signing out this one sends the user back to the landing page.
+#[post("/account/sessions/{session_id}/revoke")]
+async fn revoke_session(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    session_id: Path<Uuid>,
+) -> Result<HttpResponse> {
+    // Looked up before it goes, as afterwards it can't be told apart from any other.
+    let current = current_session_id(&app_state, &req, &user).await?;
+    if !app_state
+        .repo
+        .remove_user_session(&user.id(), &session_id)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        return Err(ErrorNotFound("No such session."));
+    }
+    info!("Session signed out for {}", user.id());
+    if current == Some(*session_id) {
+        return Ok(signed_out());
+    }
+    Ok(HttpResponse::Ok().body(
+        render_sessions(
+            &app_state,
+            &req,
+            &user,
+            &csrf_token,
+            Some("That device has been signed out."),
+        )
+        .await?,
+    ))
+}
+
+#[post("/account/sessions/revoke_all")]
+async fn revoke_all_sessions(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+) -> Result<HttpResponse> {
+    app_state
+        .repo
+        .remove_user_sessions(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Signed out everywhere for {}", user.id());
+    Ok(signed_out())
+}
+
+/// Remember the device a user signed in from, and email them if it's a new one.
+///
+/// Sending the alert can fail without stopping the sign-in.
+pub(crate) async fn alert_new_device(
+    app_state: &AppState,
+    user: &AuthorizedUser,
+    device: &SessionDevice,
+) -> Result<()> {
+    let history = app_state
+        .repo
+        .remember_device(&user.id(), device)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if history != DeviceHistory::New {
+        return Ok(());
+    }
+    info!("Sign-in from a new device for {}", user.id());
+    let token = app_state
+        .repo
+        .create_token(
+            &user.id(),
+            TokenPurpose::SignInDenial,
+            chrono::Duration::days(NOT_ME_LENGTH_DAYS),
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if let Err(e) = app_state
+        .email_client
+        .send_new_device_email(user.email(), &token, device, Utc::now())
+        .await
+    {
+        error!("Failed to send new device email: {}", e);
+    }
+    Ok(())
+}
+
+/// The "this wasn't me" link, asks before locking the account, as mail scanners follow links.
+#[get("/account/not_me/{token}")]
+async fn not_me_page(
+    app_state: Data<AppState>,
+    csrf_token: CsrfToken,
+    token: Path<String>,
+) -> Result<HttpResponse> {
+    let token = Token::from(token.into_inner());
+    if let Err(e) = app_state
+        .repo
+        .check_token(&token, TokenPurpose::SignInDenial)
+        .await
+    {
+        return not_me_failure(&csrf_token, e);
+    }
+    Ok(HttpResponse::Ok().body(render_not_me(
+        &csrf_token,
+        "Wasn't this you? Lock your account to keep them out.",
+        Some(&token),
+    )?))
+}
+
+/// Signs out every device and sends the user to pick a new password.
+#[post("/account/not_me/{token}")]
+async fn deny_sign_in(
+    app_state: Data<AppState>,
+    csrf_token: CsrfToken,
+    token: Path<String>,
+) -> Result<HttpResponse> {
+    let token = Token::from(token.into_inner());
+    let user_id = match app_state
+        .repo
+        .consume_token(&token, TokenPurpose::SignInDenial)
+        .await
+    {
+        Ok(id) => id,
+        Err(e) => return not_me_failure(&csrf_token, e),
+    };
+    app_state
+        .repo
+        .require_password_reset(&user_id)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Sign-in reported as not them by {}", user_id);
+    // Following the link proves access to the email, so it can go straight to the reset.
+    let reset = app_state
+        .repo
+        .create_token(
+            &user_id,
+            TokenPurpose::PasswordReset,
+            chrono::Duration::minutes(RESET_LENGTH_MINUTES),
+        )
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header((
+            header::LOCATION,
+            format!("/reset_password/{}", reset.expose()),
+        ))
+        .cookie(removal_cookie())
+        .finish())
+}
+
+fn not_me_failure(csrf_token: &CsrfToken, e: TokenError) -> Result<HttpResponse> {
+    if let TokenError::UnexpectedError(e) = e {
+        return Err(ErrorInternalServerError(e));
+    }
+    Ok(HttpResponse::Gone().body(render_not_me(
+        csrf_token,
+        &format!(
+            "{} If you still think someone else has your password, use Forgot password.",
+            e
+        ),
+        None,
+    )?))
+}
+
+/// * `token`: The link's token, if it can still be used to lock the account.
+fn render_not_me(csrf_token: &CsrfToken, message: &str, token: Option<&Token>) -> Result<String> {
+    let mut ctx = Context::new();
+    ctx.insert("message", message);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("token", &token.map(Token::expose));
+    Tera::one_off(&fs::read_to_string("./pages/not_me.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
+
+fn signed_out() -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, "/"))
+        .cookie(removal_cookie())
+        .finish()
+}
+
+async fn current_session_id(
+    app_state: &AppState,
+    req: &HttpRequest,
+    user: &AuthorizedUser,
+) -> Result<Option<Uuid>> {
+    let Some(token) = session_token(req, &app_state.session_key) else {
+        return Ok(None);
+    };
+    Ok(app_state
+        .repo
+        .list_sessions(&user.id(), &token)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .find(|s| s.current)
+        .map(|s| s.session_id))
+}
+
+/// Render the sessions page.
+///
+/// * `message`: Shown after a device is signed out.
+async fn render_sessions(
+    app_state: &AppState,
+    req: &HttpRequest,
+    user: &AuthorizedUser,
+    csrf_token: &CsrfToken,
+    message: Option<&str>,
+) -> Result<String> {
+    let Some(token) = session_token(req, &app_state.session_key) else {
+        return Err(ErrorInternalServerError("Logged in without a session."));
+    };
+    let sessions: Vec<_> = app_state
+        .repo
+        .list_sessions(&user.id(), &token)
+        .await
+        .map_err(ErrorInternalServerError)?
+        .into_iter()
+        .map(|s| {
+            json!({
+                "id": s.session_id,
+                "created_at": s.created_at.format("%Y-%m-%d %H:%M").to_string(),
+                "last_seen_at": s.last_seen_at.format("%Y-%m-%d %H:%M").to_string(),
+                "user_agent": s.user_agent,
+                "ip": s.ip,
+                "current": s.current,
+            })
+        })
+        .collect();
+    let mut ctx = Context::new();
+    ctx.insert("sessions", &sessions);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    ctx.insert("message", &message);
+    Tera::one_off(&fs::read_to_string("./pages/sessions.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
@@ -0,0 +1,230 @@
+//! Turning TOTP two-factor on and off from the account page.
+use std::fs;
+
+use actix_web::error::ErrorInternalServerError;
+use actix_web::web::{Data, Form, ServiceConfig};
+use actix_web::{get, post, HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use secrecy::Secret;
+use serde::Deserialize;
+use tera::{Context, Tera};
+use tracing::info;
+
+use crate::auth::csrf::CsrfToken;
+use crate::auth::totp::{
+    generate_recovery_codes, generate_secret, hash_recovery_code, otpauth_uri, verify_code,
+};
+use crate::auth::user::AuthorizedUser;
+use crate::domain::repository::AppState;
+use crate::routes::account::{confirm_identity, has_password, REAUTH_MINUTES};
+
+pub fn two_factor_service(cfg: &mut ServiceConfig) {
+    cfg.service(two_factor_page)
+        .service(enable_two_factor)
+        .service(disable_two_factor);
+}
+
+/// What the two-factor page shows.
+#[derive(Default)]
+struct TwoFactorPage<'a> {
+    /// Unconfirmed secret, while enrolling.
+    secret: Option<&'a str>,
+    /// Shown once, right after two-factor is switched on.
+    recovery_codes: Option<Vec<String>>,
+    message: Option<&'a str>,
+    error: Option<&'a str>,
+}
+
+#[get("/account/two_factor")]
+async fn two_factor_page(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+) -> Result<HttpResponse> {
+    let totp = app_state
+        .repo
+        .get_totp(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    if totp.is_some_and(|t| t.enabled) {
+        return Ok(HttpResponse::Ok().body(
+            render_two_factor(&app_state, &user, &csrf_token, TwoFactorPage::default()).await?,
+        ));
+    }
+    // Each visit starts over with a new secret, until one is confirmed.
+    let secret = generate_secret();
+    app_state
+        .repo
+        .set_pending_totp(&user.id(), &secret)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().body(
+        render_two_factor(
+            &app_state,
+            &user,
+            &csrf_token,
+            TwoFactorPage {
+                secret: Some(&secret),
+                ..Default::default()
+            },
+        )
+        .await?,
+    ))
+}
+
+#[derive(Deserialize)]
+struct EnableForm {
+    code: String,
+}
+#[post("/account/two_factor")]
+async fn enable_two_factor(
+    app_state: Data<AppState>,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    form: Form<EnableForm>,
+) -> Result<HttpResponse> {
+    let Some(totp) = app_state
+        .repo
+        .get_totp(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?
+        .filter(|t| !t.enabled)
+    else {
+        return Ok(HttpResponse::Conflict().body(
+            render_two_factor(
+                &app_state,
+                &user,
+                &csrf_token,
+                TwoFactorPage {
+                    error: Some("There's no new authenticator to confirm."),
+                    ..Default::default()
+                },
+            )
+            .await?,
+        ));
+    };
+    let Some(step) = verify_code(&totp.secret, &form.code, Utc::now().timestamp(), None) else {
+        return Ok(HttpResponse::BadRequest().body(
+            render_two_factor(
+                &app_state,
+                &user,
+                &csrf_token,
+                TwoFactorPage {
+                    secret: Some(&totp.secret),
+                    error: Some("That code isn't right, check your authenticator's clock."),
+                    ..Default::default()
+                },
+            )
+            .await?,
+        ));
+    };
+    let recovery_codes = generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|c| hash_recovery_code(c))
+        .collect();
+    app_state
+        .repo
+        .enable_totp(&user.id(), step, &hashes)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Two-factor enabled for {}", user.id());
+    Ok(HttpResponse::Ok().body(
+        render_two_factor(
+            &app_state,
+            &user,
+            &csrf_token,
+            TwoFactorPage {
+                recovery_codes: Some(recovery_codes),
+                message: Some("Two-factor is on. Keep these recovery codes somewhere safe, they won't be shown again."),
+                ..Default::default()
+            },
+        )
+        .await?,
+    ))
+}
+
+#[derive(Deserialize)]
+struct DisableForm {
+    current_password: Secret<String>,
+}
+#[post("/account/two_factor/disable")]
+async fn disable_two_factor(
+    app_state: Data<AppState>,
+    req: HttpRequest,
+    user: AuthorizedUser,
+    csrf_token: CsrfToken,
+    form: Form<DisableForm>,
+) -> Result<HttpResponse> {
+    let password = form.into_inner().current_password;
+    if let Some(error) = confirm_identity(&app_state, &req, &user, password).await? {
+        return Ok(HttpResponse::Unauthorized().body(
+            render_two_factor(
+                &app_state,
+                &user,
+                &csrf_token,
+                TwoFactorPage {
+                    error: Some(error),
+                    ..Default::default()
+                },
+            )
+            .await?,
+        ));
+    }
+    app_state
+        .repo
+        .disable_totp(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?;
+    info!("Two-factor disabled for {}", user.id());
+    Ok(HttpResponse::Ok().body(
+        render_two_factor(
+            &app_state,
+            &user,
+            &csrf_token,
+            TwoFactorPage {
+                message: Some("Two-factor is off."),
+                ..Default::default()
+            },
+        )
+        .await?,
+    ))
+}
+
+async fn render_two_factor(
+    app_state: &AppState,
+    user: &AuthorizedUser,
+    csrf_token: &CsrfToken,
+    page: TwoFactorPage<'_>,
+) -> Result<String> {
+    let enabled = app_state
+        .repo
+        .get_totp(&user.id())
+        .await
+        .map_err(ErrorInternalServerError)?
+        .is_some_and(|t| t.enabled);
+    let mut ctx = Context::new();
+    ctx.insert("enabled", &enabled);
+    if enabled {
+        ctx.insert(
+            "remaining",
+            &app_state
+                .repo
+                .remaining_recovery_codes(&user.id())
+                .await
+                .map_err(ErrorInternalServerError)?,
+        );
+    }
+    if let Some(secret) = page.secret {
+        ctx.insert("secret", secret);
+        ctx.insert("otpauth_uri", &otpauth_uri(secret, user.email()));
+    }
+    ctx.insert("recovery_codes", &page.recovery_codes);
+    ctx.insert("message", &page.message);
+    ctx.insert("error", &page.error);
+    ctx.insert("has_password", &has_password(app_state, user).await?);
+    ctx.insert("reauth_minutes", &REAUTH_MINUTES);
+    ctx.insert("csrf_token", csrf_token.as_str());
+    Tera::one_off(&fs::read_to_string("./pages/two_factor.html")?, &ctx, true)
+        .map_err(ErrorInternalServerError)
+}
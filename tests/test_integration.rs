@@ -1,20 +1,51 @@
 #[cfg(test)]
 mod tests {
-    use actix_web::http::StatusCode;
+    use actix_web::body::MessageBody;
+    use actix_web::cookie::{Cookie, Key};
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::http::{header, StatusCode};
     use actix_web::test;
     use actix_web::web::{self, ServiceConfig};
-    use actix_web::{web::Data, App};
+    use actix_web::{web::Data, App, HttpResponse, HttpServer};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
     use chrono::{NaiveDate, NaiveTime};
+    use date_rs::auth::api_token::{ApiScope, GROUP_HEADER};
+    use date_rs::auth::csrf::CSRF_HEADER;
+    use date_rs::auth::email_address::{DisposableDomains, EmailAddress};
+    use date_rs::auth::oidc::OidcConfig;
+    use date_rs::auth::passkey::RelyingParty;
+    use date_rs::auth::password_policy::{
+        BreachedPasswords, PasswordPolicy, BREACHED_PASSWORDS_FILE,
+    };
+    use date_rs::auth::proof_of_work::{
+        is_solution, solve, ProofOfWork, CHALLENGE_FIELD, SOLUTION_FIELD,
+    };
+    use date_rs::auth::session::{private_cookie, DeviceHistory, SessionDevice, SESSION_COOKIE};
+    use date_rs::auth::throttle::{ThrottleSubject, FREE_ATTEMPTS, LOCKOUT_ATTEMPTS};
+    use date_rs::auth::token::{Token, TokenPurpose};
+    use date_rs::auth::totp::{code_at, RECOVERY_CODE_COUNT, TOTP_STEP_SECONDS};
     use date_rs::auth::user::AuthorizedUser;
-    use date_rs::auth::user::{GroupUser, UnRegisteredUser};
+    use date_rs::auth::user::{GroupUser, NoGroupUser, UnRegisteredUser};
     use date_rs::backend::postgres::PgRepo;
     use date_rs::domain::dates::Date;
     use date_rs::domain::repository::AppState;
     use date_rs::email::EmailClient;
+    use date_rs::routes::email_link::{EMAIL_LINK_COOKIE, EMAIL_LINK_MINUTES};
     use date_rs::routes::landing::MainService;
+    use date_rs::routes::oidc::OIDC_COOKIE;
+    use date_rs::routes::passkeys::PASSKEY_COOKIE;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+    use rsa::traits::PublicKeyParts;
+    use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+    use secrecy::Secret;
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
     use sqlx::PgPool;
     use std::collections::HashMap;
-    use uuid::Uuid;
+    use std::sync::{Arc, Mutex};
+    use url::Url;
     // TODO: Make tabular. At the moment this is much to long.
     //
     //
@@ -25,19 +56,20 @@ mod tests {
     }
     async fn mock_db() -> web::Data<AppState> {
         let state = AppState::new(
-            Box::new(PgRepo {
-                pool: get_pool().await,
-            }),
+            Box::new(PgRepo::new(get_pool().await)),
             EmailClient::new("test", "test", "test"),
+            Key::generate(),
         );
         Data::new(state)
     }
 
     async fn mock_user(state: &AppState) -> anyhow::Result<GroupUser> {
-        let mock_user =
-            UnRegisteredUser::new(format!("{}@test.com", uuid::Uuid::new_v4()), "assword");
+        let mock_user = UnRegisteredUser::new(
+            EmailAddress::parse(&format!("{}@test.com", uuid::Uuid::new_v4()))?,
+            "assword",
+        );
         let id;
-        if let Ok(user) = state.repo.get_user_by_email(&mock_user.email).await {
+        if let Ok(user) = state.repo.get_user_by_email(mock_user.email.as_str()).await {
             id = user.id();
         } else {
             id = state.repo.register_user(mock_user).await?;
@@ -51,10 +83,13 @@ mod tests {
     async fn mock_date(state: &AppState, user: &GroupUser) -> anyhow::Result<Date> {
         let mock_date = Date::new("test date");
 
-        state.repo.add(mock_date.clone(), user.user_id).await?;
         state
             .repo
-            .get(&mock_date.id, &user.user_id)
+            .add(mock_date.clone(), user.user_group, user.user_id)
+            .await?;
+        state
+            .repo
+            .get(&mock_date.id, user.user_group, &user.user_id)
             .await
             .ok_or(anyhow::anyhow!("Date wans't found"))
     }
@@ -65,6 +100,381 @@ mod tests {
         let date = mock_date(&state, &user).await?;
         Ok((state, user, date))
     }
+    /// Log a user in, returning their session cookie.
+    async fn login_cookie<S, B>(app: &S, user: &GroupUser) -> Cookie<'static>
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let mut form = HashMap::new();
+        form.insert("email".to_string(), user.email.as_str());
+        form.insert("password".to_string(), "assword");
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        resp.response()
+            .cookies()
+            .find(|c| c.name() == SESSION_COOKIE)
+            .expect("Login didn't set a session cookie.")
+            .into_owned()
+    }
+    /// Anti-forgery header for a session, read from the page the user would be shown.
+    async fn csrf_header<S, B>(app: &S, session: &Cookie<'static>) -> (&'static str, String)
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .cookie(session.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(app, req).await).to_string();
+        page_csrf_header(&body)
+    }
+    /// Header carrying the CSRF token a page was rendered with.
+    fn page_csrf_header(body: &str) -> (&'static str, String) {
+        let token = body
+            .split("name=\"csrf_token\" value=\"")
+            .nth(1)
+            .and_then(|b| b.split('"').next())
+            .expect("Page has no CSRF token.")
+            .to_string();
+        (CSRF_HEADER, token)
+    }
+    /// Identity the mock provider hands out for a code.
+    #[derive(Clone)]
+    struct MockIdentity {
+        challenge: String,
+        nonce: String,
+        sub: String,
+        email: String,
+        email_verified: bool,
+    }
+    #[derive(Clone)]
+    struct MockIssuer {
+        url: String,
+        key: RsaPrivateKey,
+        codes: Arc<Mutex<HashMap<String, MockIdentity>>>,
+    }
+    impl MockIssuer {
+        fn config(&self) -> OidcConfig {
+            OidcConfig {
+                name: "Mock".into(),
+                issuer: self.url.clone(),
+                client_id: "date_rs".into(),
+                client_secret: Secret::new("secret".into()),
+                redirect_url: "http://localhost/oidc/callback".into(),
+            }
+        }
+        fn id_token(&self, identity: &MockIdentity) -> String {
+            let header = URL_SAFE_NO_PAD.encode(json!({"alg": "RS256", "kid": "mock"}).to_string());
+            let payload = URL_SAFE_NO_PAD.encode(
+                json!({
+                    "iss": self.url,
+                    "sub": identity.sub,
+                    "aud": "date_rs",
+                    "exp": chrono::Utc::now().timestamp() + 300,
+                    "iat": chrono::Utc::now().timestamp(),
+                    "nonce": identity.nonce,
+                    "email": identity.email,
+                    "email_verified": identity.email_verified,
+                })
+                .to_string(),
+            );
+            let digest = Sha256::digest(format!("{}.{}", header, payload).as_bytes());
+            let signature = self
+                .key
+                .sign(Pkcs1v15Sign::new::<Sha256>(), &digest)
+                .unwrap();
+            format!(
+                "{}.{}.{}",
+                header,
+                payload,
+                URL_SAFE_NO_PAD.encode(signature)
+            )
+        }
+    }
+    /// Run a standards shaped OpenID provider, that serves discovery, JWKS and code exchange.
+    async fn mock_issuer() -> MockIssuer {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = MockIssuer {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            key: RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap(),
+            codes: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let state = issuer.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(Data::new(state.clone()))
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(|issuer: Data<MockIssuer>| async move {
+                        HttpResponse::Ok().json(json!({
+                            "issuer": issuer.url,
+                            "authorization_endpoint": format!("{}/authorize", issuer.url),
+                            "token_endpoint": format!("{}/token", issuer.url),
+                            "jwks_uri": format!("{}/jwks", issuer.url),
+                        }))
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    web::get().to(|issuer: Data<MockIssuer>| async move {
+                        HttpResponse::Ok().json(json!({"keys": [{
+                            "kty": "RSA",
+                            "kid": "mock",
+                            "use": "sig",
+                            "n": URL_SAFE_NO_PAD.encode(issuer.key.n().to_bytes_be()),
+                            "e": URL_SAFE_NO_PAD.encode(issuer.key.e().to_bytes_be()),
+                        }]}))
+                    }),
+                )
+                .route(
+                    "/token",
+                    web::post().to(
+                        |issuer: Data<MockIssuer>,
+                         form: web::Form<HashMap<String, String>>| async move {
+                            let identity = issuer.codes.lock().unwrap().remove(&form["code"]);
+                            let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+                            match identity {
+                                Some(identity)
+                                    if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier))
+                                        == identity.challenge =>
+                                {
+                                    HttpResponse::Ok().json(json!({
+                                        "access_token": "unused",
+                                        "token_type": "Bearer",
+                                        "id_token": issuer.id_token(&identity),
+                                    }))
+                                }
+                                _ => HttpResponse::BadRequest()
+                                    .json(json!({"error": "invalid_grant"})),
+                            }
+                        },
+                    ),
+                )
+        })
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        issuer
+    }
+    /// Start an OpenID login, and have the mock provider approve it for an identity.
+    ///
+    /// Returns the flow cookie and the callback url the provider would redirect to.
+    async fn approved_oidc_login<S, B>(
+        app: &S,
+        issuer: &MockIssuer,
+        sub: &str,
+        email: &str,
+        email_verified: bool,
+    ) -> (Cookie<'static>, String)
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        start_oidc_flow(app, issuer, sub, email, email_verified, None).await
+    }
+    /// Like `approved_oidc_login`, but connecting the identity to a logged in user.
+    async fn start_oidc_flow<S, B>(
+        app: &S,
+        issuer: &MockIssuer,
+        sub: &str,
+        email: &str,
+        email_verified: bool,
+        session: Option<&Cookie<'static>>,
+    ) -> (Cookie<'static>, String)
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let req = match session {
+            Some(session) => test::TestRequest::post()
+                .uri("/oidc/connect")
+                .cookie(session.clone())
+                .insert_header(csrf_header(app, session).await)
+                .to_request(),
+            None => test::TestRequest::get().uri("/oidc/login").to_request(),
+        };
+        let resp = test::call_service(app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == OIDC_COOKIE)
+            .expect("Login didn't set a flow cookie.")
+            .into_owned();
+        let location = Url::parse(
+            resp.headers()
+                .get(header::LOCATION)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(location.as_str().starts_with(&issuer.url));
+        let params: HashMap<String, String> = location.query_pairs().into_owned().collect();
+        assert_eq!(params["code_challenge_method"], "S256");
+        let code = uuid::Uuid::new_v4().to_string();
+        issuer.codes.lock().unwrap().insert(
+            code.clone(),
+            MockIdentity {
+                challenge: params["code_challenge"].clone(),
+                nonce: params["nonce"].clone(),
+                sub: sub.into(),
+                email: email.into(),
+                email_verified,
+            },
+        );
+        (
+            cookie,
+            format!("/oidc/callback?code={}&state={}", code, params["state"]),
+        )
+    }
+    /// CBOR head for an item, with its length or value as the argument.
+    fn cbor_head(major: u8, arg: usize) -> Vec<u8> {
+        match arg {
+            0..=23 => vec![major << 5 | arg as u8],
+            24..=255 => vec![major << 5 | 24, arg as u8],
+            _ => {
+                let mut head = vec![major << 5 | 25];
+                head.extend_from_slice(&(arg as u16).to_be_bytes());
+                head
+            }
+        }
+    }
+    fn cbor_bytes(bytes: &[u8]) -> Vec<u8> {
+        let mut out = cbor_head(2, bytes.len());
+        out.extend_from_slice(bytes);
+        out
+    }
+    fn cbor_text(text: &str) -> Vec<u8> {
+        let mut out = cbor_head(3, text.len());
+        out.extend_from_slice(text.as_bytes());
+        out
+    }
+    /// A software passkey, doing what a browser and authenticator would between them.
+    struct MockAuthenticator {
+        key: EcdsaKeyPair,
+        credential_id: Vec<u8>,
+        counter: u32,
+        rng: SystemRandom,
+    }
+    impl MockAuthenticator {
+        const RP_ID: &'static str = "localhost";
+        const ORIGIN: &'static str = "https://localhost";
+        fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+            MockAuthenticator {
+                key: EcdsaKeyPair::from_pkcs8(
+                    &ECDSA_P256_SHA256_ASN1_SIGNING,
+                    pkcs8.as_ref(),
+                    &rng,
+                )
+                .unwrap(),
+                credential_id: uuid::Uuid::new_v4().as_bytes().to_vec(),
+                counter: 0,
+                rng,
+            }
+        }
+        fn id(&self) -> String {
+            URL_SAFE_NO_PAD.encode(&self.credential_id)
+        }
+        fn client_data(ceremony: &str, options: &Value) -> Vec<u8> {
+            json!({
+                "type": ceremony,
+                "challenge": options["challenge"],
+                "origin": Self::ORIGIN,
+            })
+            .to_string()
+            .into_bytes()
+        }
+        /// Authenticator data, with the user present and verified.
+        fn auth_data(&mut self, extra_flags: u8) -> Vec<u8> {
+            self.counter += 1;
+            let mut data = Sha256::digest(Self::RP_ID.as_bytes()).to_vec();
+            data.push(0x05 | extra_flags);
+            data.extend_from_slice(&self.counter.to_be_bytes());
+            data
+        }
+        /// Answer a registration's creation options.
+        fn register(&mut self, options: &Value, name: &str) -> Value {
+            assert_eq!(options["rp"]["id"], Self::RP_ID);
+            let point = self.key.public_key().as_ref();
+            // {1: 2, 3: -7, -1: 1, -2: x, -3: y}
+            let mut cose_key = vec![0xa5, 0x01, 0x02, 0x03, 0x26, 0x20, 0x01, 0x21];
+            cose_key.extend(cbor_bytes(&point[1..33]));
+            cose_key.push(0x22);
+            cose_key.extend(cbor_bytes(&point[33..65]));
+            let mut auth_data = self.auth_data(0x40);
+            auth_data.extend_from_slice(&[0; 16]);
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            auth_data.extend(cose_key);
+            // {"fmt": "none", "attStmt": {}, "authData": auth_data}
+            let mut attestation = vec![0xa3];
+            attestation.extend(cbor_text("fmt"));
+            attestation.extend(cbor_text("none"));
+            attestation.extend(cbor_text("attStmt"));
+            attestation.push(0xa0);
+            attestation.extend(cbor_text("authData"));
+            attestation.extend(cbor_bytes(&auth_data));
+            json!({
+                "id": self.id(),
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(Self::client_data("webauthn.create", options)),
+                "attestationObject": URL_SAFE_NO_PAD.encode(attestation),
+                "name": name,
+            })
+        }
+        /// Sign a login's request options.
+        fn assert(&mut self, options: &Value, user_id: &uuid::Uuid) -> Value {
+            assert_eq!(options["rpId"], Self::RP_ID);
+            let client_data = Self::client_data("webauthn.get", options);
+            let auth_data = self.auth_data(0);
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(&client_data));
+            let signature = self.key.sign(&self.rng, &signed).unwrap();
+            json!({
+                "id": self.id(),
+                "clientDataJSON": URL_SAFE_NO_PAD.encode(client_data),
+                "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+                "userHandle": URL_SAFE_NO_PAD.encode(user_id.as_bytes()),
+            })
+        }
+    }
+    /// Start a passkey ceremony, returning its options and challenge cookie.
+    async fn start_passkey_ceremony<S, B>(
+        app: &S,
+        uri: &str,
+        session: Option<Cookie<'static>>,
+    ) -> (Value, Cookie<'static>)
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let mut req = test::TestRequest::post().uri(uri);
+        if let Some(session) = session {
+            req = req
+                .insert_header(csrf_header(app, &session).await)
+                .cookie(session);
+        }
+        let resp = test::call_service(app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == PASSKEY_COOKIE)
+            .expect("Ceremony didn't set a challenge cookie.")
+            .into_owned();
+        (test::read_body_json(resp).await, cookie)
+    }
     fn get_mock_form() -> HashMap<String, String> {
         let mut form_data = HashMap::new();
         form_data.insert(
@@ -93,8 +503,13 @@ mod tests {
         }))
         .await;
         let form_data = get_mock_form();
-        let uri = format!("/dates/{}/{}/description", user.user_id, date.id);
-        let req = test::TestRequest::post().uri(&uri).set_form(form_data);
+        let cookie = login_cookie(&app, &user).await;
+        let uri = format!("/dates/{}/description", date.id);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(csrf_header(&app, &cookie).await)
+            .cookie(cookie)
+            .set_form(form_data);
         let resp = test::call_service(&app, req.to_request()).await;
         assert!(resp.status().is_success());
     }
@@ -109,13 +524,99 @@ mod tests {
         }))
         .await;
         let form_data = get_mock_form();
-        let uri = format!("/dates/{}/{}/description", user.user_id, date.id);
-        let req = test::TestRequest::post().uri(&uri).set_form(form_data);
+        let cookie = login_cookie(&app, &user).await;
+        let uri = format!("/dates/{}/description", date.id);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(csrf_header(&app, &cookie).await)
+            .cookie(cookie)
+            .set_form(form_data);
         let resp = test::call_and_read_body(&app, req.to_request()).await;
         let text = String::from_utf8(resp.to_vec()).unwrap();
         assert!(text.contains("Test Description."));
     }
     #[actix_web::test]
+    async fn test_update_description_missing_fields() {
+        let (_, user, date) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &user).await;
+        let csrf = csrf_header(&app, &cookie).await;
+        let uri = format!("/dates/{}/description", date.id);
+        for missing in ["time", "day", "description_text"] {
+            let mut form_data = get_mock_form();
+            form_data.remove(missing);
+            let req = test::TestRequest::post()
+                .uri(&uri)
+                .insert_header(csrf.clone())
+                .cookie(cookie.clone())
+                .set_form(form_data);
+            assert_eq!(
+                test::call_service(&app, req.to_request()).await.status(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+    #[actix_web::test]
+    async fn test_dates_follow_group_timezone_and_approval() {
+        let (state, user, date) = mock_db_user_date().await.unwrap();
+        let mut profile = state.repo.get_group_profile(user.user_group).await.unwrap();
+        profile.timezone = "Asia/Tokyo".into();
+        state
+            .repo
+            .update_group_profile(&user.user_id, &profile)
+            .await
+            .unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &user).await;
+        let csrf = csrf_header(&app, &cookie).await;
+        let req = test::TestRequest::post()
+            .uri(&format!("/dates/{}/description", date.id))
+            .insert_header(csrf.clone())
+            .cookie(cookie.clone())
+            .set_form([
+                ("description_text", "Test Description."),
+                ("time", "08:00"),
+                ("day", "2020-11-01"),
+            ])
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        // The time is entered and shown in the group's timezone.
+        assert!(body.contains("08:00 01/11/2020"));
+        let day = state
+            .repo
+            .get(&date.id, user.user_group, &user.user_id)
+            .await
+            .unwrap()
+            .description
+            .day
+            .unwrap();
+        assert_eq!(
+            day.naive_utc(),
+            NaiveDate::from_ymd_opt(2020, 10, 31)
+                .unwrap()
+                .and_hms_opt(23, 0, 0)
+                .unwrap()
+        );
+        assert!(body.contains(&format!("/dates/{}/approve", date.id)));
+        let req = test::TestRequest::post()
+            .uri(&format!("/dates/{}/approve", date.id))
+            .insert_header(csrf)
+            .cookie(cookie)
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("Approved"));
+    }
+    #[actix_web::test]
     async fn test_update_description_fails_with_empty_date() {
         // start_tracting();
         let (_, user, date) = mock_db_user_date().await.unwrap();
@@ -127,8 +628,13 @@ mod tests {
         .await;
         let mut form_data = get_mock_form();
         form_data.insert("day".to_string(), "".to_string());
-        let uri = format!("/dates/{}/{}/description", user.user_id, date.id);
-        let req = test::TestRequest::post().uri(&uri).set_form(form_data);
+        let cookie = login_cookie(&app, &user).await;
+        let uri = format!("/dates/{}/description", date.id);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(csrf_header(&app, &cookie).await)
+            .cookie(cookie)
+            .set_form(form_data);
         assert!(test::call_service(&app, req.to_request())
             .await
             .status()
@@ -146,8 +652,13 @@ mod tests {
         .await;
         let mut form_data = get_mock_form();
         form_data.insert("time".to_string(), "".to_string());
-        let uri = format!("/dates/{}/{}/description", user.user_id, date.id);
-        let req = test::TestRequest::post().uri(&uri).set_form(form_data);
+        let cookie = login_cookie(&app, &user).await;
+        let uri = format!("/dates/{}/description", date.id);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .insert_header(csrf_header(&app, &cookie).await)
+            .cookie(cookie)
+            .set_form(form_data);
         assert!(test::call_service(&app, req.to_request())
             .await
             .status()
@@ -165,14 +676,70 @@ mod tests {
         .await;
         let mut form = HashMap::new();
         form.insert("name".to_string(), "Test".to_string());
-        let uri = format!("/dates/{}/new_date", user.user_id);
-        let req = test::TestRequest::post().uri(&uri).set_form(form);
+        let cookie = login_cookie(&app, &user).await;
+        let req = test::TestRequest::post()
+            .uri("/dates/new_date")
+            .insert_header(csrf_header(&app, &cookie).await)
+            .cookie(cookie)
+            .set_form(form);
         assert!(test::call_service(&app, req.to_request())
             .await
             .status()
             .is_success());
     }
     #[actix_web::test]
+    async fn test_csrf_required() {
+        let (_, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &user).await;
+        let mut form = HashMap::new();
+        form.insert("name", "Forged");
+        let req = test::TestRequest::post()
+            .uri("/dates/new_date")
+            .cookie(cookie.clone())
+            .set_form(&form);
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+        assert!(body.contains("couldn't be checked"));
+        // A token from another session doesn't do either.
+        let (header, _) = csrf_header(&app, &cookie).await;
+        let other = login_cookie(&app, &user).await;
+        let req = test::TestRequest::post()
+            .uri("/dates/new_date")
+            .insert_header(csrf_header(&app, &other).await)
+            .cookie(cookie.clone())
+            .set_form(&form);
+        assert_eq!(
+            test::call_service(&app, req.to_request()).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let req = test::TestRequest::post()
+            .uri("/dates/new_date")
+            .insert_header((header, "00"))
+            .cookie(cookie.clone())
+            .set_form(&form);
+        assert_eq!(
+            test::call_service(&app, req.to_request()).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        // Plain forms send it as a field instead, and the route still sees the whole form.
+        let (_, token) = csrf_header(&app, &cookie).await;
+        form.insert("csrf_token", &token);
+        let req = test::TestRequest::post()
+            .uri("/dates/new_date")
+            .cookie(cookie)
+            .set_form(&form);
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req.to_request()).await)
+            .to_string();
+        assert!(body.contains("Forged"));
+    }
+    #[actix_web::test]
     async fn test_add_date_fail() {
         // start_tracting();
         mock_db_user_date().await.unwrap();
@@ -182,10 +749,11 @@ mod tests {
                 .service_configuration(cfg)
         }))
         .await;
-        let uri = format!("/dates/{}/new_date", Uuid::new_v4());
         let mut form = HashMap::new();
         form.insert("name".to_string(), "Test".to_string());
-        let req = test::TestRequest::post().uri(&uri).set_form(form);
+        let req = test::TestRequest::post()
+            .uri("/dates/new_date")
+            .set_form(form);
         assert_eq!(
             test::call_service(&app, req.to_request()).await.status(),
             StatusCode::UNAUTHORIZED
@@ -203,28 +771,116 @@ mod tests {
         .await;
         let mut form = HashMap::new();
         form.insert("name".to_string(), "".to_string());
-        let uri = format!("/dates/{}/new_date", user.user_id);
-        let req = test::TestRequest::post().uri(&uri).set_form(form);
+        let cookie = login_cookie(&app, &user).await;
+        let req = test::TestRequest::post()
+            .uri("/dates/new_date")
+            .cookie(cookie)
+            .set_form(form);
         assert_eq!(
             test::call_service(&app, req.to_request()).await.status(),
             StatusCode::FORBIDDEN
         );
     }
     #[actix_web::test]
-    async fn test_remove_user() {
-        // start_tracting();
-        let (_, user, _) = mock_db_user_date().await.unwrap();
+    async fn test_delete_account() {
+        let (state, user, _) = mock_db_user_date().await.unwrap();
         let pool = get_pool().await;
         let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
             MainService::new(pool, EmailClient::new("test", "test", "test"))
                 .service_configuration(cfg)
         }))
         .await;
+        // Deleting by address alone is no longer possible.
         let req = test::TestRequest::delete()
             .uri(&format!("/{}", user.email))
             .to_request();
-        let resp = test::call_service(&app, req).await.status();
-        assert_eq!(resp, StatusCode::OK);
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let cookie = login_cookie(&app, &user).await;
+        let req = test::TestRequest::get()
+            .uri("/account/delete")
+            .cookie(cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("last member of your group"));
+        let token = body
+            .split("name=\"csrf_token\" value=\"")
+            .nth(1)
+            .and_then(|b| b.split('"').next())
+            .unwrap()
+            .to_string();
+        let mut form = HashMap::new();
+        form.insert("password", "wrong");
+        form.insert("csrf_token", &token);
+        let req = test::TestRequest::post()
+            .uri("/account/delete")
+            .cookie(cookie.clone())
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        form.remove("csrf_token");
+        form.insert("password", "assword");
+        let req = test::TestRequest::post()
+            .uri("/account/delete")
+            .cookie(cookie.clone())
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        assert!(state
+            .repo
+            .get_user_deletion(&user.user_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        state
+            .repo
+            .schedule_user_deletion(
+                &user.user_id,
+                chrono::Utc::now() + chrono::Duration::days(1),
+            )
+            .await
+            .unwrap();
+        let cancel = state
+            .repo
+            .create_token(
+                &user.user_id,
+                TokenPurpose::AccountDeletionCancel,
+                chrono::Duration::days(1),
+            )
+            .await
+            .unwrap();
+        let req = test::TestRequest::get()
+            .uri("/account/delete")
+            .cookie(cookie)
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("id=\"deletion_at\""));
+        let req = test::TestRequest::get()
+            .uri(&format!("/account/delete/cancel/{}", cancel.expose()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(state
+            .repo
+            .get_user_deletion(&user.user_id)
+            .await
+            .unwrap()
+            .is_none());
+        let req = test::TestRequest::get()
+            .uri(&format!("/account/delete/cancel/{}", cancel.expose()))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::GONE
+        );
     }
 
     #[actix_web::test]
@@ -237,13 +893,108 @@ mod tests {
                 .service_configuration(cfg)
         }))
         .await;
+        let cookie = login_cookie(&app, &user).await;
         let req = test::TestRequest::get()
-            .uri(&format!("/dates/{}", user.user_id))
+            .uri("/dates")
+            .cookie(cookie)
             .to_request();
         let resp = test::call_service(&app, req).await.status();
         assert_eq!(resp, StatusCode::OK);
     }
     #[actix_web::test]
+    async fn test_index_requires_session() {
+        let (_, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/dates/{}", user.user_id))
+            .to_request();
+        let resp = test::call_service(&app, req).await.status();
+        assert_eq!(resp, StatusCode::UNAUTHORIZED);
+        let req = test::TestRequest::get().uri("/dates").to_request();
+        let resp = test::call_service(&app, req).await.status();
+        assert_eq!(resp, StatusCode::UNAUTHORIZED);
+    }
+    #[actix_web::test]
+    async fn test_dates_require_group() {
+        let (state, user, date) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &user).await;
+        let csrf = csrf_header(&app, &cookie).await;
+        state
+            .repo
+            .remove_user_from_group(&user.user_id, user.user_group)
+            .await
+            .unwrap();
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        for uri in [
+            format!("/dates/{}/increment", date.id),
+            format!("/dates/{}/remove", date.id),
+            format!("/dates/{}", date.id),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(&uri)
+                .cookie(cookie.clone())
+                .insert_header(csrf.clone())
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::FORBIDDEN,
+                "{} let a group-less user through",
+                uri
+            );
+        }
+        let req = test::TestRequest::get()
+            .uri(&format!("/dates/{}/description", date.id))
+            .cookie(cookie)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+    #[actix_web::test]
+    async fn test_logout() {
+        let (_, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &user).await;
+        let csrf = csrf_header(&app, &cookie).await;
+        let req = test::TestRequest::post()
+            .uri("/logout")
+            .cookie(cookie.clone())
+            .insert_header(csrf)
+            .to_request();
+        let resp = test::call_service(&app, req).await.status();
+        assert_eq!(resp, StatusCode::SEE_OTHER);
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&app, req).await.status();
+        assert_eq!(resp, StatusCode::UNAUTHORIZED);
+    }
+    #[actix_web::test]
     async fn test_login() {
         let pool = get_pool().await;
         let (_, user, _) = mock_db_user_date().await.unwrap();
@@ -292,15 +1043,143 @@ mod tests {
         }))
         .await;
         let mut form = HashMap::new();
-        form.insert("email".to_string(), "doesnt@exist.com");
+        // A fresh address each run, so failures don't pile up against it.
+        let email = format!("{}@doesnt.exist", uuid::Uuid::new_v4());
+        form.insert("email".to_string(), email.as_str());
         form.insert("password".to_string(), "failword");
         let req = test::TestRequest::post()
             .uri("/login")
             .set_form(&form)
             .to_request();
         tracing::info!("Sending request.");
-        let resp = test::call_service(&app, req).await.status();
-        assert_eq!(resp, StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, req).await;
+        // Answered the same way as a wrong password.
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+        assert_eq!(body, "Wrong email or password.");
+    }
+    #[actix_web::test]
+    async fn test_login_throttled() {
+        let (_, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let mut form = HashMap::new();
+        form.insert("email", user.email.as_str());
+        form.insert("password", "failword");
+        for _ in 0..FREE_ATTEMPTS {
+            let req = test::TestRequest::post()
+                .uri("/login")
+                .set_form(&form)
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        // Even the right password has to wait now, however the address is spelled.
+        let shouted = user.email.to_uppercase();
+        form.insert("email", shouted.as_str());
+        form.insert("password", "assword");
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+    }
+    #[actix_web::test]
+    async fn test_unlock_account() {
+        let (state, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let subject = ThrottleSubject::Email(&user.email);
+        for _ in 0..LOCKOUT_ATTEMPTS {
+            state.repo.record_failure(&subject).await.unwrap();
+        }
+        let mut form = HashMap::new();
+        form.insert("email", user.email.as_str());
+        form.insert("password", "assword");
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        let token = state
+            .repo
+            .create_token(
+                &user.user_id,
+                TokenPurpose::AccountUnlock,
+                chrono::Duration::hours(1),
+            )
+            .await
+            .unwrap();
+        let uri = format!("/unlock/{}", token.expose());
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::GONE
+        );
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_form(&form)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+    #[actix_web::test]
+    async fn test_forwarded_ip_needs_trusted_proxy() {
+        let (_, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let proxy = "10.0.0.1".parse().unwrap();
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .trusted_proxies(vec![proxy])
+                .service_configuration(cfg)
+        }))
+        .await;
+        let mut form = HashMap::new();
+        form.insert("email", user.email.as_str());
+        form.insert("password", "assword");
+        // Only the proxy gets to say who it's forwarding for.
+        for (peer, shown) in [("203.0.113.5", "203.0.113.5"), ("10.0.0.1", "198.51.100.7")] {
+            let req = test::TestRequest::post()
+                .uri("/login")
+                .peer_addr(format!("{}:4000", peer).parse().unwrap())
+                .insert_header(("X-Forwarded-For", "198.51.100.7"))
+                .set_form(&form)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let cookie = resp
+                .response()
+                .cookies()
+                .find(|c| c.name() == SESSION_COOKIE)
+                .unwrap()
+                .into_owned();
+            let req = test::TestRequest::get()
+                .uri("/account/sessions")
+                .cookie(cookie)
+                .to_request();
+            let body =
+                String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+            let this_device = body.split("(this device)").nth(1).unwrap();
+            let from = this_device.find("From ").unwrap();
+            assert!(this_device[from..].starts_with(&format!("From {}", shown)));
+        }
     }
     #[actix_web::test]
     async fn test_register() {
@@ -314,7 +1193,17 @@ mod tests {
 
         let email = format!("{}@test.com", uuid::Uuid::new_v4());
         form.insert("email".to_string(), email.as_str());
-        form.insert("password".to_string(), "assword");
+        form.insert("password".to_string(), "");
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+        assert!(body.contains("id=\"password_error\""));
+        assert!(body.contains(&email));
+        form.insert("password".to_string(), "tolerably-long-passphrase");
         let req = test::TestRequest::post()
             .uri("/register")
             .set_form(&form)
@@ -322,4 +1211,1851 @@ mod tests {
         let resp = test::call_service(&app, req).await.status();
         assert_eq!(resp, StatusCode::OK);
     }
+    #[actix_web::test]
+    async fn test_breached_password_rejected() {
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .password_policy(PasswordPolicy {
+                    breached: BreachedPasswords::load(BREACHED_PASSWORDS_FILE).unwrap(),
+                    ..Default::default()
+                })
+                .service_configuration(cfg)
+        }))
+        .await;
+        let email = format!("{}@test.com", uuid::Uuid::new_v4());
+        let mut form = HashMap::new();
+        form.insert("email", email.as_str());
+        form.insert("password", "password123");
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+        assert!(body.contains("data breach"));
+    }
+    #[actix_web::test]
+    async fn test_register_normalizes_email() {
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .disposable_domains(DisposableDomains::parse("mailinator.com"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let register = |email: String| {
+            let mut form = HashMap::new();
+            form.insert("email", email);
+            form.insert("password", "tolerably-long-passphrase".to_string());
+            test::TestRequest::post()
+                .uri("/register")
+                .set_form(&form)
+                .to_request()
+        };
+        let resp = test::call_service(&app, register("not an email".to_string())).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+        assert!(body.contains("email_error"));
+        let resp = test::call_service(&app, register("someone@mailinator.com".to_string())).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let local = uuid::Uuid::new_v4();
+        let resp = test::call_service(&app, register(format!(" {}@Test.com", local))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, register(format!("{}@test.COM", local))).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+    }
+    #[actix_web::test]
+    async fn test_proof_of_work() {
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .proof_of_work(ProofOfWork {
+                    base_difficulty: 8,
+                    ..Default::default()
+                })
+                .service_configuration(cfg)
+        }))
+        .await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&body).contains("/pow.js"));
+
+        let email = format!("{}@test.com", uuid::Uuid::new_v4());
+        let mut form = HashMap::new();
+        form.insert("email", email.clone());
+        form.insert("password", "tolerably-long-passphrase".to_string());
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+        assert!(body.contains("id=\"pow_error\""));
+
+        let req = test::TestRequest::get().uri("/challenge").to_request();
+        let challenge: Value = test::call_and_read_body_json(&app, req).await;
+        let signed = challenge["challenge"].as_str().unwrap().to_string();
+        let difficulty = challenge["difficulty"].as_u64().unwrap() as u8;
+        assert!(difficulty >= 8);
+        form.insert(CHALLENGE_FIELD, signed.clone());
+        form.insert(SOLUTION_FIELD, solve(&signed, difficulty).to_string());
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_form(&form)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        // A solution is only good once.
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        // Nor can a challenge be made easier.
+        let easier = signed.replacen(&format!(".{}.", difficulty), ".0.", 1);
+        form.insert(CHALLENGE_FIELD, easier);
+        form.insert(SOLUTION_FIELD, "0".to_string());
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        // A challenge fetched before the load climbed has to meet the new difficulty.
+        let key = Key::generate();
+        let proof_of_work_app = |base_difficulty: u8| {
+            let key = key.clone();
+            async move {
+                let pool = get_pool().await;
+                test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+                    MainService::new(pool, EmailClient::new("test", "test", "test"))
+                        .session_key(key)
+                        .proof_of_work(ProofOfWork {
+                            base_difficulty,
+                            max_difficulty: base_difficulty,
+                            ..Default::default()
+                        })
+                        .service_configuration(cfg)
+                }))
+                .await
+            }
+        };
+        let quiet = proof_of_work_app(8).await;
+        let req = test::TestRequest::get().uri("/challenge").to_request();
+        let challenge: Value = test::call_and_read_body_json(&quiet, req).await;
+        let signed = challenge["challenge"].as_str().unwrap().to_string();
+        let busy = proof_of_work_app(12).await;
+        let solved = (0..)
+            .map(|c: u64| c.to_string())
+            .find(|c| is_solution(&signed, c, 8) && !is_solution(&signed, c, 12))
+            .unwrap();
+        form.insert("email", format!("{}@test.com", uuid::Uuid::new_v4()));
+        form.insert(CHALLENGE_FIELD, signed.clone());
+        form.insert(SOLUTION_FIELD, solved);
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&busy, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        form.insert(SOLUTION_FIELD, solve(&signed, 12).to_string());
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&busy, req).await.status(),
+            StatusCode::OK
+        );
+
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let req = test::TestRequest::get().uri("/challenge").to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+    #[actix_web::test]
+    async fn test_new_device_alert() {
+        let (state, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let login = |password: &str, user_agent: &str| {
+            let mut form = HashMap::new();
+            form.insert("email", user.email.as_str().to_string());
+            form.insert("password", password.to_string());
+            test::TestRequest::post()
+                .uri("/login")
+                .insert_header((header::USER_AGENT, user_agent.to_string()))
+                .set_form(&form)
+                .to_request()
+        };
+        let resp = test::call_service(&app, login("assword", "Laptop")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let cookie = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == SESSION_COOKIE)
+            .unwrap()
+            .into_owned();
+        // A new device still signs in, the alert is only an email.
+        let resp = test::call_service(&app, login("assword", "Phone")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let token = state
+            .repo
+            .create_token(
+                &user.user_id,
+                TokenPurpose::SignInDenial,
+                chrono::Duration::hours(1),
+            )
+            .await
+            .unwrap();
+        let uri = format!("/account/not_me/{}", token.expose());
+        // Following the link only asks, the lockdown waits for the form.
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("Lock my account"));
+        let req = test::TestRequest::get()
+            .uri("/account")
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        let reset_uri = resp
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(reset_uri.starts_with("/reset_password/"));
+        let req = test::TestRequest::post().uri(&uri).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::GONE
+        );
+
+        // Every session is gone, and the old password no longer works.
+        let req = test::TestRequest::get()
+            .uri("/account")
+            .cookie(cookie)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let resp = test::call_service(&app, login("assword", "Laptop")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let mut form = HashMap::new();
+        form.insert("password", "tolerably-long-passphrase");
+        form.insert("confirm_password", "tolerably-long-passphrase");
+        let req = test::TestRequest::post()
+            .uri(&reset_uri)
+            .set_form(&form)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let resp = test::call_service(&app, login("tolerably-long-passphrase", "Laptop")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+    #[actix_web::test]
+    async fn test_group_roles() {
+        let (state, owner, date) = mock_db_user_date().await.unwrap();
+        let member = mock_user(&state).await.unwrap();
+        let invite = state
+            .repo
+            .create_invite(
+                owner.user_group,
+                &owner.user_id,
+                None,
+                chrono::Duration::hours(1),
+            )
+            .await
+            .unwrap();
+        let member = state
+            .repo
+            .accept_invite(&invite, &member.user_id)
+            .await
+            .unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let member_cookie = login_cookie(&app, &member).await;
+        let member_csrf = csrf_header(&app, &member_cookie).await;
+        let remove = || {
+            test::TestRequest::post()
+                .uri(&format!("/dates/{}/remove", date.id))
+                .cookie(member_cookie.clone())
+                .insert_header(member_csrf.clone())
+                .to_request()
+        };
+        assert_eq!(
+            test::call_service(&app, remove()).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let req = test::TestRequest::get()
+            .uri("/group")
+            .cookie(member_cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("You're the group's member."));
+
+        let owner_cookie = login_cookie(&app, &owner).await;
+        let owner_csrf = csrf_header(&app, &owner_cookie).await;
+        let mut form = HashMap::new();
+        form.insert("role", "admin");
+        let req = test::TestRequest::post()
+            .uri(&format!("/group/members/{}/role", member.user_id))
+            .cookie(owner_cookie)
+            .insert_header(owner_csrf)
+            .set_form(&form)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(
+            test::call_service(&app, remove()).await.status(),
+            StatusCode::OK
+        );
+        assert!(state
+            .repo
+            .get(&date.id, owner.user_group, &owner.user_id)
+            .await
+            .is_none());
+
+        // Only the owner can delete the group.
+        let req = test::TestRequest::post()
+            .uri("/group/delete")
+            .cookie(member_cookie)
+            .insert_header(member_csrf)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+    #[actix_web::test]
+    async fn test_group_invite() {
+        let (state, owner, _) = mock_db_user_date().await.unwrap();
+        let guest = mock_user(&state).await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let owner_cookie = login_cookie(&app, &owner).await;
+        let owner_csrf = csrf_header(&app, &owner_cookie).await;
+        let mut form = HashMap::new();
+        form.insert("email", "");
+        let req = test::TestRequest::post()
+            .uri("/group/invites")
+            .cookie(owner_cookie)
+            .insert_header(owner_csrf)
+            .insert_header((header::HOST, "attacker.example"))
+            .set_form(&form)
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("<svg"));
+        // The link points at the configured url, whatever host the request named.
+        assert!(body.replace("&#x2F;", "/").contains("test/invite/"));
+        assert!(!body.contains("attacker.example"));
+        let token = body
+            .replace("&#x2F;", "/")
+            .split("/invite/")
+            .nth(1)
+            .and_then(|b| b.split('"').next())
+            .expect("No invite link on the page.")
+            .to_string();
+        let uri = format!("/invite/{}", token);
+
+        let guest_cookie = login_cookie(&app, &guest).await;
+        let guest_csrf = csrf_header(&app, &guest_cookie).await;
+        let req = test::TestRequest::get()
+            .uri(&uri)
+            .cookie(guest_cookie.clone())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .cookie(guest_cookie.clone())
+            .insert_header(guest_csrf.clone())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(
+            state.repo.get_user(&guest.user_id).await.unwrap().group(),
+            Some(owner.user_group)
+        );
+        // The guest keeps their own group too.
+        assert_eq!(
+            state
+                .repo
+                .get_user_groups(&guest.user_id)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        // Links work once.
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .cookie(guest_cookie)
+            .insert_header(guest_csrf)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::GONE
+        );
+    }
+    #[actix_web::test]
+    async fn test_group_switcher() {
+        let (state, owner, _) = mock_db_user_date().await.unwrap();
+        let guest = mock_user(&state).await.unwrap();
+        let stranger = mock_user(&state).await.unwrap();
+        state
+            .repo
+            .add_user_to_group(
+                NoGroupUser::new(guest.user_id, guest.email.clone()),
+                owner.user_group,
+            )
+            .await
+            .unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &guest).await;
+        let csrf = csrf_header(&app, &cookie).await;
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .cookie(cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("group_switcher"));
+        assert!(body.contains("test date"));
+
+        let open = |group: i32| {
+            test::TestRequest::post()
+                .uri("/group/open")
+                .cookie(cookie.clone())
+                .insert_header(csrf.clone())
+                .set_form([("group", group.to_string())])
+                .to_request()
+        };
+        let resp = test::call_service(&app, open(guest.user_group)).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            state.repo.get_user(&guest.user_id).await.unwrap().group(),
+            Some(guest.user_group)
+        );
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .cookie(cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(!body.contains("test date"));
+        // Only groups the user belongs to can be opened.
+        assert_eq!(
+            test::call_service(&app, open(stranger.user_group))
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            state.repo.get_user(&guest.user_id).await.unwrap().group(),
+            Some(guest.user_group)
+        );
+    }
+    #[actix_web::test]
+    async fn test_group_settings() {
+        let (state, owner, _) = mock_db_user_date().await.unwrap();
+        let member = mock_user(&state).await.unwrap();
+        let member = state
+            .repo
+            .add_user_to_group(
+                NoGroupUser::new(member.user_id, member.email.clone()),
+                owner.user_group,
+            )
+            .await
+            .unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let owner_cookie = login_cookie(&app, &owner).await;
+        let owner_csrf = csrf_header(&app, &owner_cookie).await;
+        let member_cookie = login_cookie(&app, &member).await;
+        let member_csrf = csrf_header(&app, &member_cookie).await;
+        let handle = format!("party-{}", owner.user_group);
+        let profile = |cookie: &Cookie<'static>, csrf: &(&'static str, String), name: &str| {
+            test::TestRequest::post()
+                .uri("/group/profile")
+                .cookie(cookie.clone())
+                .insert_header(csrf.clone())
+                .set_form([
+                    ("handle", handle.as_str()),
+                    ("name", name),
+                    ("description", "Our dates"),
+                    ("emoji", "🎉"),
+                    ("timezone", "Europe/Berlin"),
+                ])
+                .to_request()
+        };
+        assert_eq!(
+            test::call_service(&app, profile(&member_cookie, &member_csrf, "Mine"))
+                .await
+                .status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            test::call_service(&app, profile(&owner_cookie, &owner_csrf, " "))
+                .await
+                .status(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            test::call_service(&app, profile(&owner_cookie, &owner_csrf, "Party people"))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .cookie(member_cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("Party people"));
+        // Names are shown as text, not markup.
+        test::call_service(&app, profile(&owner_cookie, &owner_csrf, "<i>Party</i>")).await;
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .cookie(member_cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("&lt;i&gt;Party"));
+        assert!(!body.contains("<i>Party"));
+
+        let sort = |cookie: &Cookie<'static>, csrf: &(&'static str, String)| {
+            test::TestRequest::post()
+                .uri("/group/settings")
+                .cookie(cookie.clone())
+                .insert_header(csrf.clone())
+                .set_form([("date_sort", "day")])
+                .to_request()
+        };
+        assert_eq!(
+            test::call_service(&app, sort(&member_cookie, &member_csrf))
+                .await
+                .status(),
+            StatusCode::FORBIDDEN
+        );
+        let req = sort(&owner_cookie, &owner_csrf);
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("Settings saved."));
+        let req = test::TestRequest::get()
+            .uri("/group/settings")
+            .cookie(member_cookie)
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("Approve or reject dates"));
+        assert!(body.contains("value=\"day\" selected"));
+        assert!(body.contains(&handle));
+    }
+    #[actix_web::test]
+    async fn test_join_request() {
+        let (state, owner, _) = mock_db_user_date().await.unwrap();
+        let asker = mock_user(&state).await.unwrap();
+        let handle = state
+            .repo
+            .get_group_profile(owner.user_group)
+            .await
+            .unwrap()
+            .handle;
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let asker_cookie = login_cookie(&app, &asker).await;
+        let asker_csrf = csrf_header(&app, &asker_cookie).await;
+        let ask = |handle: &str| {
+            test::TestRequest::post()
+                .uri("/join")
+                .cookie(asker_cookie.clone())
+                .insert_header(asker_csrf.clone())
+                .set_form([("handle", handle)])
+                .to_request()
+        };
+        assert_eq!(
+            test::call_service(&app, ask("no-such-group"))
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        let body = String::from_utf8_lossy(
+            &test::call_and_read_body(&app, ask(&handle.to_uppercase())).await,
+        )
+        .to_string();
+        assert!(body.contains("You&#x27;ve asked to join"));
+        assert_eq!(
+            test::call_service(&app, ask(&handle)).await.status(),
+            StatusCode::CONFLICT
+        );
+        // Nobody is added before the request is answered.
+        assert_eq!(
+            state
+                .repo
+                .get_user_groups(&asker.user_id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let owner_cookie = login_cookie(&app, &owner).await;
+        let owner_csrf = csrf_header(&app, &owner_cookie).await;
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .cookie(owner_cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("1 waiting to join"));
+        let request = state
+            .repo
+            .list_group_join_requests(owner.user_group)
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        let req = test::TestRequest::get()
+            .uri("/group/requests")
+            .cookie(owner_cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains(&request.request_id.to_string()));
+        let req = test::TestRequest::post()
+            .uri(&format!("/group/requests/{}/approve", request.request_id))
+            .cookie(owner_cookie)
+            .insert_header(owner_csrf)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let groups = state.repo.get_user_groups(&asker.user_id).await.unwrap();
+        assert!(groups.iter().any(|g| g.user_group == owner.user_group));
+        let open_group = || async { state.repo.get_user(&asker.user_id).await.unwrap().group() };
+        assert_eq!(open_group().await, Some(owner.user_group));
+
+        // A link about another group offers to open it, rather than opening it on a GET.
+        let req = test::TestRequest::get()
+            .uri(&format!("/group/requests?group={}", asker.user_group))
+            .cookie(asker_cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("open_elsewhere"));
+        assert_eq!(open_group().await, Some(owner.user_group));
+        let req = test::TestRequest::post()
+            .uri("/group/open")
+            .cookie(asker_cookie.clone())
+            .insert_header(asker_csrf.clone())
+            .set_form([
+                ("group", asker.user_group.to_string()),
+                ("next", "requests".to_string()),
+            ])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            resp.headers().get(header::LOCATION).unwrap(),
+            "/group/requests"
+        );
+        assert_eq!(open_group().await, Some(asker.user_group));
+
+        let req = test::TestRequest::get()
+            .uri("/join")
+            .cookie(asker_cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("approved"));
+    }
+    #[actix_web::test]
+    async fn test_authenticate_token_single_use() {
+        let state = mock_db().await;
+        let email = format!("{}@test.com", uuid::Uuid::new_v4());
+        let id = state
+            .repo
+            .register_user(UnRegisteredUser::new(
+                EmailAddress::parse(&email).unwrap(),
+                "assword",
+            ))
+            .await
+            .unwrap();
+        let token = state
+            .repo
+            .create_token(
+                &id,
+                TokenPurpose::EmailVerification,
+                chrono::Duration::hours(1),
+            )
+            .await
+            .unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let uri = format!("/authenticate/{}", token.expose());
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert!(state.repo.get_user(&id).await.is_ok());
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("/resend_verification"));
+    }
+    #[actix_web::test]
+    async fn test_authenticate_by_email_address_fails() {
+        let state = mock_db().await;
+        let email = format!("{}@test.com", uuid::Uuid::new_v4());
+        let id = state
+            .repo
+            .register_user(UnRegisteredUser::new(
+                EmailAddress::parse(&email).unwrap(),
+                "assword",
+            ))
+            .await
+            .unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/authenticate/{}", email))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert!(state.repo.get_user(&id).await.is_err());
+    }
+    #[actix_web::test]
+    async fn test_resend_verification_throttled() {
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let nobody = format!("{}@test.com", uuid::Uuid::new_v4());
+        let mut form = HashMap::new();
+        form.insert("email", nobody.as_str());
+        for _ in 0..FREE_ATTEMPTS {
+            let req = test::TestRequest::post()
+                .uri("/resend_verification")
+                .set_form(&form)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+        let shouted = nobody.to_uppercase();
+        form.insert("email", shouted.as_str());
+        let req = test::TestRequest::post()
+            .uri("/resend_verification")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+    }
+    #[actix_web::test]
+    async fn test_password_reset() {
+        let (state, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &user).await;
+        let api_token = state
+            .repo
+            .create_api_token(
+                &user.user_id,
+                "Script",
+                &[ApiScope::ReadDates],
+                chrono::Utc::now() + chrono::Duration::days(1),
+            )
+            .await
+            .unwrap();
+        let token = state
+            .repo
+            .create_token(
+                &user.user_id,
+                TokenPurpose::PasswordReset,
+                chrono::Duration::hours(1),
+            )
+            .await
+            .unwrap();
+        let uri = format!("/reset_password/{}", token.expose());
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let mut form = HashMap::new();
+        form.insert("password", "new_assword");
+        form.insert("confirm_password", "not_new_assword");
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        form.insert("confirm_password", "new_assword");
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .set_form(&form)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        // Existing sessions, api tokens and the used link no longer work.
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .cookie(cookie)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .insert_header((
+                header::AUTHORIZATION,
+                format!("Bearer {}", api_token.expose()),
+            ))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::GONE
+        );
+        let mut login = HashMap::new();
+        login.insert("email", user.email.as_str());
+        login.insert("password", "new_assword");
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_form(&login)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+    #[actix_web::test]
+    async fn test_forgot_password_unknown_email() {
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let nobody = format!("{}@test.com", uuid::Uuid::new_v4());
+        let mut form = HashMap::new();
+        form.insert("email", nobody.as_str());
+        let req = test::TestRequest::post()
+            .uri("/forgot_password")
+            .set_form(&form)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+    #[actix_web::test]
+    async fn test_forgot_password_throttled() {
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        // Per address, however it's spelled.
+        let nobody = format!("{}@test.com", uuid::Uuid::new_v4());
+        let mut form = HashMap::new();
+        form.insert("email", nobody.clone());
+        for _ in 0..FREE_ATTEMPTS {
+            let req = test::TestRequest::post()
+                .uri("/forgot_password")
+                .set_form(&form)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+        form.insert("email", nobody.to_uppercase());
+        let req = test::TestRequest::post()
+            .uri("/forgot_password")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+        // And per ip, whichever addresses it asks for.
+        let bytes = uuid::Uuid::new_v4().into_bytes();
+        let peer = std::net::SocketAddr::from(([10, bytes[0], bytes[1], bytes[2]], 4000));
+        for _ in 0..FREE_ATTEMPTS {
+            form.insert("email", format!("{}@test.com", uuid::Uuid::new_v4()));
+            let req = test::TestRequest::post()
+                .uri("/forgot_password")
+                .peer_addr(peer)
+                .set_form(&form)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+        form.insert("email", format!("{}@test.com", uuid::Uuid::new_v4()));
+        let req = test::TestRequest::post()
+            .uri("/forgot_password")
+            .peer_addr(peer)
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+    #[actix_web::test]
+    async fn test_change_password() {
+        let (_, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &user).await;
+        let csrf = csrf_header(&app, &cookie).await;
+        let mut form = HashMap::new();
+        form.insert("current_password", "failword");
+        form.insert("new_password", "new_assword");
+        form.insert("confirm_password", "new_assword");
+        let req = test::TestRequest::post()
+            .uri("/account/password")
+            .cookie(cookie.clone())
+            .insert_header(csrf.clone())
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        form.insert("current_password", "assword");
+        let req = test::TestRequest::post()
+            .uri("/account/password")
+            .cookie(cookie)
+            .insert_header(csrf)
+            .set_form(&form)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let mut login = HashMap::new();
+        login.insert("email", user.email.as_str());
+        login.insert("password", "new_assword");
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_form(&login)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+    #[actix_web::test]
+    async fn test_change_email() {
+        let (state, user, _) = mock_db_user_date().await.unwrap();
+        let other = mock_user(&state).await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &user).await;
+        let csrf = csrf_header(&app, &cookie).await;
+        let mut form = HashMap::new();
+        form.insert("new_email", other.email.as_str());
+        form.insert("current_password", "assword");
+        let req = test::TestRequest::post()
+            .uri("/account/email")
+            .cookie(cookie)
+            .insert_header(csrf)
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CONFLICT
+        );
+
+        let new_email = format!("{}@test.com", uuid::Uuid::new_v4());
+        let token = state
+            .repo
+            .create_token_with_payload(
+                &user.user_id,
+                TokenPurpose::EmailChange,
+                chrono::Duration::hours(1),
+                Some(new_email.clone()),
+            )
+            .await
+            .unwrap();
+        // Until the link is followed the old address is still in use.
+        assert!(state.repo.get_user_by_email(&user.email).await.is_ok());
+        let req = test::TestRequest::get()
+            .uri(&format!("/account/confirm_email/{}", token.expose()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(
+            state.repo.get_user_by_email(&new_email).await.unwrap().id(),
+            user.user_id
+        );
+    }
+    #[actix_web::test]
+    async fn test_register_duplicate_email() {
+        let (_, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let mut form = HashMap::new();
+        form.insert("email", user.email.as_str());
+        form.insert("password", "tolerably-long-passphrase");
+        let req = test::TestRequest::post()
+            .uri("/register")
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CONFLICT
+        );
+    }
+    #[actix_web::test]
+    async fn test_email_link_login() {
+        let (state, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let key = Key::generate();
+        let app_key = key.clone();
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .session_key(app_key)
+                .service_configuration(cfg)
+        }))
+        .await;
+        // Unknown addresses get the same reply, and a nonce all the same.
+        let nobody = format!("{}@test.com", uuid::Uuid::new_v4());
+        let mut form = HashMap::new();
+        form.insert("email", nobody.as_str());
+        for _ in 0..FREE_ATTEMPTS {
+            let req = test::TestRequest::post()
+                .uri("/login/email_link")
+                .set_form(&form)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(resp
+                .response()
+                .cookies()
+                .any(|c| c.name() == EMAIL_LINK_COOKIE));
+        }
+        // Then the address has to wait, however it's spelled.
+        let shouted = nobody.to_uppercase();
+        form.insert("email", shouted.as_str());
+        let req = test::TestRequest::post()
+            .uri("/login/email_link")
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+
+        let nonce = Token::generate();
+        let browser = private_cookie(
+            EMAIL_LINK_COOKIE,
+            nonce.expose().to_string(),
+            "/login/email_link",
+            EMAIL_LINK_MINUTES,
+            &key,
+        );
+        let link = |token: &Token| format!("/login/email_link/{}", token.expose());
+        let token = state
+            .repo
+            .create_token_with_payload(
+                &user.user_id,
+                TokenPurpose::EmailLogin,
+                chrono::Duration::minutes(EMAIL_LINK_MINUTES),
+                Some(nonce.hash()),
+            )
+            .await
+            .unwrap();
+        // Opened somewhere without the nonce, the link is refused but still usable.
+        let req = test::TestRequest::get().uri(&link(&token)).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let req = test::TestRequest::get()
+            .uri(&link(&token))
+            .cookie(browser.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), "/user");
+        let session = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == SESSION_COOKIE)
+            .unwrap()
+            .into_owned();
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .cookie(session)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri(&link(&token))
+            .cookie(browser)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::GONE
+        );
+
+        // A link requested from another browser doesn't work in this one.
+        let other = state
+            .repo
+            .create_token_with_payload(
+                &user.user_id,
+                TokenPurpose::EmailLogin,
+                chrono::Duration::minutes(EMAIL_LINK_MINUTES),
+                Some(Token::generate().hash()),
+            )
+            .await
+            .unwrap();
+        let req = test::TestRequest::get()
+            .uri(&link(&other))
+            .cookie(private_cookie(
+                EMAIL_LINK_COOKIE,
+                nonce.expose().to_string(),
+                "/login/email_link",
+                EMAIL_LINK_MINUTES,
+                &key,
+            ))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        // And isn't used up by being opened there.
+        assert!(state
+            .repo
+            .check_token(&other, TokenPurpose::EmailLogin)
+            .await
+            .is_ok());
+    }
+    #[actix_web::test]
+    async fn test_oidc_login() {
+        let state = mock_db().await;
+        let issuer = mock_issuer().await;
+        let pool = get_pool().await;
+        let config = issuer.config();
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .oidc(config)
+                .service_configuration(cfg)
+        }))
+        .await;
+        let sub = uuid::Uuid::new_v4().to_string();
+        let email = format!("{}@oidc.com", sub);
+        // First login creates the user, later ones find them again.
+        for _ in 0..2 {
+            let (cookie, callback) = approved_oidc_login(&app, &issuer, &sub, &email, true).await;
+            let req = test::TestRequest::get()
+                .uri(&callback)
+                .cookie(cookie)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+            let session = resp
+                .response()
+                .cookies()
+                .find(|c| c.name() == SESSION_COOKIE)
+                .unwrap()
+                .into_owned();
+            let req = test::TestRequest::get()
+                .uri("/user")
+                .cookie(session)
+                .to_request();
+            let body = test::call_and_read_body(&app, req).await;
+            assert!(String::from_utf8_lossy(&body).contains(&email));
+        }
+        let user_id = state
+            .repo
+            .get_identity_user(&issuer.url, &sub)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            state.repo.get_user_by_email(&email).await.unwrap().id(),
+            user_id
+        );
+        // A code is only good for the login that asked for it.
+        let (_, callback) = approved_oidc_login(&app, &issuer, &sub, &email, true).await;
+        let (cookie, _) = approved_oidc_login(&app, &issuer, &sub, &email, true).await;
+        let req = test::TestRequest::get()
+            .uri(&callback)
+            .cookie(cookie)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        state.repo.remove_user(&user_id).await.unwrap();
+    }
+    #[actix_web::test]
+    async fn test_passwordless_reauth() {
+        let state = mock_db().await;
+        let issuer = mock_issuer().await;
+        let pool = get_pool().await;
+        let config = issuer.config();
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .oidc(config)
+                .service_configuration(cfg)
+        }))
+        .await;
+        let sub = uuid::Uuid::new_v4().to_string();
+        let email = format!("{}@oidc.com", sub);
+        let sign_in = || async {
+            let (cookie, callback) = approved_oidc_login(&app, &issuer, &sub, &email, true).await;
+            let req = test::TestRequest::get()
+                .uri(&callback)
+                .cookie(cookie)
+                .to_request();
+            test::call_service(&app, req)
+                .await
+                .response()
+                .cookies()
+                .find(|c| c.name() == SESSION_COOKIE)
+                .unwrap()
+                .into_owned()
+        };
+        let mut form = HashMap::new();
+        form.insert("current_password", "");
+        form.insert("new_password", "tolerably-long-passphrase");
+        form.insert("confirm_password", "tolerably-long-passphrase");
+        let change_password = |session: Cookie<'static>, csrf: (&'static str, String)| {
+            test::TestRequest::post()
+                .uri("/account/password")
+                .cookie(session)
+                .insert_header(csrf)
+                .set_form(&form)
+                .to_request()
+        };
+        // Without a password to give, an old session isn't enough.
+        let stale = sign_in().await;
+        let user_id = state.repo.get_user_by_email(&email).await.unwrap().id();
+        sqlx::query("UPDATE sessions SET created_at = now() - interval '1 hour' WHERE user_id=$1")
+            .bind(user_id)
+            .execute(&get_pool().await)
+            .await
+            .unwrap();
+        let req = test::TestRequest::get()
+            .uri("/account")
+            .cookie(stale.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("class=\"reauth\""));
+        let csrf = csrf_header(&app, &stale).await;
+        let resp = test::call_service(&app, change_password(stale, csrf)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+        assert!(body.contains("sign in again"));
+        // Signing in again is.
+        let fresh = sign_in().await;
+        let csrf = csrf_header(&app, &fresh).await;
+        assert_eq!(
+            test::call_service(&app, change_password(fresh, csrf))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        state.repo.remove_user(&user_id).await.unwrap();
+    }
+    #[actix_web::test]
+    async fn test_oidc_links_existing_user() {
+        let (state, user, _) = mock_db_user_date().await.unwrap();
+        let issuer = mock_issuer().await;
+        let pool = get_pool().await;
+        let config = issuer.config();
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .oidc(config)
+                .service_configuration(cfg)
+        }))
+        .await;
+        let sub = uuid::Uuid::new_v4().to_string();
+        // Not even an address the provider has verified can claim the account.
+        for verified in [false, true] {
+            let (cookie, callback) =
+                approved_oidc_login(&app, &issuer, &sub, &user.email, verified).await;
+            let req = test::TestRequest::get()
+                .uri(&callback)
+                .cookie(cookie)
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::CONFLICT
+            );
+        }
+        assert_eq!(
+            state
+                .repo
+                .get_identity_user(&issuer.url, &sub)
+                .await
+                .unwrap(),
+            None
+        );
+        // The account's owner connects it while logged in.
+        let session = login_cookie(&app, &user).await;
+        let (cookie, callback) =
+            start_oidc_flow(&app, &issuer, &sub, &user.email, true, Some(&session)).await;
+        // Only in the browser that's logged in.
+        let req = test::TestRequest::get()
+            .uri(&callback)
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let (cookie, callback) =
+            start_oidc_flow(&app, &issuer, &sub, &user.email, true, Some(&session)).await;
+        let req = test::TestRequest::get()
+            .uri(&callback)
+            .cookie(cookie)
+            .cookie(session)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::SEE_OTHER
+        );
+        let (cookie, callback) = approved_oidc_login(&app, &issuer, &sub, &user.email, true).await;
+        let req = test::TestRequest::get()
+            .uri(&callback)
+            .cookie(cookie)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::SEE_OTHER
+        );
+        assert_eq!(
+            state
+                .repo
+                .get_identity_user(&issuer.url, &sub)
+                .await
+                .unwrap(),
+            Some(user.user_id)
+        );
+    }
+    #[actix_web::test]
+    async fn test_two_factor_login() {
+        let (state, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let cookie = login_cookie(&app, &user).await;
+        let req = test::TestRequest::get()
+            .uri("/account/two_factor")
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let secret = state
+            .repo
+            .get_totp(&user.user_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .secret;
+        let now = chrono::Utc::now().timestamp();
+        let mut form = HashMap::new();
+        form.insert("code", code_at(&secret, now).unwrap());
+        let csrf = csrf_header(&app, &cookie).await;
+        let req = test::TestRequest::post()
+            .uri("/account/two_factor")
+            .cookie(cookie)
+            .insert_header(csrf)
+            .set_form(&form)
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        let recovery_codes: Vec<String> = body
+            .split("<li>")
+            .skip(1)
+            .map(|li| li.split("</li>").next().unwrap().to_string())
+            .collect();
+        assert_eq!(recovery_codes.len(), RECOVERY_CODE_COUNT);
+
+        // The password alone only gets as far as the code page.
+        let pending_login = || async {
+            let mut login = HashMap::new();
+            login.insert("email", user.email.as_str());
+            login.insert("password", "assword");
+            let req = test::TestRequest::post()
+                .uri("/login")
+                .set_form(&login)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let cookie = resp
+                .response()
+                .cookies()
+                .find(|c| c.name() == SESSION_COOKIE)
+                .unwrap()
+                .into_owned();
+            let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+            (cookie, page_csrf_header(&body))
+        };
+        let pending = pending_login().await;
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .cookie(pending.0.clone())
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let send_code =
+            |code: String, (cookie, csrf): (Cookie<'static>, (&'static str, String))| {
+                let mut form = HashMap::new();
+                form.insert("code", code);
+                test::TestRequest::post()
+                    .uri("/login/two_factor")
+                    .cookie(cookie)
+                    .insert_header(csrf)
+                    .set_form(&form)
+                    .to_request()
+            };
+        // Neither a wrong code, nor the code already used to enrol, is accepted.
+        let req = send_code("000000".into(), pending.clone());
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = send_code(code_at(&secret, now).unwrap(), pending.clone());
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = send_code(
+            code_at(&secret, now + TOTP_STEP_SECONDS).unwrap(),
+            pending.clone(),
+        );
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let session = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == SESSION_COOKIE)
+            .unwrap()
+            .into_owned();
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .cookie(session)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        // Recovery codes work once each.
+        let req = send_code(recovery_codes[0].clone(), pending_login().await);
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = send_code(recovery_codes[0].clone(), pending_login().await);
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // The password alone doesn't make a device known, the code is needed too.
+        let mut login = HashMap::new();
+        login.insert("email", user.email.as_str());
+        login.insert("password", "assword");
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .insert_header((header::USER_AGENT, "Stolen password"))
+            .set_form(&login)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let device = SessionDevice::from_request(
+            &test::TestRequest::get()
+                .insert_header((header::USER_AGENT, "Stolen password"))
+                .to_http_request(),
+        );
+        assert_eq!(
+            state
+                .repo
+                .remember_device(&user.user_id, &device)
+                .await
+                .unwrap(),
+            DeviceHistory::New
+        );
+    }
+    #[actix_web::test]
+    async fn test_passkey_login() {
+        let (_, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .relying_party(RelyingParty::from_origin(MockAuthenticator::ORIGIN).unwrap())
+                .service_configuration(cfg)
+        }))
+        .await;
+        let session = login_cookie(&app, &user).await;
+        let mut authenticator = MockAuthenticator::new();
+        let (options, challenge) = start_passkey_ceremony(
+            &app,
+            "/account/passkeys/register/start",
+            Some(session.clone()),
+        )
+        .await;
+        let csrf = csrf_header(&app, &session).await;
+        let req = test::TestRequest::post()
+            .uri("/account/passkeys/register/finish")
+            .cookie(session.clone())
+            .insert_header(csrf.clone())
+            .cookie(challenge)
+            .set_json(authenticator.register(&options, "Laptop"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CREATED
+        );
+
+        let login = |body: Value, challenge: Cookie<'static>| {
+            test::TestRequest::post()
+                .uri("/passkeys/login/finish")
+                .cookie(challenge)
+                .set_json(body)
+                .to_request()
+        };
+        let (options, challenge) =
+            start_passkey_ceremony(&app, "/passkeys/login/start", None).await;
+        let assertion = authenticator.assert(&options, &user.user_id);
+        let resp = test::call_service(&app, login(assertion.clone(), challenge.clone())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let passkey_session = resp
+            .response()
+            .cookies()
+            .find(|c| c.name() == SESSION_COOKIE)
+            .unwrap()
+            .into_owned();
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .cookie(passkey_session)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        // A replayed signature doesn't move the counter on.
+        assert_eq!(
+            test::call_service(&app, login(assertion, challenge.clone()))
+                .await
+                .status(),
+            StatusCode::UNAUTHORIZED
+        );
+        // And an answered challenge can't be signed again.
+        let resigned = authenticator.assert(&options, &user.user_id);
+        assert_eq!(
+            test::call_service(&app, login(resigned, challenge))
+                .await
+                .status(),
+            StatusCode::UNAUTHORIZED
+        );
+        // Nor does one signed over an old challenge.
+        let (_, new_challenge) = start_passkey_ceremony(&app, "/passkeys/login/start", None).await;
+        let stale = authenticator.assert(&options, &user.user_id);
+        assert_eq!(
+            test::call_service(&app, login(stale, new_challenge))
+                .await
+                .status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let path = format!("/account/passkeys/{}", authenticator.id());
+        let mut form = HashMap::new();
+        form.insert("name", "Phone");
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/rename", path))
+            .cookie(session.clone())
+            .insert_header(csrf.clone())
+            .set_form(&form)
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert!(body.contains("Phone"));
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/revoke", path))
+            .cookie(session.clone())
+            .insert_header(csrf.clone())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/revoke", path))
+            .cookie(session)
+            .insert_header(csrf)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let (options, challenge) =
+            start_passkey_ceremony(&app, "/passkeys/login/start", None).await;
+        let assertion = authenticator.assert(&options, &user.user_id);
+        assert_eq!(
+            test::call_service(&app, login(assertion, challenge))
+                .await
+                .status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+    #[actix_web::test]
+    async fn test_api_token() {
+        let (state, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let session = login_cookie(&app, &user).await;
+        let csrf = csrf_header(&app, &session).await;
+        let mut form = HashMap::new();
+        form.insert("name", "Script");
+        form.insert("expires_in_days", "30");
+        // Ticking no scopes doesn't hand out all of them.
+        let req = test::TestRequest::post()
+            .uri("/account/api_tokens")
+            .cookie(session.clone())
+            .insert_header(csrf.clone())
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+        assert!(body.contains("Pick at least one scope"));
+        form.insert("dates:read", "on");
+        // An authorization header doesn't let a session cookie skip the CSRF check.
+        let req = test::TestRequest::post()
+            .uri("/account/api_tokens")
+            .cookie(session.clone())
+            .insert_header((header::AUTHORIZATION, "Bearer drs_not_a_token"))
+            .set_form(&form)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let req = test::TestRequest::post()
+            .uri("/account/api_tokens")
+            .cookie(session.clone())
+            .insert_header(csrf.clone())
+            .set_form(&form)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = String::from_utf8_lossy(&test::read_body(resp).await).to_string();
+        let token = body
+            .split("id=\"new_token\"")
+            .nth(1)
+            .and_then(|b| b.split('>').nth(1))
+            .and_then(|b| b.split('<').next())
+            .unwrap()
+            .to_string();
+        let bearer = |token: &str| (header::AUTHORIZATION, format!("Bearer {}", token));
+        let handle = state
+            .repo
+            .get_group_profile(user.user_group)
+            .await
+            .unwrap()
+            .handle;
+        let in_group = |handle: &str| (GROUP_HEADER, handle.to_string());
+
+        // A token doesn't act in whichever group is open in the browser, it names one.
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .insert_header(bearer(&token))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+        // Which the user has to belong to.
+        let other = state.repo.create_group().await.unwrap();
+        let other_handle = state.repo.get_group_profile(other).await.unwrap().handle;
+        for handle in [other_handle.as_str(), "group-unknown"] {
+            let req = test::TestRequest::get()
+                .uri("/dates")
+                .insert_header(bearer(&token))
+                .insert_header(in_group(handle))
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::FORBIDDEN
+            );
+        }
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .insert_header(bearer(&token))
+            .insert_header(in_group(&handle))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        // Read only, so it can't add dates.
+        let mut new_date = HashMap::new();
+        new_date.insert("name", "Scripted");
+        let req = test::TestRequest::post()
+            .uri("/dates/new_date")
+            .insert_header(bearer(&token))
+            .insert_header(in_group(&handle))
+            .set_form(&new_date)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        // Nor manage the account that made it.
+        let req = test::TestRequest::get()
+            .uri("/account/api_tokens")
+            .insert_header(bearer(&token))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .insert_header(bearer("drs_not_a_token"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let listed = state.repo.list_api_tokens(&user.user_id).await.unwrap();
+        assert_eq!(listed[0].scopes, vec!["dates:read"]);
+        assert!(listed[0].last_used_at.is_some());
+        let req = test::TestRequest::post()
+            .uri(&format!(
+                "/account/api_tokens/{}/revoke",
+                listed[0].token_id
+            ))
+            .cookie(session)
+            .insert_header(csrf)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri("/dates")
+            .insert_header(bearer(&token))
+            .insert_header(in_group(&handle))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+    #[actix_web::test]
+    async fn test_sessions_page() {
+        let (_, user, _) = mock_db_user_date().await.unwrap();
+        let pool = get_pool().await;
+        let app = test::init_service(App::new().configure(move |cfg: &mut ServiceConfig| {
+            MainService::new(pool, EmailClient::new("test", "test", "test"))
+                .service_configuration(cfg)
+        }))
+        .await;
+        let other = login_cookie(&app, &user).await;
+        let cookie = login_cookie(&app, &user).await;
+        let csrf = csrf_header(&app, &cookie).await;
+        let req = test::TestRequest::get()
+            .uri("/account/sessions")
+            .cookie(cookie.clone())
+            .to_request();
+        let body = String::from_utf8_lossy(&test::call_and_read_body(&app, req).await).to_string();
+        assert_eq!(body.matches("class=\"session ").count(), 2);
+        assert!(body.contains("(this device)"));
+        // The other session is the one listed second, as this one was seen last.
+        let other_id = body
+            .split("/account/sessions/")
+            .nth(2)
+            .and_then(|b| b.split('/').next())
+            .unwrap()
+            .to_string();
+        let req = test::TestRequest::post()
+            .uri(&format!("/account/sessions/{}/revoke", other_id))
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        let req = test::TestRequest::post()
+            .uri(&format!("/account/sessions/{}/revoke", other_id))
+            .cookie(cookie.clone())
+            .insert_header(csrf.clone())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .cookie(other)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let req = test::TestRequest::post()
+            .uri("/account/sessions/revoke_all")
+            .cookie(cookie.clone())
+            .insert_header(csrf)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::SEE_OTHER
+        );
+        let req = test::TestRequest::get()
+            .uri("/user")
+            .cookie(cookie)
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
 }